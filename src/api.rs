@@ -1,8 +1,9 @@
-use anyhow::anyhow;
+use std::net::SocketAddr;
+
 use axum::{
     body::Bytes,
-    extract::{Path, Query, State},
-    routing::{delete, get, put},
+    extract::{ConnectInfo, Path, Query, State},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use reqwest::StatusCode;
@@ -10,15 +11,22 @@ use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
-use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc::UnboundedSender};
-use tracing::info;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
 
 use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    blob::{self, Blobs},
+    cache,
     config::Config,
-    crate_path,
+    owner::{self, CrateOwner},
     package::{self, UploadedPackage},
-    token::ApiAuth,
-    AppState, Entry, InternalError,
+    paseto::Mutation,
+    team,
+    token::{self, ApiAuth, EndpointScope},
+    vendor,
+    visibility::{self, IndexReader, Reader},
+    AppState, CrateHeader, InternalError,
 };
 
 pub fn router() -> Router<AppState> {
@@ -27,6 +35,14 @@ pub fn router() -> Router<AppState> {
         .route("/v1/crates/new", put(add_crate))
         .route("/v1/crates/:crate_name/:version/yank", delete(yank_crate))
         .route("/v1/crates/:crate_name/:version/unyank", put(unyank_crate))
+        .route(
+            "/v1/crates/:crate_name/owners",
+            get(list_owners).put(add_owners).delete(remove_owners),
+        )
+        .route("/v1/tokens/revoke", post(revoke_tokens))
+        .merge(audit::api_router())
+        .merge(cache::api_router())
+        .merge(vendor::api_router())
 }
 
 fn create_error(msg: &str) -> Result<(StatusCode, Json<Value>), InternalError> {
@@ -36,15 +52,54 @@ fn create_error(msg: &str) -> Result<(StatusCode, Json<Value>), InternalError> {
     ))
 }
 
+fn forbidden_error(msg: &str) -> Result<(StatusCode, Json<Value>), InternalError> {
+    Ok((
+        StatusCode::FORBIDDEN,
+        Json(json!({ "errors": [{"detail": msg}]})),
+    ))
+}
+
 async fn add_crate(
     ApiAuth(token, user): ApiAuth,
     State(db): State<crate::Db>,
     State(state): State<Config>,
+    State(blobs): State<Blobs>,
     State(docs_queue_tx): State<UnboundedSender<(String, String)>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     body: Bytes,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let event = AuditEvent::new(&user, AuditAction::Publish, "")
+        .token(token.label())
+        .ip(addr.ip());
+    // The crate being published is only known once the body has been parsed
+    let mut target = String::new();
+    let result = publish_crate(
+        ApiAuth(token, user),
+        State(db.clone()),
+        State(state),
+        State(blobs),
+        State(docs_queue_tx),
+        body,
+        &mut target,
+    )
+    .await;
+
+    audit::record_api_result(&db, AuditEvent { target, ..event }, &result)?;
+    result
+}
+
+async fn publish_crate(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(state): State<Config>,
+    State(blobs): State<Blobs>,
+    State(docs_queue_tx): State<UnboundedSender<(String, String)>>,
+    body: Bytes,
+    target: &mut String,
 ) -> Result<(StatusCode, Json<Value>), InternalError> {
     info!(
-        "user {} attempting to upload crate using token {}",
+        "{} {} attempting to upload crate using token {}",
+        user.kind_label(),
         user.username,
         token.label()
     );
@@ -70,75 +125,92 @@ async fn add_crate(
     let crate_name = metadata.name.clone();
     let crate_version = metadata.vers.clone();
     let cksum = format!("{:x}", Sha256::digest(&data));
+    *target = format!("{crate_name}@{crate_version}");
+
+    if !token.allows_mutation(&Mutation::Publish {
+        name: &crate_name,
+        vers: &crate_version,
+        cksum: &cksum,
+    }) {
+        return forbidden_error("token was not issued to publish this crate");
+    }
 
-    // Check if crate already exists
-    match db.get_crate(&crate_name)? {
-        Some(mut entry) => {
-            // If it already exists, add a new version to the entry
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        let header = db.get_crate_header(&crate_name)?;
+        let version = UploadedPackage {
+            pkg: metadata.to_package(cksum.clone()),
+            upload_meta: Some(metadata.clone()),
+            upload_timestamp: Some(chrono::Utc::now()),
+        };
+
+        // Check if crate already exists
+        let inserted = match &header {
+            Some(expected) => {
+                // If it already exists, add a new version to the entry
+                if !token.is_authorized(EndpointScope::PublishUpdate, &crate_name) {
+                    return forbidden_error(
+                        "token is not authorized to publish updates to this crate",
+                    );
+                }
 
-            // Make sure we don't publish new versions of existing upstream crates
-            if !entry.is_local {
-                return create_error(
-                    "attempted to upload crate with the same name as a cached upstream crate",
-                );
-            }
+                // Make sure we don't publish new versions of existing upstream crates
+                if !expected.is_local {
+                    return create_error(
+                        "attempted to upload crate with the same name as a cached upstream crate",
+                    );
+                }
 
-            // Check that it is valid to upload this version
-            let new_version = Version::parse(&metadata.vers)?;
-            let mut is_older_than_latest = false;
-            for version in &entry.versions {
-                let existing_version = Version::parse(&version.pkg.vers)?;
-                if new_version == existing_version {
-                    return create_error("attempted to upload existing version");
+                if !owner::may_publish(&db, &state, &expected.owners, &user.username)? {
+                    return forbidden_error("you are not an owner of this crate");
                 }
-                if new_version < existing_version {
-                    is_older_than_latest = true;
+
+                // Check that it is valid to upload this version
+                Version::parse(&metadata.vers)?;
+                if db.get_crate_version(&crate_name, &metadata.vers)?.is_some() {
+                    return create_error("attempted to upload existing version");
                 }
+
+                // Add this version, which the database keeps in semver order
+                let mut entry = expected.clone();
+                owner::claim_unowned(&mut entry.owners, &user.username);
+                entry.time_of_last_update = chrono::Utc::now();
+                db.insert_crate_version(&crate_name, Some(expected), &entry, &version)?
             }
+            None => {
+                // If it doesn't exist, create a new entry
+                if !token.is_authorized(EndpointScope::PublishNew, &crate_name) {
+                    return forbidden_error("token is not authorized to publish this new crate");
+                }
 
-            // Add this version
-            entry.versions.push(UploadedPackage {
-                pkg: metadata.to_package(cksum),
-                upload_meta: Some(metadata),
-                upload_timestamp: Some(chrono::Utc::now()),
-            });
-            if is_older_than_latest {
-                entry.versions.sort_unstable_by_key(|version| {
-                    Version::parse(&version.pkg.vers)
-                        .expect("all existing versions have valid identifiers")
-                })
+                let entry = CrateHeader {
+                    time_of_last_update: chrono::Utc::now(),
+                    is_local: true,
+                    owners: vec![CrateOwner::User(user.username.clone())],
+                    visibility: state.default_visibility.clone(),
+                    upstream: None,
+                };
+                db.insert_crate_version(&crate_name, None, &entry, &version)?
             }
-            entry.time_of_last_update = chrono::Utc::now();
-            db.insert_crate(&crate_name, &entry)?;
-        }
-        None => {
-            // If it doesn't exist, create a new entry
-            let entry = Entry {
-                versions: vec![UploadedPackage {
-                    pkg: metadata.to_package(cksum),
-                    upload_meta: Some(metadata),
-                    upload_timestamp: Some(chrono::Utc::now()),
-                }],
-                time_of_last_update: chrono::Utc::now(),
-                is_local: true,
-            };
-            db.insert_crate(&crate_name, &entry)?;
+        };
+        if inserted {
+            break;
         }
     }
 
     // Store crate file
-    let cache_path = crate_path(state.data_dir, &crate_name, &crate_version);
-    let parent = cache_path
-        .parent()
-        .ok_or_else(|| anyhow!("invalid cache path"))?;
-    if !parent.exists() {
-        tokio::fs::create_dir_all(parent).await?;
+    blobs
+        .put(&blob::crate_key(&crate_name, &crate_version), data)
+        .await?;
+
+    // Notify the background thread to build the docs for this crate, which is not a reason to fail the publish since
+    // the version has already been stored
+    if let Err(e) = docs_queue_tx.send((crate_name, crate_version)) {
+        warn!(
+            "could not queue docs build for {}@{}: docs builder has stopped",
+            e.0 .0, e.0 .1
+        );
     }
-    let mut file = File::create(cache_path).await?;
-    file.write_all(&data).await?;
-
-    // Notify the background thread to build the docs for this crate
-    docs_queue_tx.send((crate_name, crate_version))?;
 
     Ok((StatusCode::OK, Json(json!({}))))
 }
@@ -146,87 +218,379 @@ async fn add_crate(
 async fn yank_crate(
     ApiAuth(token, user): ApiAuth,
     State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((crate_name, version)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let event = AuditEvent::new(&user, AuditAction::Yank, format!("{crate_name}@{version}"))
+        .token(token.label())
+        .ip(addr.ip());
+    let result = yank_version(
+        ApiAuth(token, user),
+        State(db.clone()),
+        State(config),
+        Path((crate_name, version)),
+    )
+    .await;
+
+    audit::record_api_result(&db, event, &result)?;
+    result
+}
+
+async fn yank_version(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
     Path((crate_name, version)): Path<(String, String)>,
 ) -> Result<(StatusCode, Json<Value>), InternalError> {
     info!(
-        "user {} attempting to yank crate {}@{} using token {}",
+        "{} {} attempting to yank crate {}@{} using token {}",
+        user.kind_label(),
         user.username,
         crate_name,
         version,
         token.label()
     );
 
+    if !token.is_authorized(EndpointScope::Yank, &crate_name) {
+        return forbidden_error("token is not authorized to yank this crate");
+    }
+    if !token.allows_mutation(&Mutation::Yank {
+        name: &crate_name,
+        vers: &version,
+    }) {
+        return forbidden_error("token was not issued to yank this version");
+    }
+
     // Check the user supplied a valid semver version
     let Ok(yank_version) = Version::parse(&version) else {
         return create_error("invalid crate version supplied");
     };
 
-    // Get the crate
-    let Some(mut entry) = db.get_crate(&crate_name)? else {
-        return create_error("crate does not exist in index");
-    };
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        // Get the crate
+        let Some(expected) = db.get_crate_header(&crate_name)? else {
+            return create_error("crate does not exist in index");
+        };
 
-    // Find the package to yank
-    let Some(package) = entry.versions.iter_mut().find(|version| {
-        Version::parse(&version.pkg.vers).expect("all existing versions have valid identifiers")
-            == yank_version
-    }) else {
-        return create_error("crate does not have the specified version published");
-    };
+        if !owner::is_owner(&db, &config, &expected.owners, &user.username)? {
+            return forbidden_error("you are not an owner of this crate");
+        }
 
-    if package.pkg.yanked {
-        return create_error("version has already been yanked");
-    }
+        // Find the package to yank
+        let Some(mut package) = db.get_crate_version(&crate_name, &yank_version.to_string())?
+        else {
+            return create_error("crate does not have the specified version published");
+        };
 
-    package.pkg.yanked = true;
+        if package.pkg.yanked {
+            return create_error("version has already been yanked");
+        }
 
-    // Reinsert the crate into the database
-    db.insert_crate(&crate_name, &entry)?;
+        package.pkg.yanked = true;
 
-    Ok((StatusCode::OK, Json(json!({"ok": true}))))
+        // Reinsert the version into the database, marking the index file as modified
+        let mut entry = expected.clone();
+        entry.time_of_last_update = chrono::Utc::now();
+        if db.replace_crate_version(&crate_name, &expected, &entry, &package)? {
+            return Ok((StatusCode::OK, Json(json!({"ok": true}))));
+        }
+    }
 }
 
 async fn unyank_crate(
     ApiAuth(token, user): ApiAuth,
     State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((crate_name, version)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let event = AuditEvent::new(
+        &user,
+        AuditAction::Unyank,
+        format!("{crate_name}@{version}"),
+    )
+    .token(token.label())
+    .ip(addr.ip());
+    let result = unyank_version(
+        ApiAuth(token, user),
+        State(db.clone()),
+        State(config),
+        Path((crate_name, version)),
+    )
+    .await;
+
+    audit::record_api_result(&db, event, &result)?;
+    result
+}
+
+async fn unyank_version(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
     Path((crate_name, version)): Path<(String, String)>,
 ) -> Result<(StatusCode, Json<Value>), InternalError> {
     info!(
-        "user {} attempting to unyank crate {}@{} using token {}",
+        "{} {} attempting to unyank crate {}@{} using token {}",
+        user.kind_label(),
         user.username,
         crate_name,
         version,
         token.label()
     );
 
+    if !token.is_authorized(EndpointScope::Yank, &crate_name) {
+        return forbidden_error("token is not authorized to unyank this crate");
+    }
+    if !token.allows_mutation(&Mutation::Unyank {
+        name: &crate_name,
+        vers: &version,
+    }) {
+        return forbidden_error("token was not issued to unyank this version");
+    }
+
     // Check the user supplied a valid semver version
     let Ok(yank_version) = Version::parse(&version) else {
         return create_error("invalid crate version supplied");
     };
 
-    // Get the crate
-    let Some(mut entry) = db.get_crate(&crate_name)? else {
-        return create_error("crate does not exist in index");
-    };
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        // Get the crate
+        let Some(expected) = db.get_crate_header(&crate_name)? else {
+            return create_error("crate does not exist in index");
+        };
+
+        if !owner::is_owner(&db, &config, &expected.owners, &user.username)? {
+            return forbidden_error("you are not an owner of this crate");
+        }
+
+        // Find the package to unyank
+        let Some(mut package) = db.get_crate_version(&crate_name, &yank_version.to_string())?
+        else {
+            return create_error("crate does not have the specified version published");
+        };
+
+        if !package.pkg.yanked {
+            return create_error("version has not been yanked");
+        }
+
+        package.pkg.yanked = false;
+
+        // Reinsert the version into the database, marking the index file as modified
+        let mut entry = expected.clone();
+        entry.time_of_last_update = chrono::Utc::now();
+        if db.replace_crate_version(&crate_name, &expected, &entry, &package)? {
+            return Ok((StatusCode::OK, Json(json!({"ok": true}))));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OwnersRequest {
+    users: Vec<String>,
+}
 
-    // Find the package to unyank
-    let Some(package) = entry.versions.iter_mut().find(|version| {
-        Version::parse(&version.pkg.vers).expect("all existing versions have valid identifiers")
-            == yank_version
-    }) else {
-        return create_error("crate does not have the specified version published");
+async fn list_owners(
+    reader: IndexReader,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Path(crate_name): Path<String>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    // Crates that cannot be read are reported the same as crates that do not exist
+    let entry = match db.get_crate_header(&crate_name)? {
+        Some(entry) if visibility::can_read(&db, &config, &entry, reader.username())? => entry,
+        _ => return create_error("crate does not exist in index"),
     };
 
-    if !package.pkg.yanked {
-        return create_error("version has not been yanked");
+    let users: Vec<_> = owner::describe(&db, &entry.owners)?
+        .into_iter()
+        .enumerate()
+        .map(|(id, owner)| {
+            let name = match owner.kind {
+                "user" => owner.name,
+                kind => format!("{} ({kind})", owner.name),
+            };
+            json!({ "id": id, "login": owner.login, "name": name })
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!({ "users": users }))))
+}
+
+async fn add_owners(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(crate_name): Path<String>,
+    Json(request): Json<OwnersRequest>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let event = AuditEvent::new(&user, AuditAction::AddOwners, &crate_name)
+        .token(token.label())
+        .ip(addr.ip());
+    let result = add_crate_owners(
+        ApiAuth(token, user),
+        State(db.clone()),
+        State(config),
+        Path(crate_name),
+        Json(request),
+    )
+    .await;
+
+    audit::record_api_result(&db, event, &result)?;
+    result
+}
+
+async fn add_crate_owners(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Path(crate_name): Path<String>,
+    Json(request): Json<OwnersRequest>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    info!(
+        "{} {} attempting to add owners {:?} to crate {} using token {}",
+        user.kind_label(),
+        user.username,
+        request.users,
+        crate_name,
+        token.label()
+    );
+
+    if !token.is_authorized(EndpointScope::ChangeOwners, &crate_name) {
+        return forbidden_error("token is not authorized to change the owners of this crate");
+    }
+    if !token.allows_mutation(&Mutation::Owners { name: &crate_name }) {
+        return forbidden_error("token was not issued to change the owners of this crate");
+    }
+
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        let Some(expected) = db.get_crate_header(&crate_name)? else {
+            return create_error("crate does not exist in index");
+        };
+        if !expected.is_local {
+            return create_error("cannot change the owners of an upstream crate");
+        }
+        if !owner::is_owner(&db, &config, &expected.owners, &user.username)? {
+            return forbidden_error("you are not an owner of this crate");
+        }
+
+        let mut entry = expected.clone();
+        for login in &request.users {
+            let new_owner = CrateOwner::parse(login);
+            match &new_owner {
+                CrateOwner::User(username) => {
+                    if db.get_user(username)?.is_none() {
+                        return create_error(&format!("user {username} does not exist"));
+                    }
+                }
+                CrateOwner::Team(team) => {
+                    // Only members of a team can give it ownership of a crate
+                    if db.get_team(team)?.is_none() {
+                        return create_error(&format!("team {team} does not exist"));
+                    }
+                    if !config.is_admin(&user.username)
+                        && !team::is_member(&db, team, &user.username)?
+                    {
+                        return forbidden_error(&format!("you are not a member of team {team}"));
+                    }
+                }
+            }
+            if !entry.owners.contains(&new_owner) {
+                entry.owners.push(new_owner);
+            }
+        }
+        if db.replace_crate_header(&crate_name, &expected, &entry)? {
+            break;
+        }
+    }
+
+    let msg = format!(
+        "added {} as owners of {crate_name}",
+        request.users.join(", ")
+    );
+    Ok((StatusCode::OK, Json(json!({ "ok": true, "msg": msg }))))
+}
+
+async fn remove_owners(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(crate_name): Path<String>,
+    Json(request): Json<OwnersRequest>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let event = AuditEvent::new(&user, AuditAction::RemoveOwners, &crate_name)
+        .token(token.label())
+        .ip(addr.ip());
+    let result = remove_crate_owners(
+        ApiAuth(token, user),
+        State(db.clone()),
+        State(config),
+        Path(crate_name),
+        Json(request),
+    )
+    .await;
+
+    audit::record_api_result(&db, event, &result)?;
+    result
+}
+
+async fn remove_crate_owners(
+    ApiAuth(token, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Path(crate_name): Path<String>,
+    Json(request): Json<OwnersRequest>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    info!(
+        "{} {} attempting to remove owners {:?} from crate {} using token {}",
+        user.kind_label(),
+        user.username,
+        request.users,
+        crate_name,
+        token.label()
+    );
+
+    if !token.is_authorized(EndpointScope::ChangeOwners, &crate_name) {
+        return forbidden_error("token is not authorized to change the owners of this crate");
+    }
+    if !token.allows_mutation(&Mutation::Owners { name: &crate_name }) {
+        return forbidden_error("token was not issued to change the owners of this crate");
     }
 
-    package.pkg.yanked = false;
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        let Some(expected) = db.get_crate_header(&crate_name)? else {
+            return create_error("crate does not exist in index");
+        };
+        if !owner::is_owner(&db, &config, &expected.owners, &user.username)? {
+            return forbidden_error("you are not an owner of this crate");
+        }
 
-    // Reinsert the crate into the database
-    db.insert_crate(&crate_name, &entry)?;
+        let removed: Vec<_> = request
+            .users
+            .iter()
+            .map(|login| CrateOwner::parse(login))
+            .collect();
+        let mut entry = expected.clone();
+        entry.owners.retain(|owner| !removed.contains(owner));
+        if entry.owners.is_empty() {
+            return create_error("cannot remove all owners of a crate");
+        }
+        if db.replace_crate_header(&crate_name, &expected, &entry)? {
+            break;
+        }
+    }
 
-    Ok((StatusCode::OK, Json(json!({"ok": true}))))
+    let msg = format!(
+        "removed {} as owners of {crate_name}",
+        request.users.join(", ")
+    );
+    Ok((StatusCode::OK, Json(json!({ "ok": true, "msg": msg }))))
 }
 
 #[derive(Serialize)]
@@ -243,41 +607,49 @@ struct SearchQuery {
 }
 
 async fn search_crates(
+    reader: Reader,
     State(db): State<crate::Db>,
+    State(config): State<Config>,
     Query(search_query): Query<SearchQuery>,
 ) -> Result<(StatusCode, Json<Value>), InternalError> {
-    let crates: Vec<_> = db
-        .iter_crates()
-        .filter(|(name, _entry)| name.contains(&search_query.q))
-        .collect();
+    let mut crates = Vec::new();
+    for (name, entry) in db.iter_crate_headers() {
+        if name.contains(&search_query.q)
+            && visibility::can_read(&db, &config, &entry, reader.username())?
+        {
+            crates.push((name, entry));
+        }
+    }
 
-    let total_count = crates.len();
+    let mut total_count = crates.len();
 
-    let crates: Vec<_> = crates
-        .into_iter()
-        .take(search_query.per_page)
-        .map(|(name, entry)| {
-            let most_recent = entry
-                .versions
-                .last()
-                .expect("crate has at least one version");
-            SearchResult {
-                name,
-                max_version: most_recent.pkg.vers.clone(),
-                description: most_recent
-                    .upload_meta
-                    .as_ref()
-                    .and_then(|meta| meta.description.clone())
-                    .unwrap_or_else(|| "".to_owned()),
-            }
-        })
-        .collect();
+    let mut results = Vec::new();
+    for (name, _) in crates {
+        if results.len() == search_query.per_page {
+            break;
+        }
+        // Versions that cannot be decoded are skipped, which can leave a crate with none to show
+        let versions = db.get_crate_versions(&name)?;
+        let Some(most_recent) = versions.last() else {
+            total_count -= 1;
+            continue;
+        };
+        results.push(SearchResult {
+            max_version: most_recent.pkg.vers.clone(),
+            description: most_recent
+                .upload_meta
+                .as_ref()
+                .and_then(|meta| meta.description.clone())
+                .unwrap_or_else(|| "".to_owned()),
+            name,
+        });
+    }
 
     Ok((
         StatusCode::OK,
         Json(json!({
             // Array of results.
-            "crates": crates,
+            "crates": results,
             "meta": {
                 // Total number of results available on the server.
                 "total": total_count
@@ -285,3 +657,182 @@ async fn search_crates(
         })),
     ))
 }
+
+/// A token reported by a secret scanner, in the format used by GitHub's secret scanning partner program.
+#[derive(Deserialize)]
+struct LeakedToken {
+    token: String,
+    #[serde(rename = "type", default)]
+    token_type: String,
+    #[serde(default)]
+    url: String,
+}
+
+#[derive(Serialize)]
+struct LeakedTokenResult {
+    token_raw: String,
+    token_type: String,
+    label: &'static str,
+}
+
+/// Revoke leaked tokens.
+///
+/// This does not require authentication, since holding a token is sufficient to be allowed to revoke it.
+async fn revoke_tokens(
+    State(db): State<crate::Db>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(leaked): Json<Vec<LeakedToken>>,
+) -> Result<(StatusCode, Json<Value>), InternalError> {
+    let mut results = Vec::with_capacity(leaked.len());
+    for leaked in leaked {
+        let label = match token::revoke(&db, &leaked.token)? {
+            Some(entry) => {
+                warn!(
+                    "revoked leaked token {} of user {} found at {}",
+                    entry.label(),
+                    entry.username(),
+                    leaked.url
+                );
+                // Tokens are revoked on the report of a secret scanner, rather than by an account
+                let event = AuditEvent::anonymous(AuditAction::TokenRevoke, entry.username())
+                    .token(entry.label())
+                    .ip(addr.ip());
+                audit::record(&db, &event)?;
+                "true_positive"
+            }
+            None => "false_positive",
+        };
+        results.push(LeakedTokenResult {
+            token_raw: leaked.token,
+            token_type: leaked.token_type,
+            label,
+        });
+    }
+
+    Ok((StatusCode::OK, Json(json!(results))))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        auth::{AccountOwner, User},
+        blob::FsBlobStore,
+        db::{Db, StoreKind},
+        test_util::{self, TempDir},
+        token::Credential,
+        visibility::Visibility,
+        Entry,
+    };
+
+    /// The body of a publish request for a version of `foo` with no dependencies.
+    fn publish_body(version: &str) -> Bytes {
+        let metadata = serde_json::to_vec(&json!({
+            "name": "foo",
+            "vers": version,
+            "deps": [],
+            "features": {},
+            "authors": [],
+            "description": null,
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": [],
+            "categories": [],
+            "license": null,
+            "license_file": null,
+            "repository": null,
+            "badges": {},
+            "links": null,
+        }))
+        .unwrap();
+        let data = b"crate file";
+        let mut body = Vec::new();
+        body.extend((metadata.len() as u32).to_le_bytes());
+        body.extend(metadata);
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(data);
+        body.into()
+    }
+
+    #[tokio::test]
+    async fn publish_without_docs_builder() {
+        let dir = TempDir::new("api-publish");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+        let mut config = test_util::config(&dir, true);
+        config.auth_required = true;
+        let account =
+            User::new_service_account("ci".to_owned(), AccountOwner::User("alice".to_owned()));
+        db.insert_user("ci", &account).unwrap();
+        let secret = token::create_token(&db, "ci", "deploy", None, None, None)
+            .unwrap()
+            .unwrap();
+        // A docs builder that has stopped must not fail publishes of versions that were stored
+        let (docs_queue_tx, docs_queue_rx) = mpsc::unbounded_channel();
+        drop(docs_queue_rx);
+
+        for version in ["1.0.0", "1.1.0"] {
+            let (entry, user) = token::lookup_token(&db, &secret).unwrap().unwrap();
+            let (status, _) = add_crate(
+                ApiAuth(Credential::Token(entry), user),
+                State(db.clone()),
+                State(config.clone()),
+                State(blobs.clone()),
+                State(docs_queue_tx.clone()),
+                ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))),
+                publish_body(version),
+            )
+            .await
+            .ok()
+            .unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert!(blobs
+                .exists(&blob::crate_key("foo", version))
+                .await
+                .unwrap());
+        }
+        assert_eq!(db.get_crate("foo").unwrap().unwrap().versions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn search_skips_crates_without_versions() {
+        let dir = TempDir::new("api-search");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let config = test_util::config(&dir, false);
+        for (name, versions) in [
+            ("foo", vec![test_util::package("foo", "1.0.0", "0")]),
+            ("foo-empty", vec![]),
+        ] {
+            let entry = Entry {
+                versions,
+                time_of_last_update: chrono::Utc::now(),
+                is_local: true,
+                owners: Vec::new(),
+                visibility: Visibility::Public,
+                upstream: None,
+            };
+            db.insert_crate(name, &entry).unwrap();
+        }
+
+        let (status, Json(body)) = search_crates(
+            Reader(None),
+            State(db),
+            State(config),
+            Query(SearchQuery {
+                q: "foo".to_owned(),
+                per_page: 10,
+            }),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["crates"].as_array().unwrap().len(), 1);
+        assert_eq!(body["crates"][0]["max_version"], "1.0.0");
+        assert_eq!(body["meta"]["total"], 1);
+    }
+}
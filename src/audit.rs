@@ -0,0 +1,484 @@
+use std::{fmt::Write, net::IpAddr, time::Duration};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    auth::AuthSession, auth::User, config::Config, token::ApiAuth, AppState, InternalError,
+};
+
+/// Number of events returned by a query when no limit is given
+const DEFAULT_LIMIT: usize = 100;
+/// Maximum number of events returned by a single query, other than an export
+const MAX_LIMIT: usize = 1000;
+/// How often old events are removed from the audit log
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// An action that is recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Publish,
+    Yank,
+    Unyank,
+    AddOwners,
+    RemoveOwners,
+    TokenCreate,
+    TokenDelete,
+    TokenRevoke,
+    Login,
+    /// Presenting a credential to the API, which is only recorded when it is refused
+    Authenticate,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Publish => "publish",
+            AuditAction::Yank => "yank",
+            AuditAction::Unyank => "unyank",
+            AuditAction::AddOwners => "add-owners",
+            AuditAction::RemoveOwners => "remove-owners",
+            AuditAction::TokenCreate => "token-create",
+            AuditAction::TokenDelete => "token-delete",
+            AuditAction::TokenRevoke => "token-revoke",
+            AuditAction::Login => "login",
+            AuditAction::Authenticate => "authenticate",
+        }
+    }
+}
+
+/// Whether an audited action was carried out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    /// The action was refused, with the reason given to the requester
+    Rejected(String),
+    /// The action failed because of an internal error
+    Error,
+    /// The credentials of the request were refused before the action was attempted, with the reason given to the
+    /// requester
+    Denied(String),
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Rejected(_) => "rejected",
+            Outcome::Error => "error",
+            Outcome::Denied(_) => "denied",
+        }
+    }
+}
+
+/// A single entry in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    /// Account that performed the action, if it was authenticated
+    pub actor: Option<String>,
+    /// Kind of the actor's account, so that service accounts can be told apart from people
+    pub actor_kind: Option<String>,
+    /// Label of the token used to perform the action
+    pub token: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub action: AuditAction,
+    /// What the action was performed on, such as a crate version or token label
+    pub target: String,
+    pub outcome: Outcome,
+}
+
+impl AuditEvent {
+    /// Create a successful event for an action performed by `actor`.
+    pub fn new(actor: &User, action: AuditAction, target: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            actor: Some(actor.username.clone()),
+            actor_kind: Some(actor.kind_label().to_owned()),
+            token: None,
+            ip: None,
+            action,
+            target: target.into(),
+            outcome: Outcome::Success,
+        }
+    }
+
+    /// Create a successful event for an action performed through the UI by a logged in user.
+    pub fn session(username: &str, action: AuditAction, target: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            actor: Some(username.to_owned()),
+            // Service accounts cannot log in, so sessions always belong to people
+            actor_kind: Some("user".to_owned()),
+            token: None,
+            ip: None,
+            action,
+            target: target.into(),
+            outcome: Outcome::Success,
+        }
+    }
+
+    /// Create a successful event for an action performed without an account.
+    pub fn anonymous(action: AuditAction, target: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            actor: None,
+            actor_kind: None,
+            token: None,
+            ip: None,
+            action,
+            target: target.into(),
+            outcome: Outcome::Success,
+        }
+    }
+
+    pub fn token(mut self, label: &str) -> Self {
+        self.token = Some(label.to_owned());
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = outcome;
+        self
+    }
+}
+
+/// Append an event to the audit log.
+pub fn record(db: &crate::Db, event: &AuditEvent) -> Result<(), anyhow::Error> {
+    db.insert_audit_event(event)
+}
+
+/// Record the result of an API request, taking the outcome from the response.
+pub fn record_api_result(
+    db: &crate::Db,
+    event: AuditEvent,
+    result: &Result<(StatusCode, Json<Value>), InternalError>,
+) -> Result<(), anyhow::Error> {
+    let outcome = match result {
+        Ok((status, _)) if status.is_success() => Outcome::Success,
+        Ok((_, Json(body))) => {
+            let detail = body["errors"][0]["detail"].as_str().unwrap_or_default();
+            Outcome::Rejected(detail.to_owned())
+        }
+        Err(_) => Outcome::Error,
+    };
+    record(db, &event.outcome(outcome))
+}
+
+/// Filters for querying the audit log, as given in a query string.
+///
+/// Empty fields do not filter, so that the query can be submitted directly from a form.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    actor: String,
+    #[serde(default)]
+    action: String,
+    #[serde(default)]
+    target: String,
+    #[serde(default)]
+    outcome: String,
+    /// First day to include, as `YYYY-MM-DD`
+    #[serde(default)]
+    since: String,
+    /// Last day to include, as `YYYY-MM-DD`
+    #[serde(default)]
+    until: String,
+    limit: Option<usize>,
+}
+
+/// A parsed audit query.
+struct AuditFilter<'a> {
+    query: &'a AuditQuery,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl AuditQuery {
+    fn filter(&self) -> Result<AuditFilter<'_>, anyhow::Error> {
+        let parse_day = |day: &str, offset: i64| -> Result<Option<DateTime<Utc>>, anyhow::Error> {
+            if day.is_empty() {
+                return Ok(None);
+            }
+            let day = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+                .map_err(|_| anyhow!("invalid date {day}, expected YYYY-MM-DD"))?;
+            let start =
+                Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).expect("midnight is a valid time"));
+            Ok(Some(start + chrono::Duration::days(offset)))
+        };
+
+        Ok(AuditFilter {
+            query: self,
+            since: parse_day(&self.since, 0)?,
+            // Include the whole of the last day
+            until: parse_day(&self.until, 1)?,
+        })
+    }
+}
+
+impl AuditFilter<'_> {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        let query = self.query;
+        (query.actor.is_empty() || event.actor.as_deref() == Some(query.actor.as_str()))
+            && (query.action.is_empty() || event.action.as_str() == query.action)
+            && (query.target.is_empty() || event.target.contains(&query.target))
+            && (query.outcome.is_empty() || event.outcome.as_str() == query.outcome)
+            && !matches!(self.since, Some(since) if event.timestamp < since)
+            && !matches!(self.until, Some(until) if event.timestamp >= until)
+    }
+}
+
+/// Find the most recent events matching `query`, newest first.
+fn query_events(db: &crate::Db, query: &AuditQuery) -> Result<Vec<AuditEvent>, anyhow::Error> {
+    let filter = query.filter()?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let mut events = Vec::new();
+    for event in db.iter_audit_events().rev() {
+        if filter.matches(&event) {
+            events.push(event);
+            if events.len() >= limit {
+                break;
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Export every event matching `query` as JSON lines, oldest first.
+fn export_events(db: &crate::Db, query: &AuditQuery) -> Result<String, anyhow::Error> {
+    let filter = query.filter()?;
+
+    let mut output = String::new();
+    for event in db.iter_audit_events() {
+        if filter.matches(&event) {
+            writeln!(output, "{}", serde_json::to_string(&event)?)?;
+        }
+    }
+    Ok(output)
+}
+
+fn export_response(body: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit.jsonl\"",
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Periodically remove events older than the configured retention from the audit log.
+pub fn start_retention_task(db: crate::Db, retention_days: Option<u32>) {
+    let Some(retention_days) = retention_days else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            // A retention too long to subtract from now keeps every event
+            let Some(cutoff) =
+                Utc::now().checked_sub_signed(chrono::Duration::days(retention_days.into()))
+            else {
+                continue;
+            };
+            match db.prune_audit_events(cutoff) {
+                Ok(0) => {}
+                Ok(removed) => info!("removed {removed} expired audit log events"),
+                Err(e) => warn!("could not remove expired audit log events: {e:?}"),
+            }
+        }
+    });
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/audit", get(audit_index))
+        .route("/audit/export", get(audit_export))
+}
+
+pub fn api_router() -> Router<AppState> {
+    Router::new()
+        .route("/v1/audit", get(api_audit_events))
+        .route("/v1/audit/export", get(api_audit_export))
+}
+
+fn forbidden() -> Response {
+    (StatusCode::FORBIDDEN, "only admins can view the audit log").into_response()
+}
+
+/// An audit event as displayed on the audit log page.
+#[derive(Serialize)]
+struct AuditEventView {
+    #[serde(flatten)]
+    event: AuditEvent,
+    outcome_kind: &'static str,
+    reason: Option<String>,
+}
+
+async fn audit_index(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&username) {
+        return Ok(forbidden());
+    }
+
+    let mut context = tera::Context::new();
+    match query_events(&db, &query) {
+        Ok(events) => {
+            let events: Vec<_> = events
+                .into_iter()
+                .map(|event| AuditEventView {
+                    outcome_kind: event.outcome.as_str(),
+                    reason: match &event.outcome {
+                        Outcome::Rejected(reason) | Outcome::Denied(reason) => Some(reason.clone()),
+                        _ => None,
+                    },
+                    event,
+                })
+                .collect();
+            context.insert("events", &events);
+        }
+        Err(e) => {
+            context.insert("events", &Vec::<AuditEventView>::new());
+            context.insert("warning", &e.to_string());
+        }
+    }
+    context.insert("query", &query);
+
+    let body = tera.render("audit.html", &context)?;
+    Ok((jar, Html(body)).into_response())
+}
+
+async fn audit_export(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&username) {
+        return Ok(forbidden());
+    }
+
+    match export_events(&db, &query) {
+        Ok(body) => Ok((jar, export_response(body)).into_response()),
+        Err(e) => Ok((StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+    }
+}
+
+fn api_error(status: StatusCode, detail: &str) -> Response {
+    (status, Json(json!({ "errors": [{ "detail": detail }] }))).into_response()
+}
+
+async fn api_audit_events(
+    ApiAuth(_, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&user.username) {
+        return Ok(api_error(
+            StatusCode::FORBIDDEN,
+            "only admins can view the audit log",
+        ));
+    }
+
+    match query_events(&db, &query) {
+        Ok(events) => Ok(Json(json!({ "events": events })).into_response()),
+        Err(e) => Ok(api_error(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+async fn api_audit_export(
+    ApiAuth(_, user): ApiAuth,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&user.username) {
+        return Ok(api_error(
+            StatusCode::FORBIDDEN,
+            "only admins can view the audit log",
+        ));
+    }
+
+    match export_events(&db, &query) {
+        Ok(body) => Ok(export_response(body)),
+        Err(e) => Ok(api_error(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(actor: &str, action: AuditAction, timestamp: &str) -> AuditEvent {
+        AuditEvent {
+            timestamp: DateTime::parse_from_rfc3339(timestamp)
+                .unwrap()
+                .with_timezone(&Utc),
+            actor: Some(actor.to_owned()),
+            actor_kind: Some("user".to_owned()),
+            token: None,
+            ip: None,
+            action,
+            target: "foo@1.0.0".to_owned(),
+            outcome: Outcome::Success,
+        }
+    }
+
+    #[test]
+    fn query_filters() {
+        let query = AuditQuery {
+            actor: "alice".to_owned(),
+            action: "yank".to_owned(),
+            since: "2022-10-01".to_owned(),
+            until: "2022-10-02".to_owned(),
+            ..Default::default()
+        };
+        let filter = query.filter().unwrap();
+
+        assert!(filter.matches(&event("alice", AuditAction::Yank, "2022-10-02T23:00:00Z")));
+        assert!(!filter.matches(&event("bob", AuditAction::Yank, "2022-10-02T23:00:00Z")));
+        assert!(!filter.matches(&event("alice", AuditAction::Unyank, "2022-10-02T23:00:00Z")));
+        assert!(!filter.matches(&event("alice", AuditAction::Yank, "2022-10-03T00:00:00Z")));
+        assert!(!filter.matches(&event("alice", AuditAction::Yank, "2022-09-30T23:59:59Z")));
+    }
+
+    #[test]
+    fn invalid_date_rejected() {
+        let query = AuditQuery {
+            since: "yesterday".to_owned(),
+            ..Default::default()
+        };
+        assert!(query.filter().is_err());
+    }
+}
@@ -1,7 +1,9 @@
+use std::{fmt, net::SocketAddr};
+
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts, State},
+    extract::{ConnectInfo, FromRef, FromRequestParts, State},
     http::request::Parts,
     response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
@@ -16,9 +18,16 @@ use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info};
 
-use crate::{token, AppState, InternalError};
+use crate::{
+    audit::{self, AuditAction, AuditEvent, Outcome},
+    paseto,
+    token::{self, EndpointScope},
+    AppState, InternalError,
+};
 
 static COOKIE_NAME: &str = "altreg_session";
+/// Longest time until a token expires that can be chosen, of about ten years
+const MAX_TOKEN_EXPIRY_DAYS: u32 = 3650;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -26,6 +35,56 @@ pub struct User {
     /// Argon2id hashed password
     password: String,
     pub blocked: bool,
+    pub kind: AccountKind,
+}
+
+impl User {
+    /// Create a service account, which has no password and so can only authenticate with tokens.
+    pub fn new_service_account(username: String, owner: AccountOwner) -> Self {
+        Self {
+            username,
+            password: String::new(),
+            blocked: false,
+            kind: AccountKind::Service { owner },
+        }
+    }
+
+    pub fn is_service_account(&self) -> bool {
+        matches!(self.kind, AccountKind::Service { .. })
+    }
+
+    /// Description of the kind of account, for use in logs and listings.
+    pub fn kind_label(&self) -> &'static str {
+        match self.kind {
+            AccountKind::Human => "user",
+            AccountKind::Service { .. } => "service account",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountKind {
+    /// A person who logs in through the web UI
+    Human,
+    /// A non-interactive account that only authenticates with tokens
+    Service { owner: AccountOwner },
+}
+
+/// The entity responsible for managing a service account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountOwner {
+    User(String),
+    /// Managed by the maintainers of the team
+    Team(String),
+}
+
+impl fmt::Display for AccountOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountOwner::User(username) => write!(f, "user {username}"),
+            AccountOwner::Team(team) => write!(f, "team {team}"),
+        }
+    }
 }
 
 pub fn router() -> Router<AppState> {
@@ -33,9 +92,11 @@ pub fn router() -> Router<AppState> {
         .route("/me", get(auth_me))
         .route(
             "/auth/tokens",
-            get(auth_tokens_page).post(auth_token_create),
+            get(auth_tokens_index).post(auth_token_create),
         )
         .route("/auth/tokens/delete", post(auth_tokens_delete))
+        .route("/auth/keys", get(auth_keys_index).post(auth_key_create))
+        .route("/auth/keys/delete", post(auth_keys_delete))
         .route("/auth/login", get(auth_login_page).post(auth_login))
         .route("/auth/logout", get(auth_logout))
         .route(
@@ -57,6 +118,7 @@ struct LoginParams {
 async fn auth_login(
     State(db): State<crate::Db>,
     State(tera): State<tera::Tera>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     session: Result<AuthSession, UnauthSession>,
     Form(login): Form<LoginParams>,
 ) -> Result<Response, InternalError> {
@@ -68,11 +130,30 @@ async fn auth_login(
         Err(UnauthSession(jar)) => jar,
     };
 
+    let audit_login = |user: Option<&User>, outcome: Outcome| {
+        let event = match user {
+            Some(user) => AuditEvent::new(user, AuditAction::Login, &user.username),
+            None => AuditEvent::anonymous(AuditAction::Login, &login.username),
+        };
+        audit::record(&db, &event.ip(addr.ip()).outcome(outcome))
+    };
+
     let Some(user) = db.get_user(&login.username)? else {
         // User doesn't exist in database
+        audit_login(None, Outcome::Rejected("non-existent user".into()))?;
         return auth_login_page(State(tera), Some("non-existent user".into())).await.map(|resp| resp.into_response());
     };
 
+    if user.is_service_account() {
+        audit_login(
+            Some(&user),
+            Outcome::Rejected("service accounts cannot log in".into()),
+        )?;
+        return auth_login_page(State(tera), Some("service accounts cannot log in".into()))
+            .await
+            .map(|resp| resp.into_response());
+    }
+
     // Check user password
     let parsed_hash = PasswordHash::new(&user.password)?;
     if Argon2::default()
@@ -80,18 +161,21 @@ async fn auth_login(
         .is_err()
     {
         // Incorrect password
+        audit_login(Some(&user), Outcome::Rejected("incorrect password".into()))?;
         return auth_login_page(State(tera), Some("incorrect password".into()))
             .await
             .map(|resp| resp.into_response());
     }
 
     if user.blocked {
+        audit_login(Some(&user), Outcome::Rejected("user blocked".into()))?;
         return auth_login_page(State(tera), Some("user blocked".into()))
             .await
             .map(|resp| resp.into_response());
     }
 
     info!("user {} logged in", login.username);
+    audit_login(Some(&user), Outcome::Success)?;
 
     // Set cookies
     let jar = set_auth_cookie(jar, login.username);
@@ -146,12 +230,14 @@ async fn auth_register(
         Err(UnauthSession(jar)) => jar,
     };
 
-    if db.get_user(&login.username)?.is_some() {
-        // User already exists in database
-        return auth_register_page(State(tera), Some("user already exists".into()))
-            .await
-            .map(|resp| resp.into_response());
-    };
+    if !is_valid_username(&login.username) {
+        return auth_register_page(
+            State(tera),
+            Some("usernames may only contain letters, numbers, - and _".into()),
+        )
+        .await
+        .map(|resp| resp.into_response());
+    }
 
     // Hash password
     let salt = argon2::password_hash::SaltString::generate(&mut OsRng);
@@ -165,8 +251,14 @@ async fn auth_register(
         username: login.username.clone(),
         password: password_hash,
         blocked: false,
+        kind: AccountKind::Human,
     };
-    db.insert_user(&user.username, &user)?;
+    if !db.create_user(&user.username, &user)? {
+        // User already exists in database
+        return auth_register_page(State(tera), Some("user already exists".into()))
+            .await
+            .map(|resp| resp.into_response());
+    }
 
     info!("user {} registered", login.username);
 
@@ -176,6 +268,15 @@ async fn auth_register(
     Ok((StatusCode::OK, jar, "register success").into_response())
 }
 
+/// Check whether a name for a new account is well formed.
+pub fn is_valid_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 async fn auth_register_page(
     State(tera): State<tera::Tera>,
     warning: Option<String>,
@@ -189,45 +290,269 @@ async fn auth_register_page(
 }
 
 #[derive(Deserialize)]
-struct TokenParams {
+pub struct TokenParams {
+    pub label: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenCreateParams {
     label: String,
+    #[serde(default)]
+    publish_new: bool,
+    #[serde(default)]
+    publish_update: bool,
+    #[serde(default)]
+    yank: bool,
+    #[serde(default)]
+    change_owners: bool,
+    /// Comma separated list of crate name patterns
+    #[serde(default)]
+    crates: String,
+    /// Number of days until the token expires, or 0 if it never expires
+    #[serde(default)]
+    expiry_days: u32,
+}
+
+impl TokenCreateParams {
+    fn endpoint_scopes(&self) -> Option<Vec<EndpointScope>> {
+        let scopes: Vec<_> = [
+            (self.publish_new, EndpointScope::PublishNew),
+            (self.publish_update, EndpointScope::PublishUpdate),
+            (self.yank, EndpointScope::Yank),
+            (self.change_owners, EndpointScope::ChangeOwners),
+        ]
+        .into_iter()
+        .filter_map(|(selected, scope)| selected.then_some(scope))
+        .collect();
+
+        // No selected scopes gives an unrestricted token
+        (!scopes.is_empty()).then_some(scopes)
+    }
+
+    fn crate_scopes(&self) -> Option<Vec<String>> {
+        let patterns: Vec<_> = self
+            .crates
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        (!patterns.is_empty()).then_some(patterns)
+    }
+
+    /// Time that the token expires at, or `Err` if it is too far in the future.
+    fn expires_at(&self) -> Result<Option<chrono::DateTime<chrono::Utc>>, String> {
+        if self.expiry_days == 0 {
+            return Ok(None);
+        }
+        if self.expiry_days > MAX_TOKEN_EXPIRY_DAYS {
+            return Err(format!(
+                "tokens can expire in at most {MAX_TOKEN_EXPIRY_DAYS} days"
+            ));
+        }
+        chrono::Utc::now()
+            .checked_add_signed(chrono::Duration::days(self.expiry_days.into()))
+            .map(Some)
+            .ok_or_else(|| "invalid expiry time".to_owned())
+    }
+}
+
+/// A token entry as displayed on the tokens page.
+#[derive(Serialize)]
+struct TokenView {
+    #[serde(flatten)]
+    entry: token::TokenEntry,
+    expired: bool,
+}
+
+/// Create a token for `username` from the parameters of the token form.
+///
+/// Returns the new token if one was created, otherwise a warning to be displayed to the user.
+pub fn create_token_from_form(
+    db: &crate::Db,
+    username: &str,
+    params: &TokenCreateParams,
+) -> Result<Result<Option<String>, String>, InternalError> {
+    let crate_scopes = params.crate_scopes();
+    if let Some(pattern) = crate_scopes
+        .iter()
+        .flatten()
+        .find(|pattern| !token::is_valid_crate_pattern(pattern))
+    {
+        return Ok(Err(format!("invalid crate pattern {pattern}")));
+    }
+    let expires_at = match params.expires_at() {
+        Ok(expires_at) => expires_at,
+        Err(warning) => return Ok(Err(warning)),
+    };
+
+    Ok(Ok(token::create_token(
+        db,
+        username,
+        &params.label,
+        params.endpoint_scopes(),
+        crate_scopes,
+        expires_at,
+    )?))
+}
+
+/// Record the creation of a token for `account` by the logged in user `username`.
+pub fn audit_token_create(
+    db: &crate::Db,
+    username: &str,
+    account: &str,
+    ip: std::net::IpAddr,
+    params: &TokenCreateParams,
+    result: &Result<Option<String>, String>,
+) -> Result<(), anyhow::Error> {
+    let outcome = match result {
+        Ok(Some(_)) => Outcome::Success,
+        Ok(None) => Outcome::Rejected("a token with this label already exists".to_owned()),
+        Err(warning) => Outcome::Rejected(warning.clone()),
+    };
+    let target = format!("{account}/{}", params.label);
+    let event = AuditEvent::session(username, AuditAction::TokenCreate, target)
+        .ip(ip)
+        .outcome(outcome);
+    audit::record(db, &event)
 }
+
+/// Render the list of tokens belonging to `username`.
+///
+/// The token forms will submit to `token_base`, so that this page can be used for both a user's own tokens and the
+/// tokens of a service account.
+pub fn render_tokens_page(
+    db: &crate::Db,
+    tera: &tera::Tera,
+    username: &str,
+    token_base: &str,
+    token: Option<String>,
+    warning: Option<String>,
+) -> Result<Html<String>, InternalError> {
+    let mut context = tera::Context::new();
+    if let Some(token) = token {
+        context.insert("token", &token);
+    }
+    if let Some(warning) = warning {
+        context.insert("warning", &warning);
+    }
+
+    let token_entries: Vec<_> = token::get_user_tokens(db, username)?
+        .into_iter()
+        .map(|entry| TokenView {
+            expired: entry.is_expired(),
+            entry,
+        })
+        .collect();
+    context.insert("token_entries", &token_entries);
+    context.insert("account", username);
+    context.insert("token_base", token_base);
+
+    let body = tera.render("tokens.html", &context)?;
+    Ok(Html(body))
+}
+
 async fn auth_token_create(
     AuthSession(username, jar): AuthSession,
     State(db): State<crate::Db>,
     State(tera): State<tera::Tera>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(params): Form<TokenCreateParams>,
+) -> Result<impl IntoResponse, InternalError> {
+    let result = create_token_from_form(&db, &username, &params)?;
+    audit_token_create(&db, &username, &username, addr.ip(), &params, &result)?;
+    let (token, warning) = match result {
+        Ok(token) => (token, None),
+        Err(warning) => (None, Some(warning)),
+    };
+
+    let body = render_tokens_page(&db, &tera, &username, "/auth/tokens", token, warning)?;
+    Ok((jar, body))
+}
+
+async fn auth_tokens_index(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(tera): State<tera::Tera>,
+) -> Result<impl IntoResponse, InternalError> {
+    let body = render_tokens_page(&db, &tera, &username, "/auth/tokens", None, None)?;
+    Ok((jar, body))
+}
+
+async fn auth_tokens_delete(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Form(params): Form<TokenParams>,
 ) -> Result<impl IntoResponse, InternalError> {
-    let token = token::create_token(&db, &username, &params.label)?;
+    token::delete(&db, &username, &params.label)?;
+    let target = format!("{username}/{}", params.label);
+    let event = AuditEvent::session(&username, AuditAction::TokenDelete, target).ip(addr.ip());
+    audit::record(&db, &event)?;
+
+    Ok((jar, Redirect::to("/auth/tokens")))
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    label: String,
+}
+
+#[derive(Deserialize)]
+struct KeyCreateParams {
+    label: String,
+    /// PASERK encoded public key
+    public_key: String,
+}
+
+async fn auth_key_create(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(tera): State<tera::Tera>,
+    Form(params): Form<KeyCreateParams>,
+) -> Result<impl IntoResponse, InternalError> {
+    let warning = paseto::add_public_key(&db, &username, &params.label, &params.public_key)?.err();
 
-    auth_tokens_page(AuthSession(username, jar), State(db), State(tera), token).await
+    auth_keys_page(AuthSession(username, jar), State(db), State(tera), warning).await
 }
 
-async fn auth_tokens_page(
+async fn auth_keys_index(
+    session: AuthSession,
+    State(db): State<crate::Db>,
+    State(tera): State<tera::Tera>,
+) -> Result<impl IntoResponse, InternalError> {
+    auth_keys_page(session, State(db), State(tera), None).await
+}
+
+async fn auth_keys_page(
     AuthSession(username, jar): AuthSession,
     State(db): State<crate::Db>,
     State(tera): State<tera::Tera>,
-    token: Option<String>,
+    warning: Option<String>,
 ) -> Result<impl IntoResponse, InternalError> {
     let mut context = tera::Context::new();
-    if let Some(token) = token {
-        context.insert("token", &token);
+    if let Some(warning) = warning {
+        context.insert("warning", &warning);
     }
 
-    context.insert("token_entries", &token::get_user_tokens(&db, &username)?);
+    context.insert(
+        "key_entries",
+        &paseto::get_user_public_keys(&db, &username)?,
+    );
 
-    let body = tera.render("tokens.html", &context)?;
+    let body = tera.render("keys.html", &context)?;
     Ok((jar, Html(body)))
 }
 
-async fn auth_tokens_delete(
+async fn auth_keys_delete(
     AuthSession(username, jar): AuthSession,
     State(db): State<crate::Db>,
-    Form(params): Form<TokenParams>,
+    Form(params): Form<KeyParams>,
 ) -> Result<impl IntoResponse, InternalError> {
-    token::delete(&db, &username, &params.label)?;
+    paseto::delete(&db, &username, &params.label)?;
 
-    Ok((jar, Redirect::to("/auth/tokens")))
+    Ok((jar, Redirect::to("/auth/keys")))
 }
 
 fn set_auth_cookie(jar: PrivateCookieJar, username: String) -> PrivateCookieJar {
@@ -239,8 +564,8 @@ fn set_auth_cookie(jar: PrivateCookieJar, username: String) -> PrivateCookieJar
     )
 }
 
-struct AuthSession(String, PrivateCookieJar);
-struct UnauthSession(PrivateCookieJar);
+pub struct AuthSession(pub String, pub PrivateCookieJar);
+pub struct UnauthSession(PrivateCookieJar);
 
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthSession
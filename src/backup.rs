@@ -0,0 +1,613 @@
+//! Backups of a registry's data directory.
+//!
+//! A backup is a gzipped tarball holding a snapshot of the metadata store (`db/` for sled, or `db.sqlite3`), the crate
+//! files under `crates/` and the generated docs under `docs/` copied from the blob store, and a `manifest.json` listing
+//! the checksum of every other file in the archive.
+//!
+//! The database can only be opened by one process at a time, so while the server is running it listens on a socket in
+//! the data directory and takes backups on behalf of `altreg backup`.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::UnixListener,
+    runtime::Handle,
+};
+use tracing::{info, warn};
+
+use crate::{
+    blob::{self, Blobs},
+    db::{Db, StoreKind},
+};
+
+/// Name of the manifest in a backup archive
+static MANIFEST_NAME: &str = "manifest.json";
+/// Name of the socket that the server takes backups through, in the data directory
+static SOCKET_NAME: &str = "backup.sock";
+/// Files and directories of the data directory that are included in a backup
+const DATA_DIRS: [&str; 4] = ["db", "db.sqlite3", "crates", "docs"];
+/// Prefixes of the blobs that are included in a backup, as directories of the archive
+const BLOB_DIRS: [&str; 2] = ["crates", "docs"];
+
+/// List of the files in a backup archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub created_at: DateTime<Utc>,
+    /// Version of the registry that created the backup
+    pub registry_version: String,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Path of the file relative to the data directory
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// The result of restoring a backup.
+#[derive(Debug)]
+pub struct RestoreReport {
+    pub files: usize,
+    /// Number of `.crate` files checked against the index
+    pub crates: usize,
+    /// Local versions whose `.crate` file is not in the backup
+    pub missing: Vec<String>,
+}
+
+/// A request made to the server through its backup socket.
+#[derive(Serialize, Deserialize)]
+struct BackupRequest {
+    output: PathBuf,
+}
+
+/// Write a backup of the registry to `output`.
+///
+/// The database is snapshotted before the files are copied, so that crate files written after the snapshot are left
+/// out. A version that was being published while the snapshot was taken is stored before its file is, so it may be in
+/// the backup without one, which restoring reports.
+///
+/// Snapshotting, reading files and compressing them all block, so the backup runs on a blocking thread rather than
+/// stalling the runtime of a server that takes it.
+pub async fn create(
+    db: &Db,
+    blobs: &Blobs,
+    data_dir: &Path,
+    output: &Path,
+) -> Result<Manifest, anyhow::Error> {
+    let (db, blobs, data_dir, output) = (
+        db.clone(),
+        blobs.clone(),
+        data_dir.to_owned(),
+        output.to_owned(),
+    );
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        runtime.block_on(create_blocking(&db, &blobs, &data_dir, &output))
+    })
+    .await?
+}
+
+async fn create_blocking(
+    db: &Db,
+    blobs: &Blobs,
+    data_dir: &Path,
+    output: &Path,
+) -> Result<Manifest, anyhow::Error> {
+    if output.exists() {
+        return Err(anyhow!("{} already exists", output.display()));
+    }
+
+    let staging = data_dir.join(format!(".backup-{}", Utc::now().format("%Y%m%d%H%M%S%f")));
+    let result = match fs::create_dir(&staging)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| db.snapshot(&staging.join(db.kind().file_name())))
+    {
+        Ok(()) => write_archive(&staging, db.kind(), blobs, output).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        warn!("could not remove {}: {e:?}", staging.display());
+    }
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// Write an archive of the `kind` database snapshot in `staging` and the crate files and docs in `blobs`.
+async fn write_archive(
+    staging: &Path,
+    kind: StoreKind,
+    blobs: &Blobs,
+    output: &Path,
+) -> Result<Manifest, anyhow::Error> {
+    let file =
+        File::create(output).with_context(|| format!("could not create {}", output.display()))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let mut files = Vec::new();
+    let mut add = |path: &Path, data: &[u8]| -> Result<(), anyhow::Error> {
+        append(&mut archive, path, data)?;
+        files.push(ManifestFile {
+            path: archive_path(path)?,
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(data)),
+        });
+        Ok(())
+    };
+    for path in list_files(&staging.join(kind.file_name()))? {
+        let data = fs::read(&path).with_context(|| format!("could not read {}", path.display()))?;
+        add(path.strip_prefix(staging)?, &data)?;
+    }
+    for dir in BLOB_DIRS {
+        for key in blobs.list(&format!("{dir}/")).await? {
+            // Blobs deleted since they were listed are skipped
+            if let Some(data) = blobs.get(&key).await? {
+                add(Path::new(&key), &data)?;
+            }
+        }
+    }
+
+    let manifest = Manifest {
+        created_at: Utc::now(),
+        registry_version: env!("CARGO_PKG_VERSION").to_owned(),
+        files,
+    };
+    append(
+        &mut archive,
+        Path::new(MANIFEST_NAME),
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    archive
+        .into_inner()?
+        .finish()?
+        .sync_all()
+        .with_context(|| "could not write backup")?;
+    Ok(manifest)
+}
+
+/// Recursively list the files in `dir`, which may be a single file or not exist.
+fn list_files(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    if dir.is_file() {
+        files.push(dir.to_owned());
+        return Ok(files);
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("could not read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(list_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn append(
+    archive: &mut tar::Builder<impl Write>,
+    path: &Path,
+    data: &[u8],
+) -> Result<(), anyhow::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    archive
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("could not add {} to backup", path.display()))
+}
+
+/// Path of a file in an archive, which must be relative and stay within the directory it is unpacked in.
+fn archive_path(path: &Path) -> Result<String, anyhow::Error> {
+    let components = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part
+                .to_str()
+                .ok_or_else(|| anyhow!("path {} is not valid UTF-8", path.display())),
+            _ => Err(anyhow!("invalid path {} in backup", path.display())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(components.join("/"))
+}
+
+/// Rebuild the data directory `data_dir` and the blob store `blobs` from the backup `archive`.
+///
+/// The archive is unpacked next to the data directory and checked against its manifest, and every `.crate` file is
+/// checked against the checksum in its index entry, before anything in the data directory or blob store is replaced.
+pub async fn restore(
+    archive: &Path,
+    blobs: &Blobs,
+    data_dir: &Path,
+    kind: StoreKind,
+) -> Result<RestoreReport, anyhow::Error> {
+    for kind in StoreKind::ALL {
+        let path = data_dir.join(kind.file_name());
+        if path.is_file() || (path.exists() && fs::read_dir(&path)?.next().is_some()) {
+            return Err(anyhow!(
+                "{} is not empty, restore into a new data directory",
+                path.display()
+            ));
+        }
+    }
+    for dir in BLOB_DIRS {
+        if !blobs.list(&format!("{dir}/")).await?.is_empty() {
+            return Err(anyhow!(
+                "blob store already has {dir}, restore into an empty blob store"
+            ));
+        }
+    }
+
+    let staging = data_dir.join(format!(".restore-{}", Utc::now().format("%Y%m%d%H%M%S%f")));
+    let result = match unpack(archive, &staging, kind) {
+        Ok(report) => move_into_place(&staging, blobs, data_dir, kind)
+            .await
+            .map(|_| report),
+        Err(e) => Err(e),
+    };
+    if staging.exists() {
+        if let Err(e) = fs::remove_dir_all(&staging) {
+            warn!("could not remove {}: {e:?}", staging.display());
+        }
+    }
+    result
+}
+
+/// Upload the files unpacked in `staging` to the blob store, then move the metadata store into the data directory.
+async fn move_into_place(
+    staging: &Path,
+    blobs: &Blobs,
+    data_dir: &Path,
+    kind: StoreKind,
+) -> Result<(), anyhow::Error> {
+    let mut uploaded = Vec::new();
+    let result = async {
+        for dir in BLOB_DIRS {
+            for path in list_files(&staging.join(dir))? {
+                let key = archive_path(path.strip_prefix(staging)?)?;
+                blobs.put(&key, fs::read(&path)?.into()).await?;
+                uploaded.push(key);
+            }
+        }
+
+        let (from, to) = (
+            staging.join(kind.file_name()),
+            data_dir.join(kind.file_name()),
+        );
+        if to.is_dir() {
+            fs::remove_dir(&to)?;
+        }
+        fs::rename(&from, &to)
+            .with_context(|| format!("could not move {} into place", to.display()))
+    }
+    .await;
+
+    // Leave the blob store empty again, so that the restore can be retried
+    if result.is_err() {
+        for key in uploaded {
+            if let Err(e) = blobs.delete(&key).await {
+                warn!("could not remove {key}: {e:?}");
+            }
+        }
+    }
+    result
+}
+
+/// Unpack and verify the backup `archive` into `staging`.
+fn unpack(archive: &Path, staging: &Path, kind: StoreKind) -> Result<RestoreReport, anyhow::Error> {
+    let file =
+        File::open(archive).with_context(|| format!("could not open {}", archive.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    let mut manifest = None;
+    let mut unpacked = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive_path(&entry.path()?)?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        if path == MANIFEST_NAME {
+            manifest = Some(
+                serde_json::from_slice::<Manifest>(&data).with_context(|| "invalid manifest")?,
+            );
+            continue;
+        }
+        if !DATA_DIRS
+            .iter()
+            .any(|dir| path == *dir || path.starts_with(&format!("{dir}/")))
+        {
+            return Err(anyhow!("unexpected file {path} in backup"));
+        }
+        let dest = staging.join(&path);
+        fs::create_dir_all(
+            dest.parent()
+                .expect("unpacked files are within a data directory"),
+        )?;
+        fs::write(&dest, &data).with_context(|| format!("could not write {}", dest.display()))?;
+        unpacked.insert(
+            path.clone(),
+            ManifestFile {
+                path,
+                size: data.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(&data)),
+            },
+        );
+    }
+
+    let manifest = manifest.ok_or_else(|| anyhow!("backup has no manifest"))?;
+    for file in &manifest.files {
+        match unpacked.remove(&file.path) {
+            Some(unpacked) if unpacked == *file => {}
+            Some(_) => return Err(anyhow!("{} does not match the manifest", file.path)),
+            None => return Err(anyhow!("{} is missing from the backup", file.path)),
+        }
+    }
+    if let Some(path) = unpacked.keys().next() {
+        return Err(anyhow!("{path} is not listed in the manifest"));
+    }
+
+    if !staging.join(kind.file_name()).exists() {
+        return Err(anyhow!(
+            "backup has no {kind} metadata store, restore it with the store it was taken with and use migrate-store"
+        ));
+    }
+    let (crates, missing) = verify_crates(staging, kind)?;
+    Ok(RestoreReport {
+        files: manifest.files.len(),
+        crates,
+        missing,
+    })
+}
+
+/// Check every `.crate` file in the data directory `data_dir` against the checksum in its index entry, returning the
+/// number of files checked and the local versions without a file.
+///
+/// Upstream crates are only cached, so they may be missing. Local versions that were being published while the backup
+/// was taken can be missing too, and are returned rather than failing the restore.
+fn verify_crates(data_dir: &Path, kind: StoreKind) -> Result<(usize, Vec<String>), anyhow::Error> {
+    let db = Db::open(kind, data_dir).with_context(|| "could not open database in backup")?;
+
+    let mut checked = 0;
+    let mut missing = Vec::new();
+    let mut problems = Vec::new();
+    for (name, entry) in db.iter_crates() {
+        for version in &entry.versions {
+            let vers = &version.pkg.vers;
+            let path = data_dir.join(blob::crate_key(&name, vers));
+            if !path.exists() {
+                if entry.is_local {
+                    warn!("{name}@{vers} has no crate file in the backup");
+                    missing.push(format!("{name}@{vers}"));
+                }
+                continue;
+            }
+
+            let cksum = format!("{:x}", Sha256::digest(fs::read(&path)?));
+            if cksum != version.pkg.cksum {
+                problems.push(format!("{name}@{vers} does not match its checksum"));
+            }
+            checked += 1;
+        }
+    }
+
+    // Files without an index entry are harmless, as they can never be downloaded
+    let crates_dir = data_dir.join("crates");
+    for path in list_files(&crates_dir)? {
+        let indexed = path
+            .strip_prefix(&crates_dir)
+            .ok()
+            .and_then(|path| Some((path.parent()?.to_str()?, path.file_stem()?.to_str()?)))
+            .and_then(|(name, vers)| Some((db.get_crate(name).ok()??, vers)))
+            .map(|(entry, vers)| {
+                entry
+                    .versions
+                    .iter()
+                    .any(|version| version.pkg.vers == vers)
+            });
+        if indexed != Some(true) {
+            warn!("{} has no index entry", path.display());
+        }
+    }
+
+    if problems.is_empty() {
+        Ok((checked, missing))
+    } else {
+        Err(anyhow!(
+            "backup failed verification: {}",
+            problems.join(", ")
+        ))
+    }
+}
+
+/// Listen for backup requests from `altreg backup` while the server is running.
+pub fn start_backup_listener(db: Db, blobs: Blobs, data_dir: PathBuf) -> Result<(), anyhow::Error> {
+    let socket = data_dir.join(SOCKET_NAME);
+    // The database is locked by this process, so a leftover socket is from a server that has stopped
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).with_context(|| "could not create backup socket")?;
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("could not accept backup request: {e:?}");
+                    continue;
+                }
+            };
+
+            let (db, blobs, data_dir) = (db.clone(), blobs.clone(), data_dir.clone());
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut line = String::new();
+                if let Err(e) = tokio::io::BufReader::new(reader).read_line(&mut line).await {
+                    warn!("could not read backup request: {e:?}");
+                    return;
+                }
+
+                let result = match serde_json::from_str::<BackupRequest>(&line) {
+                    Ok(request) => {
+                        info!("backing up registry to {}", request.output.display());
+                        create(&db, &blobs, &data_dir, &request.output).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                let response = result
+                    .map(|manifest| manifest.files.len())
+                    .map_err(|e| format!("{e:#}"));
+                if let Ok(mut response) = serde_json::to_vec(&response) {
+                    response.push(b'\n');
+                    let _ = writer.write_all(&response).await;
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Ask a running server to write a backup to `output`, returning the number of files backed up, or `None` if the
+/// server is not running.
+pub fn request_backup(data_dir: &Path, output: &Path) -> Result<Option<usize>, anyhow::Error> {
+    let Ok(mut stream) = UnixStream::connect(data_dir.join(SOCKET_NAME)) else {
+        return Ok(None);
+    };
+
+    let mut request = serde_json::to_vec(&BackupRequest {
+        output: output.to_owned(),
+    })?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Result<usize, String> =
+        serde_json::from_str(&line).with_context(|| "invalid response from server")?;
+    response.map(Some).map_err(|e| anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        test_util::{self, TempDir},
+        visibility::Visibility,
+        Entry,
+    };
+
+    /// Create a data directory using a `kind` store, with a local crate whose index entry has the checksum `cksum`.
+    fn create_registry(name: &str, kind: StoreKind, cksum: &str) -> (TempDir, Db) {
+        let dir = TempDir::new(name);
+        let db = Db::open(kind, &dir).unwrap();
+        let entry = Entry {
+            versions: vec![test_util::package("foo", "1.0.0", cksum)],
+            time_of_last_update: Utc::now(),
+            is_local: true,
+            owners: Vec::new(),
+            visibility: Visibility::Public,
+            upstream: None,
+        };
+        db.insert_crate("foo", &entry).unwrap();
+
+        let path = dir.join(blob::crate_key("foo", "1.0.0"));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"crate contents").unwrap();
+        (dir, db)
+    }
+
+    fn blobs(dir: &Path) -> Blobs {
+        Blobs::from_store(FsBlobStore::new(dir))
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        for kind in StoreKind::ALL {
+            let cksum = format!("{:x}", Sha256::digest(b"crate contents"));
+            let (dir, db) = create_registry(&format!("backup-{kind}"), kind, &cksum);
+            let output = dir.join("registry.tar.gz");
+            let manifest = create(&db, &blobs(&dir), &dir, &output).await.unwrap();
+            assert!(manifest
+                .files
+                .iter()
+                .any(|file| file.path == "crates/foo/1.0.0.crate"));
+
+            let restored = TempDir::new(&format!("restore-{kind}"));
+            let report = restore(&output, &blobs(&restored), &restored, kind)
+                .await
+                .unwrap();
+            assert_eq!(report.files, manifest.files.len());
+            assert_eq!(report.crates, 1);
+            assert!(report.missing.is_empty());
+            assert_eq!(
+                fs::read(restored.join(blob::crate_key("foo", "1.0.0"))).unwrap(),
+                b"crate contents"
+            );
+            assert!(Db::open(kind, &restored)
+                .unwrap()
+                .get_crate("foo")
+                .unwrap()
+                .is_some());
+
+            // Restoring over existing data is refused
+            assert!(restore(&output, &blobs(&restored), &restored, kind)
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_checks_crates() {
+        let (dir, db) = create_registry("backup-bad", StoreKind::Sled, "0000");
+        let output = dir.join("registry.tar.gz");
+        create(&db, &blobs(&dir), &dir, &output).await.unwrap();
+
+        let restored = TempDir::new("restore-bad");
+        assert!(
+            restore(&output, &blobs(&restored), &restored, StoreKind::Sled)
+                .await
+                .is_err()
+        );
+        assert!(!restored.join("db").exists());
+        assert!(!restored.join("crates").exists());
+        drop(db);
+    }
+
+    #[tokio::test]
+    async fn restore_without_crate_file() {
+        // A backup taken while a version was being published has the version but not its file
+        let (dir, db) = create_registry("backup-publishing", StoreKind::Sled, "0000");
+        fs::remove_file(dir.join(blob::crate_key("foo", "1.0.0"))).unwrap();
+        let output = dir.join("registry.tar.gz");
+        create(&db, &blobs(&dir), &dir, &output).await.unwrap();
+
+        let restored = TempDir::new("restore-publishing");
+        let report = restore(&output, &blobs(&restored), &restored, StoreKind::Sled)
+            .await
+            .unwrap();
+        assert_eq!(report.crates, 0);
+        assert_eq!(report.missing, ["foo@1.0.0"]);
+        drop(db);
+    }
+}
@@ -0,0 +1,350 @@
+mod s3;
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Context};
+use axum::{async_trait, body::Bytes};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::config::Config;
+pub use s3::S3BlobStore;
+
+/// Directory under the data directory that the filesystem blob store is kept in
+const FS_BLOB_DIR: &str = "blobs";
+/// Directories that the filesystem blob store kept directly in the data directory before it had its own
+const LEGACY_BLOB_DIRS: [&str; 3] = ["crates", "docs", "quarantine"];
+
+/// Number of temporary files written by this process, which keeps their names unique
+static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Which implementation of [`BlobStore`] crate files and docs are kept in, and how to reach it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BlobStoreConfig {
+    /// Files under `blobs/` in the data directory
+    #[default]
+    Filesystem,
+    /// A bucket in an S3-compatible object store, such as MinIO
+    S3(S3Config),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    /// URL of the object store, such as `http://localhost:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Address the bucket as `endpoint/bucket` rather than `bucket.endpoint`, which MinIO requires
+    #[serde(default = "default_path_style")]
+    pub path_style: bool,
+    /// Redirect downloads to presigned URLs that are valid for this many seconds, instead of proxying them
+    #[serde(default)]
+    pub presigned_download_secs: Option<u64>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+/// Storage of `.crate` files and generated docs, addressed by `/` separated keys.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Read a blob, or `None` if it does not exist.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, anyhow::Error>;
+    /// Write a blob, replacing it if it already exists.
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), anyhow::Error>;
+    async fn exists(&self, key: &str) -> Result<bool, anyhow::Error>;
+    /// Size of a blob in bytes, or `None` if it does not exist.
+    async fn size(&self, key: &str) -> Result<Option<u64>, anyhow::Error>;
+    /// Delete a blob, which is not an error if it does not exist.
+    async fn delete(&self, key: &str) -> Result<(), anyhow::Error>;
+    /// List the keys of every blob starting with `prefix`, in order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, anyhow::Error>;
+    /// A URL that a blob can be downloaded from directly for a limited time, if the store supports it.
+    fn presigned_url(&self, key: &str) -> Option<String>;
+}
+
+/// Key of the `.crate` file of a crate version.
+pub fn crate_key(name: &str, version: &str) -> String {
+    format!("crates/{name}/{version}.crate")
+}
+
+/// Key of a file in the generated docs of a crate version, where `path` is relative to the docs root.
+pub fn docs_key(name: &str, version: &str, path: &str) -> String {
+    format!("docs/{name}/{version}/{path}")
+}
+
+/// Check that a key is a relative path that cannot escape the store.
+pub fn validate_key(key: &str) -> Result<(), anyhow::Error> {
+    let valid = !key.is_empty()
+        && !key.contains('\\')
+        && Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("invalid blob key {key}"))
+    }
+}
+
+/// A [`BlobStore`] in a directory on the local filesystem, with keys as relative paths.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, anyhow::Error> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, anyhow::Error> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("could not read {key}")),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), anyhow::Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to a temporary file first, so that readers never see a partially written blob, named uniquely so that
+        // concurrent writes of the same blob do not write into each other's files
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            TMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            // The data must be on disk before the blob appears under its key, or a crash could leave it empty
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+        result.with_context(|| format!("could not write {key}"))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, anyhow::Error> {
+        Ok(tokio::fs::metadata(self.path(key)?)
+            .await
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false))
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, anyhow::Error> {
+        Ok(tokio::fs::metadata(self.path(key)?)
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), anyhow::Error> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("could not delete {key}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, anyhow::Error> {
+        // Only walk the directory that the prefix is in
+        let dir = match prefix.rfind('/') {
+            Some(end) => self.root.join(&prefix[..end]),
+            None => self.root.clone(),
+        };
+
+        let mut keys = Vec::new();
+        let mut pending = vec![dir];
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("could not read {}", dir.display()))
+                }
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let Some(key) = path
+                    .strip_prefix(&self.root)
+                    .ok()
+                    .and_then(|key| key.to_str())
+                    .map(|key| key.replace(std::path::MAIN_SEPARATOR, "/"))
+                else {
+                    continue;
+                };
+                if key.starts_with(prefix) && !key.ends_with(".tmp") {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn presigned_url(&self, _key: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Handle to the blob store that crate files and docs are kept in, shared between requests.
+#[derive(Clone)]
+pub struct Blobs {
+    store: Arc<dyn BlobStore>,
+}
+
+impl Deref for Blobs {
+    type Target = dyn BlobStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.store.as_ref()
+    }
+}
+
+impl Blobs {
+    /// Open the blob store configured in `config`.
+    pub fn open(config: &Config) -> Result<Self, anyhow::Error> {
+        let store: Arc<dyn BlobStore> = match &config.blob_store {
+            BlobStoreConfig::Filesystem => {
+                let root = config.data_dir.join(FS_BLOB_DIR);
+                move_legacy_blobs(&config.data_dir, &root)?;
+                Arc::new(FsBlobStore::new(root))
+            }
+            BlobStoreConfig::S3(s3) => Arc::new(S3BlobStore::new(s3)?),
+        };
+        Ok(Blobs { store })
+    }
+
+    #[cfg(test)]
+    pub fn from_store(store: impl BlobStore + 'static) -> Self {
+        Blobs {
+            store: Arc::new(store),
+        }
+    }
+}
+
+/// Move the blobs that older versions kept directly in the data directory `data_dir` into the blob store at `root`, so
+/// that listing the store does not also list the metadata store and backups.
+fn move_legacy_blobs(data_dir: &Path, root: &Path) -> Result<(), anyhow::Error> {
+    for dir in LEGACY_BLOB_DIRS {
+        let (from, to) = (data_dir.join(dir), root.join(dir));
+        if !from.is_dir() {
+            continue;
+        }
+        if to.exists() {
+            return Err(anyhow!(
+                "both {} and {} exist, move the files of one into the other",
+                from.display(),
+                to.display()
+            ));
+        }
+        fs::create_dir_all(root).with_context(|| format!("could not create {}", root.display()))?;
+        fs::rename(&from, &to)
+            .with_context(|| format!("could not move {} to {}", from.display(), to.display()))?;
+        info!("moved {} to {}", from.display(), to.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    #[tokio::test]
+    async fn filesystem_store() {
+        let root = TempDir::new("blobs");
+        let store = FsBlobStore::new(root.to_path_buf());
+
+        store
+            .put(&crate_key("foo", "1.0.0"), Bytes::from_static(b"one"))
+            .await
+            .unwrap();
+        store
+            .put(&crate_key("foo", "1.1.0"), Bytes::from_static(b"two"))
+            .await
+            .unwrap();
+        store
+            .put(&docs_key("foo", "1.0.0", "foo/index.html"), Bytes::new())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get("crates/foo/1.0.0.crate").await.unwrap().unwrap(),
+            Bytes::from_static(b"one")
+        );
+        assert_eq!(
+            store.list("crates/").await.unwrap(),
+            ["crates/foo/1.0.0.crate", "crates/foo/1.1.0.crate"]
+        );
+        assert_eq!(
+            store.list("docs/foo/").await.unwrap(),
+            ["docs/foo/1.0.0/foo/index.html"]
+        );
+
+        store.delete("crates/foo/1.0.0.crate").await.unwrap();
+        assert!(!store.exists("crates/foo/1.0.0.crate").await.unwrap());
+        assert!(store.get("crates/foo/1.0.0.crate").await.unwrap().is_none());
+        assert!(store.get("../escape").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_blobs_moved() {
+        let data_dir = TempDir::new("blobs-legacy");
+        fs::create_dir_all(data_dir.join("crates/foo")).unwrap();
+        fs::write(data_dir.join("crates/foo/1.0.0.crate"), b"one").unwrap();
+        fs::create_dir(data_dir.join("db")).unwrap();
+
+        let config = test_util::config(&data_dir, false);
+        let blobs = Blobs::open(&config).unwrap();
+        assert_eq!(blobs.list("").await.unwrap(), ["crates/foo/1.0.0.crate"]);
+        assert!(!data_dir.join("crates").exists());
+
+        // Opening the store again leaves it as it is
+        let blobs = Blobs::open(&config).unwrap();
+        assert_eq!(
+            blobs.get("crates/foo/1.0.0.crate").await.unwrap().unwrap(),
+            Bytes::from_static(b"one")
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Size-limited caching of upstream crate files.
+//!
+//! Every cached upstream `.crate` file has a record of its size and when it was last downloaded, and when the cache
+//! grows past its limit the least recently used files are evicted. Local crates are never tracked or evicted.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    auth::AuthSession,
+    blob::Blobs,
+    config::Config,
+    db::Db,
+    mirror::{self, MirrorReport, MirrorSource},
+    token::ApiAuth,
+    AppState, InternalError,
+};
+
+/// How often the cache is checked against its size limit
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Number of requests to upstream at once while warming the cache from a lockfile
+const WARM_CONCURRENCY: usize = 8;
+/// Number of evictions kept for the cache page
+const EVICTION_HISTORY: usize = 100;
+
+/// Usage of a cached upstream crate file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedBlob {
+    pub size: u64,
+    pub last_access: DateTime<Utc>,
+    /// Number of downloads served from the cache
+    pub hits: u64,
+}
+
+/// A cached file that was removed to keep the cache within its limit.
+#[derive(Debug, Clone, Serialize)]
+pub struct Eviction {
+    pub key: String,
+    pub size: u64,
+    pub last_access: DateTime<Utc>,
+    pub evicted_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Most recent evictions, oldest first
+    evictions: Mutex<VecDeque<Eviction>>,
+}
+
+/// Hit rate and eviction history of the cache since the server started.
+#[derive(Clone, Default)]
+pub struct CacheStats(Arc<Stats>);
+
+impl CacheStats {
+    /// Record that the cached file `key` was downloaded.
+    pub fn record_hit(&self, db: &Db, key: &str) -> Result<(), anyhow::Error> {
+        self.0.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(mut blob) = db.get_cached_blob(key)? {
+            blob.last_access = Utc::now();
+            blob.hits += 1;
+            db.insert_cached_blob(key, &blob)?;
+        }
+        Ok(())
+    }
+
+    /// Record that `key` was fetched from upstream and cached, with a size of `size` bytes.
+    pub fn record_miss(&self, db: &Db, key: &str, size: u64) -> Result<(), anyhow::Error> {
+        self.0.misses.fetch_add(1, Ordering::Relaxed);
+        db.insert_cached_blob(
+            key,
+            &CachedBlob {
+                size,
+                last_access: Utc::now(),
+                hits: 0,
+            },
+        )
+    }
+
+    fn record_evictions(&self, evictions: Vec<Eviction>) {
+        let mut history = self.0.evictions.lock().unwrap_or_else(|e| e.into_inner());
+        history.extend(evictions);
+        while history.len() > EVICTION_HISTORY {
+            history.pop_front();
+        }
+    }
+}
+
+/// Whether the crate of the file `key` is published to this registry.
+fn is_local_crate(db: &Db, key: &str) -> Result<bool, anyhow::Error> {
+    let name = key
+        .strip_prefix("crates/")
+        .and_then(|path| path.split_once('/'))
+        .map(|(name, _)| name);
+    Ok(match name {
+        Some(name) => db
+            .get_crate_header(name)?
+            .is_some_and(|entry| entry.is_local),
+        None => false,
+    })
+}
+
+/// Start tracking upstream crate files that were cached before their usage was recorded, as if they were just used.
+pub async fn track_existing(db: &Db, blobs: &Blobs) -> Result<usize, anyhow::Error> {
+    let mut tracked = 0;
+    for key in blobs.list("crates/").await? {
+        if db.get_cached_blob(&key)?.is_some() || is_local_crate(db, &key)? {
+            continue;
+        }
+        if let Some(size) = blobs.size(&key).await? {
+            db.insert_cached_blob(
+                &key,
+                &CachedBlob {
+                    size,
+                    last_access: Utc::now(),
+                    hits: 0,
+                },
+            )?;
+            tracked += 1;
+        }
+    }
+    Ok(tracked)
+}
+
+/// Remove the least recently used upstream crate files until the cache is no larger than `max_bytes`.
+pub async fn evict(db: &Db, blobs: &Blobs, max_bytes: u64) -> Result<Vec<Eviction>, anyhow::Error> {
+    let mut cached: Vec<_> = db.iter_cached_blobs().collect();
+    cached.sort_by_key(|(_, blob)| blob.last_access);
+    let mut total: u64 = cached.iter().map(|(_, blob)| blob.size).sum();
+
+    let mut evictions = Vec::new();
+    for (key, blob) in cached {
+        if total <= max_bytes {
+            break;
+        }
+        // A crate published after its upstream files were cached keeps them
+        if !is_local_crate(db, &key)? {
+            blobs.delete(&key).await?;
+            evictions.push(Eviction {
+                key: key.clone(),
+                size: blob.size,
+                last_access: blob.last_access,
+                evicted_at: Utc::now(),
+            });
+        }
+        db.remove_cached_blob(&key)?;
+        total -= blob.size;
+    }
+    Ok(evictions)
+}
+
+/// Periodically evict upstream crate files while the cache is larger than the configured limit.
+///
+/// Nothing is evicted while the registry is offline, as evicted files could not be downloaded again.
+pub fn start_eviction_task(db: Db, blobs: Blobs, stats: CacheStats, config: &Config) {
+    let Some(max_mb) = config.upstream_cache_max_mb else {
+        return;
+    };
+    if config.offline {
+        warn!(
+            "the registry is offline, so upstream crate files will not be evicted from the cache"
+        );
+        return;
+    }
+    tokio::spawn(async move {
+        match track_existing(&db, &blobs).await {
+            Ok(0) => {}
+            Ok(tracked) => info!("tracking {tracked} previously cached upstream crate files"),
+            Err(e) => warn!("could not track previously cached upstream crate files: {e:?}"),
+        }
+
+        let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            match evict(&db, &blobs, max_mb * 1024 * 1024).await {
+                Ok(evictions) if evictions.is_empty() => {}
+                Ok(evictions) => {
+                    info!("evicted {} upstream crate files", evictions.len());
+                    stats.record_evictions(evictions);
+                }
+                Err(e) => warn!("could not evict upstream crate files: {e:?}"),
+            }
+        }
+    });
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/cache", get(cache_index))
+        .route("/cache/warm", post(cache_warm))
+}
+
+pub fn api_router() -> Router<AppState> {
+    Router::new().route("/v1/cache/warm", post(api_cache_warm))
+}
+
+/// Fetch every registry package in `lockfile` into the cache, along with its index entry, or return why it could not be
+/// done.
+async fn warm(
+    db: &Db,
+    blobs: &Blobs,
+    config: &Config,
+    stats: &CacheStats,
+    lockfile: &str,
+) -> Result<Result<MirrorReport, String>, anyhow::Error> {
+    if config.offline {
+        return Ok(Err("the registry is offline".to_owned()));
+    }
+    let source = match MirrorSource::from_lockfile(lockfile) {
+        Ok(source) => source,
+        Err(e) => return Ok(Err(format!("{e:#}"))),
+    };
+    let report = mirror::populate(db, blobs, config, stats, source, WARM_CONCURRENCY).await?;
+    info!("warmed the cache from a lockfile: {report}");
+    Ok(Ok(report))
+}
+
+#[derive(Deserialize)]
+struct WarmParams {
+    lockfile: String,
+}
+
+async fn cache_warm(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<Db>,
+    State(blobs): State<Blobs>,
+    State(config): State<Config>,
+    State(stats): State<CacheStats>,
+    State(tera): State<tera::Tera>,
+    Form(params): Form<WarmParams>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&username) {
+        return Ok((StatusCode::FORBIDDEN, "only admins can warm the cache").into_response());
+    }
+
+    let mut context = tera::Context::new();
+    match warm(&db, &blobs, &config, &stats, &params.lockfile).await? {
+        Ok(report) => context.insert("report", &report),
+        Err(warning) => context.insert("warning", &warning),
+    }
+
+    let body = tera.render("cache_warm.html", &context)?;
+    Ok((jar, Html(body)).into_response())
+}
+
+async fn api_cache_warm(
+    ApiAuth(_, user): ApiAuth,
+    State(db): State<Db>,
+    State(blobs): State<Blobs>,
+    State(config): State<Config>,
+    State(stats): State<CacheStats>,
+    lockfile: String,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&user.username) {
+        return Ok(api_error(
+            StatusCode::FORBIDDEN,
+            "only admins can warm the cache",
+        ));
+    }
+
+    match warm(&db, &blobs, &config, &stats, &lockfile).await? {
+        Ok(report) => Ok(Json(report).into_response()),
+        Err(e) => Ok(api_error(StatusCode::BAD_REQUEST, &e)),
+    }
+}
+
+fn api_error(status: StatusCode, detail: &str) -> Response {
+    (status, Json(json!({ "errors": [{ "detail": detail }] }))).into_response()
+}
+
+async fn cache_index(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<Db>,
+    State(config): State<Config>,
+    State(stats): State<CacheStats>,
+    State(tera): State<tera::Tera>,
+) -> Result<Response, InternalError> {
+    if !config.is_admin(&username) {
+        return Ok((StatusCode::FORBIDDEN, "only admins can view the cache").into_response());
+    }
+
+    let (mut files, mut used_bytes) = (0, 0);
+    for (_, blob) in db.iter_cached_blobs() {
+        files += 1;
+        used_bytes += blob.size;
+    }
+    let hits = stats.0.hits.load(Ordering::Relaxed);
+    let misses = stats.0.misses.load(Ordering::Relaxed);
+    let evictions: Vec<_> = stats
+        .0
+        .evictions
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .rev()
+        .cloned()
+        .collect();
+
+    let mut context = tera::Context::new();
+    context.insert("files", &files);
+    context.insert("used_mb", &(used_bytes as f64 / (1024.0 * 1024.0)));
+    context.insert("max_mb", &config.upstream_cache_max_mb);
+    context.insert("hits", &hits);
+    context.insert("misses", &misses);
+    context.insert(
+        "hit_rate",
+        &(hits + misses > 0).then(|| 100.0 * hits as f64 / (hits + misses) as f64),
+    );
+    context.insert("evictions", &evictions);
+
+    let body = tera.render("cache.html", &context)?;
+    Ok((jar, Html(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{Bytes, HttpBody};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{
+        auth::{AccountOwner, User},
+        blob::{self, FsBlobStore},
+        db::StoreKind,
+        test_util::{self, TempDir},
+        token::{self, Credential},
+        visibility::Visibility,
+        Entry,
+    };
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let dir = TempDir::new("cache");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+
+        let local = Entry {
+            versions: Vec::new(),
+            time_of_last_update: Utc::now(),
+            is_local: true,
+            owners: Vec::new(),
+            visibility: Visibility::Public,
+            upstream: None,
+        };
+        db.insert_crate("mine", &local).unwrap();
+        let data = Bytes::from_static(&[0; 100]);
+        for key in ["a", "b", "c", "mine"].map(|name| blob::crate_key(name, "1.0.0")) {
+            blobs.put(&key, data.clone()).await.unwrap();
+        }
+
+        assert_eq!(track_existing(&db, &blobs).await.unwrap(), 3);
+        let stats = CacheStats::default();
+        stats
+            .record_hit(&db, &blob::crate_key("a", "1.0.0"))
+            .unwrap();
+
+        let evictions = evict(&db, &blobs, 150).await.unwrap();
+        let evicted: Vec<_> = evictions.iter().map(|eviction| &eviction.key).collect();
+        assert_eq!(evicted, ["crates/b/1.0.0.crate", "crates/c/1.0.0.crate"]);
+        assert!(blobs.exists("crates/a/1.0.0.crate").await.unwrap());
+        assert!(blobs.exists("crates/mine/1.0.0.crate").await.unwrap());
+        assert_eq!(db.iter_cached_blobs().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn warms_from_lockfile() {
+        let dir = TempDir::new("cache-warm");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+        let stats = CacheStats::default();
+
+        let cksum = format!("{:x}", Sha256::digest(b"contents"));
+        let entry = |is_local, upstream: Option<&str>| Entry {
+            versions: vec![
+                test_util::package("serde", "1.0.1", "0"),
+                test_util::package("serde", "1.0.0", &cksum),
+            ],
+            time_of_last_update: Utc::now(),
+            is_local,
+            owners: Vec::new(),
+            visibility: Visibility::Public,
+            upstream: upstream.map(str::to_owned),
+        };
+        db.insert_crate("serde", &entry(false, Some("crates-io")))
+            .unwrap();
+        db.insert_crate("mine", &entry(true, None)).unwrap();
+        blobs
+            .put(
+                &blob::crate_key("serde", "1.0.0"),
+                Bytes::from_static(b"contents"),
+            )
+            .await
+            .unwrap();
+
+        let lockfile = format!(
+            r#"
+[[package]]
+name = "serde"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "{cksum}"
+
+[[package]]
+name = "serde"
+version = "1.0.1"
+source = "sparse+https://index.crates.io/"
+checksum = "{cksum}"
+
+[[package]]
+name = "mine"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "internal"
+version = "2.0.0"
+source = "sparse+https://altreg.example.com/"
+"#
+        );
+
+        let offline = test_util::config(&dir, true);
+        assert_eq!(
+            warm(&db, &blobs, &offline, &stats, &lockfile)
+                .await
+                .unwrap()
+                .unwrap_err(),
+            "the registry is offline"
+        );
+
+        let config = test_util::config(&dir, false);
+        assert!(warm(&db, &blobs, &config, &stats, "[[package]")
+            .await
+            .unwrap()
+            .is_err());
+        let report = warm(&db, &blobs, &config, &stats, &lockfile)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.index_files, 0);
+        assert_eq!(report.already_cached, ["serde@1.0.0"]);
+        assert!(report.fetched.is_empty());
+        assert_eq!(
+            report.failed,
+            [
+                (
+                    "internal@2.0.0".to_owned(),
+                    "sparse+https://altreg.example.com/ is not a configured upstream".to_owned()
+                ),
+                (
+                    "mine@1.0.0".to_owned(),
+                    "Cargo.lock expects it from crates-io, but it comes from this registry"
+                        .to_owned()
+                ),
+                (
+                    "serde@1.0.1".to_owned(),
+                    "checksum in Cargo.lock does not match the index of crates-io".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_warm_through_api() {
+        let dir = TempDir::new("cache-warm-api");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+        let config = test_util::config(&dir, false);
+
+        let warm_as = |username: &str| {
+            let account = User::new_service_account(
+                username.to_owned(),
+                AccountOwner::User("alice".to_owned()),
+            );
+            db.insert_user(username, &account).unwrap();
+            let secret = token::create_token(&db, username, "ci", None, None, None)
+                .unwrap()
+                .unwrap();
+            let (entry, user) = token::lookup_token(&db, &secret).unwrap().unwrap();
+            api_cache_warm(
+                ApiAuth(Credential::Token(entry), user),
+                State(db.clone()),
+                State(blobs.clone()),
+                State(config.clone()),
+                State(CacheStats::default()),
+                "[[package]]\nname = \"app\"\nversion = \"0.1.0\"\n".to_owned(),
+            )
+        };
+
+        let response = warm_as("someone").await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = warm_as("admin").await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().data().await.unwrap().unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["already_cached"], json!([]));
+        assert_eq!(report["failed"], json!([]));
+    }
+}
@@ -1,8 +1,11 @@
 use std::{fs, net::IpAddr, path::PathBuf};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use chrono::Duration;
 use serde::Deserialize;
 
+use crate::{blob::BlobStoreConfig, db::StoreKind, mirror::UpstreamConfig, visibility::Visibility};
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub host: IpAddr,
@@ -12,13 +15,134 @@ pub struct Config {
     pub offline: bool,
     pub tls_cert: PathBuf,
     pub tls_key: PathBuf,
+    /// Users with administrative rights over the registry
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Visibility of newly published crates
+    #[serde(default)]
+    pub default_visibility: Visibility,
+    /// Require a token for every index, download and docs request
+    #[serde(default)]
+    pub auth_required: bool,
+    /// Number of days to keep audit log events for, or forever if not set
+    #[serde(default)]
+    pub audit_retention_days: Option<u32>,
+    /// Where crate, user and token metadata is stored
+    #[serde(default)]
+    pub metadata_store: StoreKind,
+    /// Where crate files and generated docs are stored
+    #[serde(default)]
+    pub blob_store: BlobStoreConfig,
+    /// Size limit of cached upstream crate files in megabytes, beyond which the least recently used are evicted
+    #[serde(default)]
+    pub upstream_cache_max_mb: Option<u64>,
+    /// Seconds that a cached upstream index file is served for before it is fetched again
+    #[serde(default = "default_upstream_index_ttl_secs")]
+    pub upstream_index_ttl_secs: u64,
+    /// Seconds past its TTL that an upstream index file is still served while it is refreshed in the background.
+    /// Older files are refreshed before responding, and are only served if upstream cannot be reached.
+    #[serde(default = "default_upstream_index_max_stale_secs")]
+    pub upstream_index_max_stale_secs: u64,
+    /// Seconds to remember that a crate does not exist upstream, or 0 to always look it up again
+    #[serde(default = "default_upstream_negative_ttl_secs")]
+    pub upstream_negative_ttl_secs: u64,
+    /// Template of the URLs that Cargo downloads crate files from, such as a CDN in front of the blob store, instead of
+    /// this registry's own download endpoint. It may contain the same markers as the `dl` field of Cargo's `config.json`.
+    #[serde(default)]
+    pub dl_template: Option<String>,
+    /// Registries that crates not published locally are fetched from, in order of priority
+    #[serde(default = "default_upstreams")]
+    pub upstreams: Vec<UpstreamConfig>,
+}
+
+/// Longest that any of the upstream TTL settings may be, which keeps the times computed from them in range
+const MAX_TTL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+fn default_upstreams() -> Vec<UpstreamConfig> {
+    vec![UpstreamConfig::crates_io()]
+}
+
+fn default_upstream_index_ttl_secs() -> u64 {
+    30 * 60
+}
+
+fn default_upstream_index_max_stale_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_upstream_negative_ttl_secs() -> u64 {
+    5 * 60
+}
+
+impl Config {
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+
+    pub fn upstream_index_ttl(&self) -> Duration {
+        ttl(self.upstream_index_ttl_secs)
+    }
+
+    pub fn upstream_index_max_stale(&self) -> Duration {
+        ttl(self.upstream_index_max_stale_secs)
+    }
+
+    pub fn upstream_negative_ttl(&self) -> Duration {
+        ttl(self.upstream_negative_ttl_secs)
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        for (name, secs) in [
+            ("upstream_index_ttl_secs", self.upstream_index_ttl_secs),
+            (
+                "upstream_index_max_stale_secs",
+                self.upstream_index_max_stale_secs,
+            ),
+            (
+                "upstream_negative_ttl_secs",
+                self.upstream_negative_ttl_secs,
+            ),
+        ] {
+            if secs > MAX_TTL_SECS {
+                return Err(anyhow!("{name} must be at most {MAX_TTL_SECS}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A TTL setting as a duration, limited to [`MAX_TTL_SECS`] for configs that were not validated.
+fn ttl(secs: u64) -> Duration {
+    Duration::seconds(secs.min(MAX_TTL_SECS) as i64)
 }
 
 pub fn load() -> Result<Config, anyhow::Error> {
-    let config = toml::from_str(
+    let config: Config = toml::from_str(
         &fs::read_to_string("config.toml").with_context(|| "unable to read config file")?,
     )
     .with_context(|| "unable to decode config")?;
+    config.validate()?;
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::Utc;
+
+    use crate::test_util;
+
+    #[test]
+    fn ttls_are_limited() {
+        let mut config = test_util::config(Path::new("data"), false);
+        assert!(config.validate().is_ok());
+
+        config.upstream_index_max_stale_secs = u64::MAX;
+        assert!(config.validate().is_err());
+        // Durations are computed in range even from settings that were not validated
+        let expires = Utc::now() + config.upstream_index_ttl() + config.upstream_index_max_stale();
+        assert!(expires > Utc::now());
+    }
+}
@@ -1,41 +1,497 @@
-use std::path::Path;
+mod codec;
+mod migrations;
+mod sqlite;
+
+use std::{fmt, ops::Deref, path::Path, sync::Arc};
 
 use anyhow::{anyhow, Context};
-use tracing::warn;
+use serde::Deserialize;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+use tracing::{info, warn};
 
-use crate::{auth, token::TokenEntry, Entry};
+use crate::{
+    audit::AuditEvent, auth, cache::CachedBlob, package::UploadedPackage, paseto::PublicKeyEntry,
+    team::Team, token::TokenEntry, CrateHeader, Entry,
+};
+use codec::Record;
+pub use sqlite::SqliteStore;
 
-const DB_VERSION: u32 = 2;
+/// Version of the layout of the sled database.
+///
+/// Every change to the layout of a stored record that older records cannot be decoded with must bump this in the same
+/// change that adds its migration to `migrations::MIGRATIONS`, so that every release can open the databases of the
+/// ones before it. Versions 2 to 8 each changed one bincode layout and are migrated one step at a time.
+const DB_VERSION: u32 = 10;
 static DB_VERSION_KEY: &str = "version";
 
-#[derive(Debug, Clone)]
+/// Which implementation of [`MetadataStore`] the registry keeps its metadata in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// An embedded sled database, in `data_dir/db`
+    #[default]
+    Sled,
+    /// An embedded SQLite database, in `data_dir/db.sqlite3`
+    Sqlite,
+}
+
+impl StoreKind {
+    pub const ALL: [StoreKind; 2] = [StoreKind::Sled, StoreKind::Sqlite];
+
+    /// Name of the store's file or directory in the data directory.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            StoreKind::Sled => "db",
+            StoreKind::Sqlite => "db.sqlite3",
+        }
+    }
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreKind::Sled => write!(f, "sled"),
+            StoreKind::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sled" => Ok(StoreKind::Sled),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            _ => Err(anyhow!(
+                "unknown metadata store {s}, expected sled or sqlite"
+            )),
+        }
+    }
+}
+
+/// Storage of the registry's crates, users, tokens, teams, audit log and upstream cache usage.
+pub trait MetadataStore: Send + Sync {
+    /// Copy the whole store to a new store at `path`, while it remains in use.
+    fn snapshot(&self, path: &Path) -> Result<(), anyhow::Error>;
+
+    /// Get a crate's entry, with its versions in semver order.
+    fn get_crate(&self, crate_name: &str) -> Result<Option<Entry>, anyhow::Error>;
+    /// Remove a crate and all of its versions.
+    fn remove_crate(&self, crate_name: &str) -> Result<(), anyhow::Error>;
+    /// Create or replace a crate, replacing all of its versions.
+    fn insert_crate(&self, crate_name: &str, entry: &Entry) -> Result<(), anyhow::Error>;
+    /// List every record that cannot be decoded, which the iterators skip.
+    fn unreadable_records(&self) -> Result<Vec<UnreadableRecord>, anyhow::Error>;
+    /// Iterate over all crates, skipping any entries that cannot be decoded.
+    fn iter_crates(&self) -> Box<dyn Iterator<Item = (String, Entry)> + Send + '_>;
+    /// Iterate over the headers of all crates, without reading any of their versions.
+    fn iter_crate_headers(&self) -> Box<dyn Iterator<Item = (String, CrateHeader)> + Send + '_>;
+    /// Get a crate's entry without reading any of its versions.
+    fn get_crate_header(&self, crate_name: &str) -> Result<Option<CrateHeader>, anyhow::Error>;
+    /// Replace a crate's header, leaving its versions as they are, if the header is still `expected`.
+    ///
+    /// Every change to a crate rewrites its header, so this returns `false` without changing anything if the crate has
+    /// changed since `expected` was read.
+    fn replace_crate_header(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+    ) -> Result<bool, anyhow::Error>;
+    fn get_crate_version(
+        &self,
+        crate_name: &str,
+        version: &str,
+    ) -> Result<Option<UploadedPackage>, anyhow::Error>;
+    /// Get all of a crate's versions in semver order.
+    fn get_crate_versions(&self, crate_name: &str) -> Result<Vec<UploadedPackage>, anyhow::Error>;
+    /// Add a version of a crate together with the crate's header, if the header is still `expected`, or the crate does
+    /// not exist if it is `None`.
+    ///
+    /// Returns `false` without changing anything if the crate has changed since `expected` was read, or the version
+    /// already exists.
+    fn insert_crate_version(
+        &self,
+        crate_name: &str,
+        expected: Option<&CrateHeader>,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error>;
+    /// Replace an existing version of a crate together with the crate's header, if the header is still `expected`.
+    ///
+    /// Returns `false` without changing anything if the crate has changed since `expected` was read.
+    fn replace_crate_version(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error>;
+
+    fn get_user(&self, username: &str) -> Result<Option<auth::User>, anyhow::Error>;
+    fn insert_user(&self, username: &str, user: &auth::User) -> Result<(), anyhow::Error>;
+    /// Insert a new user, returning `false` without changing anything if the name is already taken.
+    fn create_user(&self, username: &str, user: &auth::User) -> Result<bool, anyhow::Error>;
+    fn delete_user(&self, username: &str) -> Result<(), anyhow::Error>;
+    /// Iterate over all users, skipping any entries that cannot be decoded.
+    fn iter_users(&self) -> Box<dyn Iterator<Item = auth::User> + Send + '_>;
+
+    fn get_team(&self, name: &str) -> Result<Option<Team>, anyhow::Error>;
+    fn insert_team(&self, team: &Team) -> Result<(), anyhow::Error>;
+    /// Modify a team atomically, returning `None` if it does not exist.
+    ///
+    /// `f` may be called several times during contention. If it returns an error, the team is left unchanged and the
+    /// error is returned.
+    fn modify_team(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&mut Team) -> Result<(), String>,
+    ) -> Result<Option<Result<(), String>>, anyhow::Error>;
+    /// Iterate over all teams, skipping any entries that cannot be decoded.
+    fn iter_teams(&self) -> Box<dyn Iterator<Item = Team> + Send + '_>;
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), anyhow::Error>;
+    /// Iterate over the audit log, oldest event first, skipping any events that cannot be decoded.
+    fn iter_audit_events(&self) -> Box<dyn DoubleEndedIterator<Item = AuditEvent> + Send + '_>;
+    /// Remove all audit events from before `cutoff`, returning the number removed.
+    fn prune_audit_events(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, anyhow::Error>;
+
+    fn get_cached_blob(&self, key: &str) -> Result<Option<CachedBlob>, anyhow::Error>;
+    fn insert_cached_blob(&self, key: &str, blob: &CachedBlob) -> Result<(), anyhow::Error>;
+    fn remove_cached_blob(&self, key: &str) -> Result<(), anyhow::Error>;
+    /// Iterate over the usage of every cached upstream crate file by its blob key, skipping any that cannot be decoded.
+    fn iter_cached_blobs(&self) -> Box<dyn Iterator<Item = (String, CachedBlob)> + Send + '_>;
+
+    fn get_token_user(
+        &self,
+        token: &[u8],
+    ) -> Result<Option<(TokenEntry, auth::User)>, anyhow::Error>;
+    /// Add a token, returning `false` without changing anything if its user already has a token with the same label.
+    fn insert_token(&self, token: &[u8], entry: &TokenEntry) -> Result<bool, anyhow::Error>;
+    /// Iterate over all tokens with their hashes, skipping any entries that cannot be decoded.
+    fn iter_tokens(&self) -> Box<dyn Iterator<Item = (Vec<u8>, TokenEntry)> + Send + '_>;
+    /// Get all of a user's token entries, skipping any that cannot be decoded.
+    fn get_user_tokens(&self, username: &str) -> Result<Vec<TokenEntry>, anyhow::Error>;
+    /// Replace an existing token entry, without recreating it if it has since been deleted.
+    fn update_token(&self, token: &[u8], entry: &TokenEntry) -> Result<(), anyhow::Error>;
+    /// Delete a token by its hash, returning the entry of the deleted token if it existed.
+    ///
+    /// A token whose entry cannot be decoded is still deleted, but is reported and returns `None`.
+    fn delete_token(&self, token: &[u8]) -> Result<Option<TokenEntry>, anyhow::Error>;
+    fn delete_user_token(&self, username: &str, label: &str) -> Result<(), anyhow::Error>;
+
+    /// Register a public key, returning `false` without changing anything if the key is already registered to any user,
+    /// or its user already has a key with the same label.
+    fn insert_public_key(&self, entry: &PublicKeyEntry) -> Result<bool, anyhow::Error>;
+    fn get_public_key(&self, key_id: &str) -> Result<Option<PublicKeyEntry>, anyhow::Error>;
+    /// Iterate over all public keys, skipping any entries that cannot be decoded.
+    fn iter_public_keys(&self) -> Box<dyn Iterator<Item = PublicKeyEntry> + Send + '_>;
+    /// Get all of a user's public keys, skipping any that cannot be decoded.
+    fn get_user_public_keys(&self, username: &str) -> Result<Vec<PublicKeyEntry>, anyhow::Error>;
+    fn has_user_public_key(&self, username: &str, label: &str) -> Result<bool, anyhow::Error>;
+    fn delete_user_public_key(&self, username: &str, label: &str) -> Result<(), anyhow::Error>;
+}
+
+/// Handle to the registry's metadata store, shared between requests.
+#[derive(Clone)]
 pub struct Db {
+    kind: StoreKind,
+    store: Arc<dyn MetadataStore>,
+}
+
+impl Deref for Db {
+    type Target = dyn MetadataStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.store.as_ref()
+    }
+}
+
+impl Db {
+    /// Open the metadata store of kind `kind` in `data_dir`, creating it if it does not exist.
+    pub fn open(kind: StoreKind, data_dir: &Path) -> Result<Self, anyhow::Error> {
+        let path = data_dir.join(kind.file_name());
+        let store: Arc<dyn MetadataStore> = match kind {
+            StoreKind::Sled => Arc::new(SledStore::open(path)?),
+            StoreKind::Sqlite => Arc::new(SqliteStore::open(path)?),
+        };
+        Ok(Db { kind, store })
+    }
+
+    pub fn kind(&self) -> StoreKind {
+        self.kind
+    }
+
+    /// Report the migrations that opening the store of kind `kind` in `data_dir` would run, without changing it.
+    pub fn dry_run_migrations(
+        kind: StoreKind,
+        data_dir: &Path,
+    ) -> Result<Vec<migrations::MigrationReport>, anyhow::Error> {
+        match kind {
+            StoreKind::Sled => SledStore::dry_run_migrations(data_dir.join(kind.file_name())),
+            StoreKind::Sqlite => SqliteStore::dry_run_migrations(data_dir.join(kind.file_name())),
+        }
+    }
+}
+
+/// A record in a store that cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnreadableRecord {
+    /// Type of the record, such as `crate-header` or `token`
+    pub kind: &'static str,
+    pub key: String,
+}
+
+impl fmt::Display for UnreadableRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.key)
+    }
+}
+
+/// Number of records of each type copied by [`copy_store`].
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    pub crates: usize,
+    pub users: usize,
+    pub teams: usize,
+    pub tokens: usize,
+    pub public_keys: usize,
+    pub audit_events: usize,
+    pub cached_blobs: usize,
+    /// Records that could not be decoded, and so were not copied
+    pub unreadable: Vec<UnreadableRecord>,
+}
+
+impl fmt::Display for CopyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} crates, {} users, {} teams, {} tokens, {} public keys, {} audit events and {} cached files",
+            self.crates,
+            self.users,
+            self.teams,
+            self.tokens,
+            self.public_keys,
+            self.audit_events,
+            self.cached_blobs
+        )?;
+        if !self.unreadable.is_empty() {
+            write!(f, ", skipping {} unreadable records", self.unreadable.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy every record from one store to another, reporting the records that cannot be decoded instead of copying them.
+pub fn copy_store(from: &Db, to: &Db) -> Result<CopyReport, anyhow::Error> {
+    let mut report = CopyReport {
+        unreadable: from.unreadable_records()?,
+        ..CopyReport::default()
+    };
+    for (name, entry) in from.iter_crates() {
+        to.insert_crate(&name, &entry)?;
+        report.crates += 1;
+    }
+    for user in from.iter_users() {
+        to.insert_user(&user.username, &user)?;
+        report.users += 1;
+    }
+    for team in from.iter_teams() {
+        to.insert_team(&team)?;
+        report.teams += 1;
+    }
+    for (token, entry) in from.iter_tokens() {
+        if !to.insert_token(&token, &entry)? {
+            return Err(anyhow!(
+                "token {} of {} already exists",
+                entry.label(),
+                entry.username()
+            ));
+        }
+        report.tokens += 1;
+    }
+    for entry in from.iter_public_keys() {
+        if !to.insert_public_key(&entry)? {
+            return Err(anyhow!(
+                "public key {} of {} already exists",
+                entry.label(),
+                entry.username()
+            ));
+        }
+        report.public_keys += 1;
+    }
+    for event in from.iter_audit_events() {
+        to.insert_audit_event(&event)?;
+        report.audit_events += 1;
+    }
+    for (key, blob) in from.iter_cached_blobs() {
+        to.insert_cached_blob(&key, &blob)?;
+        report.cached_blobs += 1;
+    }
+    Ok(report)
+}
+
+/// Sort versions into semver order, which is the order that every store returns them in.
+///
+/// Versions that are not valid semver are never published locally, but are kept after the others if they come from
+/// upstream.
+fn sort_versions(versions: &mut [UploadedPackage]) {
+    versions.sort_by_cached_key(|version| {
+        let parsed = semver::Version::parse(&version.pkg.vers).ok();
+        (parsed.is_none(), parsed)
+    });
+}
+
+/// A [`MetadataStore`] in a sled database, with values encoded by [`codec`].
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    /// The whole database, used to generate unique ids
+    inner: sled::Db,
+    /// Crate headers, keyed by crate name
     crate_tree: sled::Tree,
+    /// Versions of every crate, keyed by `crate\0version`
+    crate_version_tree: sled::Tree,
     user_tree: sled::Tree,
     token_tree: sled::Tree,
+    /// Index of `username\0label` to the hashed token in `token_tree`
+    user_token_tree: sled::Tree,
+    /// Public keys for asymmetric tokens, keyed by their PASERK key id
+    public_key_tree: sled::Tree,
+    /// Index of `username\0label` to the key id in `public_key_tree`
+    user_public_key_tree: sled::Tree,
+    team_tree: sled::Tree,
+    /// Append-only audit log, keyed by timestamp and a unique id
+    audit_tree: sled::Tree,
+    /// Usage of cached upstream crate files, keyed by blob key
+    cache_tree: sled::Tree,
 }
 
-impl Db {
+/// Key of a version of a crate, which sorts with the crate's other versions.
+fn crate_version_key(crate_name: &str, version: &str) -> Vec<u8> {
+    let mut key = crate_version_prefix(crate_name);
+    key.extend_from_slice(version.as_bytes());
+    key
+}
+
+/// Prefix shared by the keys of all of a crate's versions.
+fn crate_version_prefix(crate_name: &str) -> Vec<u8> {
+    let mut prefix = crate_name.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Key of a user's labelled item (such as a token) in a per-user index.
+fn user_label_key(username: &str, label: &str) -> Vec<u8> {
+    let mut key = user_label_prefix(username);
+    key.extend_from_slice(label.as_bytes());
+    key
+}
+
+/// Prefix shared by all of a user's keys in a per-user index.
+fn user_label_prefix(username: &str) -> Vec<u8> {
+    let mut prefix = username.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Read the version of the database, or `None` if it is empty.
+fn stored_version(db: &sled::Db) -> Result<Option<u32>, anyhow::Error> {
+    db.get(DB_VERSION_KEY)?
+        .map(|version_bytes| bincode::deserialize(&version_bytes))
+        .transpose()
+        .with_context(|| "could not deserialise database version")
+}
+
+/// Decode a record found while iterating over a tree, reporting and skipping it if it cannot be decoded.
+fn decode_or_skip<T: Record>(key: &[u8], raw: &[u8]) -> Option<T> {
+    match codec::decode(raw) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!(
+                "skipping unreadable {} record {}: {e:?}",
+                T::SCHEMA,
+                display_key(key)
+            );
+            None
+        }
+    }
+}
+
+/// Whether the stored header of a crate is `expected`, or the crate does not exist if `expected` is `None`.
+fn header_matches(current: Option<&[u8]>, expected: Option<&CrateHeader>) -> bool {
+    match (current, expected) {
+        (None, None) => true,
+        (Some(raw), Some(expected)) => {
+            codec::decode::<CrateHeader>(raw).is_ok_and(|current| current == *expected)
+        }
+        _ => false,
+    }
+}
+
+/// Display a key, which is either a name or binary data such as a token hash.
+fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.contains('\0') => key.to_owned(),
+        _ => key.iter().map(|byte| format!("{byte:02x}")).collect(),
+    }
+}
+
+/// List the records in `tree` that cannot be decoded as `T`, with their keys shown by `display`.
+fn unreadable_in<T: Record>(
+    tree: &sled::Tree,
+    display: fn(&[u8]) -> String,
+) -> Result<Vec<UnreadableRecord>, anyhow::Error> {
+    let mut unreadable = Vec::new();
+    for elem in tree.iter() {
+        let (key, raw) = elem?;
+        if codec::decode::<T>(&raw).is_err() {
+            unreadable.push(UnreadableRecord {
+                kind: T::SCHEMA,
+                key: display(&key),
+            });
+        }
+    }
+    Ok(unreadable)
+}
+
+/// Prefix of the keys of audit events that happened at `timestamp`.
+fn audit_key_prefix(timestamp: chrono::DateTime<chrono::Utc>) -> Vec<u8> {
+    // Events are never from before the epoch, so the millisecond timestamp sorts correctly as an unsigned integer
+    (timestamp.timestamp_millis().max(0) as u64)
+        .to_be_bytes()
+        .to_vec()
+}
+
+impl SledStore {
     pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
         let db = sled::open(path).with_context(|| "unable to open database")?;
 
-        match db.get(DB_VERSION_KEY)? {
-            Some(version_bytes) => {
-                let version: u32 = bincode::deserialize(&version_bytes)
-                    .with_context(|| "could not deserialise database version")?;
+        match stored_version(&db)? {
+            Some(version) if version > DB_VERSION => {
+                return Err(anyhow!(
+                    "database was created in a newer version of the registry (db version {version})"
+                ));
+            }
+            Some(version) if version < DB_VERSION => {
+                warn!("database was created in an older version of the registry (db version {version})");
+                // Keep a copy of the database as it was, in case a migration goes wrong
+                let backup_path = migrations::backup_path(path, version);
+                info!("backing up database to {}", backup_path.display());
+                migrations::backup(&db, &backup_path)?;
 
-                if version > DB_VERSION {
-                    return Err(anyhow!(
-                        "database was created in a newer version of the registry (db version {version})"
-                    ));
-                }
-                if version < DB_VERSION {
-                    warn!("database was created in an older version of the registry (db version {version})");
-                    db.insert(DB_VERSION_KEY, bincode::serialize(&DB_VERSION)?)
-                        .with_context(|| "could not update database version in database")?;
-                    // TODO: Database migrations
+                for report in migrations::migrate(&db, version)? {
+                    info!("{report}");
                 }
             }
+            Some(_) => {}
             None => {
                 // Database was empty
                 db.insert(DB_VERSION_KEY, bincode::serialize(&DB_VERSION)?)
@@ -44,132 +500,455 @@ impl Db {
         }
 
         let crate_tree = db.open_tree("crates")?;
+        let crate_version_tree = db.open_tree("crate_versions")?;
         let user_tree = db.open_tree("users")?;
         let token_tree = db.open_tree("tokens")?;
+        let user_token_tree = db.open_tree("user_tokens")?;
+        let public_key_tree = db.open_tree("public_keys")?;
+        let user_public_key_tree = db.open_tree("user_public_keys")?;
+        let team_tree = db.open_tree("teams")?;
+        let audit_tree = db.open_tree("audit")?;
+        let cache_tree = db.open_tree("upstream_cache")?;
 
-        Ok(Db {
+        Ok(SledStore {
+            inner: db,
             crate_tree,
+            crate_version_tree,
             user_tree,
             token_tree,
+            user_token_tree,
+            public_key_tree,
+            user_public_key_tree,
+            team_tree,
+            audit_tree,
+            cache_tree,
         })
     }
 
-    pub fn get_crate(&self, crate_name: &str) -> Result<Option<Entry>, anyhow::Error> {
-        self.crate_tree
-            .get(crate_name)
-            .with_context(|| "could not access crate entry")?
-            .map(|raw| bincode::deserialize(&raw))
-            .transpose()
-            .with_context(|| "could not deserialise metadata in crate entry")
-    }
-
-    pub fn remove_crate(&self, crate_name: &str) -> Result<(), anyhow::Error> {
-        self.crate_tree
-            .remove(crate_name)
-            .with_context(|| "could not remove crate")
-            .map(|_| ())
+    /// Report the migrations that opening the database at `path` would run, without changing it.
+    pub fn dry_run_migrations(
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<migrations::MigrationReport>, anyhow::Error> {
+        let db = sled::open(path).with_context(|| "unable to open database")?;
+        match stored_version(&db)? {
+            Some(version) if version > DB_VERSION => Err(anyhow!(
+                "database was created in a newer version of the registry (db version {version})"
+            )),
+            Some(version) => migrations::dry_run(&db, version),
+            None => Ok(Vec::new()),
+        }
     }
+}
 
-    pub fn insert_crate(&self, crate_name: &str, entry: &Entry) -> Result<(), anyhow::Error> {
-        self.crate_tree
-            .insert(
-                crate_name,
-                bincode::serialize(entry).with_context(|| "could not serialise crate entry")?,
-            )
-            .with_context(|| "could not insert crate")
-            .map(|_| ())
+impl SledStore {
+    /// Keys of all of a crate's versions.
+    fn version_keys(&self, crate_name: &str) -> Result<Vec<sled::IVec>, anyhow::Error> {
+        self.crate_version_tree
+            .scan_prefix(crate_version_prefix(crate_name))
+            .keys()
+            .collect::<Result<_, _>>()
+            .with_context(|| "could not access crate versions")
     }
 
-    /// Modify a crate atomically.
+    /// Replace a crate's header and all of its versions, or remove the crate if `raw_header` is `None`.
     ///
-    /// This function will call a function `f` (potentially multiple times during contention) with the result that
-    /// the change the function makes is applied atomically. If the function returns an error, then the old value is
-    /// preserved.
-    pub fn modify_crate(
+    /// Versions cannot be listed inside a transaction, so they are listed first and the transaction is retried if the
+    /// header, which every change to a crate rewrites, has changed in the meantime.
+    fn rewrite_crate(
         &self,
         crate_name: &str,
-        mut f: impl FnMut(&mut Entry) -> Result<(), anyhow::Error>,
+        raw_header: Option<&[u8]>,
+        new_versions: &[(Vec<u8>, Vec<u8>)],
     ) -> Result<(), anyhow::Error> {
-        let mut err: Option<anyhow::Error> = None;
-
-        self.crate_tree
-            .update_and_fetch(crate_name, |old| match old {
-                Some(old) => {
-                    // Deserialize the entry
-                    let mut entry = bincode::deserialize(old)
-                        .expect("existing entries should be deserializable");
-
-                    // Call the user's function
-                    if let Err(e) = f(&mut entry) {
-                        err = Some(e);
-                        return Some(old.to_vec());
+        loop {
+            let old_header = self.crate_tree.get(crate_name)?;
+            let old_keys = self.version_keys(crate_name)?;
+            let written = (&self.crate_tree, &self.crate_version_tree)
+                .transaction(|(crates, versions)| {
+                    if crates.get(crate_name)? != old_header {
+                        return Ok(false);
                     }
+                    for key in &old_keys {
+                        versions.remove(key)?;
+                    }
+                    match raw_header {
+                        Some(raw_header) => crates.insert(crate_name, raw_header)?,
+                        None => crates.remove(crate_name)?,
+                    };
+                    for (key, raw) in new_versions {
+                        versions.insert(key.as_slice(), raw.as_slice())?;
+                    }
+                    Ok(true)
+                })
+                .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))?;
+            if written {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write a crate's header and one of its versions, if the header is still `expected`, or the crate does not exist if
+    /// it is `None`. A version that already exists is only overwritten with `replace`.
+    fn write_crate_version(
+        &self,
+        crate_name: &str,
+        expected: Option<&CrateHeader>,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+        replace: bool,
+    ) -> Result<bool, anyhow::Error> {
+        let raw_header = codec::encode(header)?;
+        let raw_version = codec::encode(version)?;
+        let key = crate_version_key(crate_name, &version.pkg.vers);
+
+        (&self.crate_tree, &self.crate_version_tree)
+            .transaction(|(crates, versions)| {
+                if !header_matches(crates.get(crate_name)?.as_deref(), expected) {
+                    return Ok(false);
+                }
+                if !replace && versions.get(key.as_slice())?.is_some() {
+                    return Ok(false);
+                }
+                crates.insert(crate_name, raw_header.as_slice())?;
+                versions.insert(key.as_slice(), raw_version.as_slice())?;
+                Ok(true)
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+    }
+}
+
+impl MetadataStore for SledStore {
+    fn snapshot(&self, path: &Path) -> Result<(), anyhow::Error> {
+        migrations::backup(&self.inner, path)
+    }
+
+    fn get_crate(&self, crate_name: &str) -> Result<Option<Entry>, anyhow::Error> {
+        let Some(header) = self.get_crate_header(crate_name)? else {
+            return Ok(None);
+        };
+        Ok(Some(Entry::from_parts(
+            header,
+            self.get_crate_versions(crate_name)?,
+        )))
+    }
+
+    fn remove_crate(&self, crate_name: &str) -> Result<(), anyhow::Error> {
+        self.rewrite_crate(crate_name, None, &[])
+            .with_context(|| "could not remove crate")
+    }
+
+    fn insert_crate(&self, crate_name: &str, entry: &Entry) -> Result<(), anyhow::Error> {
+        let raw_header = codec::encode(&entry.header())?;
+        let new_versions = entry
+            .versions
+            .iter()
+            .map(|version| {
+                Ok((
+                    crate_version_key(crate_name, &version.pkg.vers),
+                    codec::encode(version)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        self.rewrite_crate(crate_name, Some(&raw_header), &new_versions)
+            .with_context(|| "could not insert crate")
+    }
+
+    fn unreadable_records(&self) -> Result<Vec<UnreadableRecord>, anyhow::Error> {
+        let mut unreadable = unreadable_in::<CrateHeader>(&self.crate_tree, display_key)?;
+        unreadable.extend(unreadable_in::<UploadedPackage>(
+            &self.crate_version_tree,
+            |key| String::from_utf8_lossy(key).replace('\0', "@"),
+        )?);
+        unreadable.extend(unreadable_in::<auth::User>(&self.user_tree, display_key)?);
+        unreadable.extend(unreadable_in::<Team>(&self.team_tree, display_key)?);
+        unreadable.extend(unreadable_in::<TokenEntry>(&self.token_tree, display_key)?);
+        unreadable.extend(unreadable_in::<PublicKeyEntry>(
+            &self.public_key_tree,
+            display_key,
+        )?);
+        unreadable.extend(unreadable_in::<AuditEvent>(&self.audit_tree, display_key)?);
+        unreadable.extend(unreadable_in::<CachedBlob>(&self.cache_tree, display_key)?);
+        Ok(unreadable)
+    }
 
-                    // Serialize the entry
-                    let entry = match bincode::serialize(&entry) {
-                        Ok(entry) => entry,
+    fn iter_crates(&self) -> Box<dyn Iterator<Item = (String, Entry)> + Send + '_> {
+        Box::new(
+            self.crate_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(name, raw)| {
+                    let header = decode_or_skip(&name, &raw)?;
+                    let name = String::from_utf8_lossy(&name).to_string();
+                    match self.get_crate_versions(&name) {
+                        Ok(versions) => Some((name, Entry::from_parts(header, versions))),
                         Err(e) => {
-                            err = Some(e.into());
-                            return Some(old.to_vec());
+                            warn!("skipping crate {name} with unreadable versions: {e:?}");
+                            None
                         }
-                    };
-                    Some(entry)
-                }
-                None => None,
-            })?;
+                    }
+                }),
+        )
+    }
 
-        // Return the error if there was one
-        match err {
-            Some(e) => Err(e),
-            None => Ok(()),
-        }
+    fn iter_crate_headers(&self) -> Box<dyn Iterator<Item = (String, CrateHeader)> + Send + '_> {
+        Box::new(
+            self.crate_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(name, raw)| {
+                    let header = decode_or_skip(&name, &raw)?;
+                    Some((String::from_utf8_lossy(&name).to_string(), header))
+                }),
+        )
     }
 
-    pub fn iter_crates(&self) -> impl Iterator<Item = (String, Entry)> {
+    fn get_crate_header(&self, crate_name: &str) -> Result<Option<CrateHeader>, anyhow::Error> {
         self.crate_tree
-            .iter()
-            .filter_map(|elem| elem.ok())
-            .map(|(name, entry)| {
-                (
-                    String::from_utf8_lossy(&name).to_string(),
-                    bincode::deserialize(&entry).expect("crate entries be valid to deserialize"),
-                )
+            .get(crate_name)
+            .with_context(|| "could not access crate entry")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode crate entry")
+    }
+
+    fn replace_crate_header(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+    ) -> Result<bool, anyhow::Error> {
+        let raw_header = codec::encode(header)?;
+        self.crate_tree
+            .transaction(|crates| {
+                if !header_matches(crates.get(crate_name)?.as_deref(), Some(expected)) {
+                    return Ok(false);
+                }
+                crates.insert(crate_name, raw_header.as_slice())?;
+                Ok(true)
             })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+            .with_context(|| "could not update crate")
+    }
+
+    fn get_crate_version(
+        &self,
+        crate_name: &str,
+        version: &str,
+    ) -> Result<Option<UploadedPackage>, anyhow::Error> {
+        self.crate_version_tree
+            .get(crate_version_key(crate_name, version))
+            .with_context(|| "could not access crate version")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode crate version")
+    }
+
+    fn get_crate_versions(&self, crate_name: &str) -> Result<Vec<UploadedPackage>, anyhow::Error> {
+        let mut versions = Vec::new();
+        for elem in self
+            .crate_version_tree
+            .scan_prefix(crate_version_prefix(crate_name))
+        {
+            let (key, raw) = elem.with_context(|| "could not access crate version")?;
+            versions.extend(decode_or_skip(&key, &raw));
+        }
+        sort_versions(&mut versions);
+        Ok(versions)
     }
 
-    pub fn get_user(&self, username: &str) -> Result<Option<auth::User>, anyhow::Error> {
+    fn insert_crate_version(
+        &self,
+        crate_name: &str,
+        expected: Option<&CrateHeader>,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error> {
+        self.write_crate_version(crate_name, expected, header, version, false)
+            .with_context(|| "could not insert crate version")
+    }
+
+    fn replace_crate_version(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error> {
+        self.write_crate_version(crate_name, Some(expected), header, version, true)
+            .with_context(|| "could not update crate version")
+    }
+
+    fn get_user(&self, username: &str) -> Result<Option<auth::User>, anyhow::Error> {
         self.user_tree
             .get(username)
             .with_context(|| "could not access user entry")?
-            .map(|raw| bincode::deserialize(&raw))
+            .map(|raw| codec::decode(&raw))
             .transpose()
-            .with_context(|| "could not deserialise user entry")
+            .with_context(|| "could not decode user entry")
     }
 
-    pub fn insert_user(&self, username: &str, user: &auth::User) -> Result<(), anyhow::Error> {
+    fn insert_user(&self, username: &str, user: &auth::User) -> Result<(), anyhow::Error> {
         self.user_tree
-            .insert(
-                username,
-                bincode::serialize(user).with_context(|| "could not serialise user entry")?,
-            )
+            .insert(username, codec::encode(user)?)
             .with_context(|| "could not insert user")
             .map(|_| ())
     }
 
-    pub fn iter_users(&self) -> sled::Iter {
-        self.user_tree.iter()
+    fn create_user(&self, username: &str, user: &auth::User) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .user_tree
+            .compare_and_swap(username, None as Option<&[u8]>, Some(codec::encode(user)?))
+            .with_context(|| "could not insert user")?
+            .is_ok())
+    }
+
+    fn delete_user(&self, username: &str) -> Result<(), anyhow::Error> {
+        self.user_tree
+            .remove(username)
+            .with_context(|| "could not remove user")
+            .map(|_| ())
+    }
+
+    fn iter_users(&self) -> Box<dyn Iterator<Item = auth::User> + Send + '_> {
+        Box::new(
+            self.user_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(username, raw)| decode_or_skip(&username, &raw)),
+        )
+    }
+
+    fn get_team(&self, name: &str) -> Result<Option<Team>, anyhow::Error> {
+        self.team_tree
+            .get(name)
+            .with_context(|| "could not access team entry")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode team entry")
+    }
+
+    fn insert_team(&self, team: &Team) -> Result<(), anyhow::Error> {
+        self.team_tree
+            .insert(&team.name, codec::encode(team)?)
+            .with_context(|| "could not insert team")
+            .map(|_| ())
+    }
+
+    fn modify_team(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&mut Team) -> Result<(), String>,
+    ) -> Result<Option<Result<(), String>>, anyhow::Error> {
+        loop {
+            let Some(raw) = self.team_tree.get(name)? else {
+                return Ok(None);
+            };
+            let mut team: Team =
+                codec::decode(&raw).with_context(|| "could not decode team entry")?;
+            if let Err(e) = f(&mut team) {
+                return Ok(Some(Err(e)));
+            }
+
+            let swapped = self
+                .team_tree
+                .compare_and_swap(name, Some(raw), Some(codec::encode(&team)?))
+                .with_context(|| "could not modify team")?;
+            if swapped.is_ok() {
+                return Ok(Some(Ok(())));
+            }
+        }
+    }
+
+    fn iter_teams(&self) -> Box<dyn Iterator<Item = Team> + Send + '_> {
+        Box::new(
+            self.team_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(name, raw)| decode_or_skip(&name, &raw)),
+        )
+    }
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        // Keys sort by time, so that old events can be removed and events can be read in order
+        let mut key = audit_key_prefix(event.timestamp);
+        key.extend_from_slice(&self.inner.generate_id()?.to_be_bytes());
+
+        self.audit_tree
+            .insert(key, codec::encode(event)?)
+            .with_context(|| "could not insert audit event")
+            .map(|_| ())
+    }
+
+    fn iter_audit_events(&self) -> Box<dyn DoubleEndedIterator<Item = AuditEvent> + Send + '_> {
+        Box::new(
+            self.audit_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(key, raw)| decode_or_skip(&key, &raw)),
+        )
+    }
+
+    fn prune_audit_events(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, anyhow::Error> {
+        let mut removed = 0;
+        for elem in self.audit_tree.range(..audit_key_prefix(cutoff)) {
+            let (key, _) = elem.with_context(|| "could not access audit event")?;
+            self.audit_tree
+                .remove(key)
+                .with_context(|| "could not remove audit event")?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    fn get_cached_blob(&self, key: &str) -> Result<Option<CachedBlob>, anyhow::Error> {
+        self.cache_tree
+            .get(key)
+            .with_context(|| "could not access cached file entry")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode cached file entry")
+    }
+
+    fn insert_cached_blob(&self, key: &str, blob: &CachedBlob) -> Result<(), anyhow::Error> {
+        self.cache_tree
+            .insert(key, codec::encode(blob)?)
+            .with_context(|| "could not insert cached file entry")
+            .map(|_| ())
+    }
+
+    fn remove_cached_blob(&self, key: &str) -> Result<(), anyhow::Error> {
+        self.cache_tree
+            .remove(key)
+            .with_context(|| "could not remove cached file entry")
+            .map(|_| ())
+    }
+
+    fn iter_cached_blobs(&self) -> Box<dyn Iterator<Item = (String, CachedBlob)> + Send + '_> {
+        Box::new(
+            self.cache_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(key, raw)| {
+                    let blob = decode_or_skip(&key, &raw)?;
+                    Some((String::from_utf8_lossy(&key).to_string(), blob))
+                }),
+        )
     }
 
-    pub fn get_token_user(
+    fn get_token_user(
         &self,
         token: &[u8],
     ) -> Result<Option<(TokenEntry, auth::User)>, anyhow::Error> {
         self.token_tree
             .get(token)
             .with_context(|| "could not access token entry")?
-            .map(|raw| bincode::deserialize::<TokenEntry>(&raw))
+            .map(|raw| codec::decode::<TokenEntry>(&raw))
             .transpose()
-            .with_context(|| "could not deserialise token entry")
+            .with_context(|| "could not decode token entry")
             .and_then(|entry| {
                 Ok(entry
                     .map(|entry| {
@@ -182,22 +961,343 @@ impl Db {
             })
     }
 
-    pub fn insert_token(&self, token: &[u8], entry: &TokenEntry) -> Result<(), anyhow::Error> {
-        self.token_tree
-            .insert(
-                token,
-                bincode::serialize(entry).with_context(|| "could not serialise token entry")?,
-            )
+    fn insert_token(&self, token: &[u8], entry: &TokenEntry) -> Result<bool, anyhow::Error> {
+        let raw = codec::encode(entry)?;
+        let key = user_label_key(entry.username(), entry.label());
+
+        (&self.token_tree, &self.user_token_tree)
+            .transaction(|(tokens, user_tokens)| {
+                if user_tokens.get(key.as_slice())?.is_some() || tokens.get(token)?.is_some() {
+                    return Ok(false);
+                }
+                tokens.insert(token, raw.as_slice())?;
+                user_tokens.insert(key.as_slice(), token)?;
+                Ok(true)
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
             .with_context(|| "could not insert token")
+    }
+
+    fn iter_tokens(&self) -> Box<dyn Iterator<Item = (Vec<u8>, TokenEntry)> + Send + '_> {
+        Box::new(
+            self.token_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(token, raw)| Some((token.to_vec(), decode_or_skip(&token, &raw)?))),
+        )
+    }
+
+    fn get_user_tokens(&self, username: &str) -> Result<Vec<TokenEntry>, anyhow::Error> {
+        let mut entries = Vec::new();
+        for token in self
+            .user_token_tree
+            .scan_prefix(user_label_prefix(username))
+            .values()
+        {
+            let token = token.with_context(|| "could not access per-user token index")?;
+            let raw = self
+                .token_tree
+                .get(&token)
+                .with_context(|| "could not access token entry")?;
+            if let Some(entry) = raw.and_then(|raw| decode_or_skip(&token, &raw)) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn update_token(&self, token: &[u8], entry: &TokenEntry) -> Result<(), anyhow::Error> {
+        let raw = codec::encode(entry)?;
+        self.token_tree
+            .update_and_fetch(token, |old| old.map(|_| raw.clone()))
+            .with_context(|| "could not update token")
             .map(|_| ())
     }
 
-    pub fn iter_tokens(&self) -> sled::Iter {
-        self.token_tree.iter()
+    fn delete_token(&self, token: &[u8]) -> Result<Option<TokenEntry>, anyhow::Error> {
+        let removed = (&self.token_tree, &self.user_token_tree)
+            .transaction(|(tokens, user_tokens)| {
+                let Some(raw) = tokens.remove(token)? else {
+                    return Ok(None);
+                };
+                let entry = decode_or_skip::<TokenEntry>(token, &raw);
+                if let Some(entry) = &entry {
+                    user_tokens.remove(user_label_key(entry.username(), entry.label()))?;
+                }
+                Ok(Some(entry))
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+            .with_context(|| "could not delete token")?;
+
+        let Some(None) = removed else {
+            return Ok(removed.flatten());
+        };
+        // The user and label of an unreadable entry are unknown, so its index entry has to be searched for
+        for elem in self.user_token_tree.iter() {
+            let (key, value) = elem.with_context(|| "could not access per-user token index")?;
+            if value == token {
+                self.user_token_tree
+                    .compare_and_swap(key, Some(value), None::<&[u8]>)
+                    .with_context(|| "could not access per-user token index")?
+                    .ok();
+            }
+        }
+        Ok(None)
     }
 
-    pub fn delete_token(&self, token: &[u8]) -> Result<(), anyhow::Error> {
-        self.token_tree.remove(token)?;
-        Ok(())
+    fn delete_user_token(&self, username: &str, label: &str) -> Result<(), anyhow::Error> {
+        let key = user_label_key(username, label);
+
+        (&self.token_tree, &self.user_token_tree)
+            .transaction(|(tokens, user_tokens)| {
+                if let Some(token) = user_tokens.remove(key.as_slice())? {
+                    tokens.remove(token)?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+            .with_context(|| "could not delete token")
+    }
+
+    fn insert_public_key(&self, entry: &PublicKeyEntry) -> Result<bool, anyhow::Error> {
+        let raw = codec::encode(entry)?;
+        let key = user_label_key(entry.username(), entry.label());
+
+        (&self.public_key_tree, &self.user_public_key_tree)
+            .transaction(|(public_keys, user_public_keys)| {
+                // Public keys are public, so a key registered by one user must never be taken over by another
+                if public_keys.get(entry.key_id())?.is_some()
+                    || user_public_keys.get(key.as_slice())?.is_some()
+                {
+                    return Ok(false);
+                }
+                public_keys.insert(entry.key_id(), raw.as_slice())?;
+                user_public_keys.insert(key.as_slice(), entry.key_id())?;
+                Ok(true)
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+            .with_context(|| "could not insert public key")
+    }
+
+    fn get_public_key(&self, key_id: &str) -> Result<Option<PublicKeyEntry>, anyhow::Error> {
+        self.public_key_tree
+            .get(key_id)
+            .with_context(|| "could not access public key entry")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode public key entry")
+    }
+
+    fn iter_public_keys(&self) -> Box<dyn Iterator<Item = PublicKeyEntry> + Send + '_> {
+        Box::new(
+            self.public_key_tree
+                .iter()
+                .filter_map(|elem| elem.ok())
+                .filter_map(|(key_id, raw)| decode_or_skip(&key_id, &raw)),
+        )
+    }
+
+    fn get_user_public_keys(&self, username: &str) -> Result<Vec<PublicKeyEntry>, anyhow::Error> {
+        let mut entries = Vec::new();
+        for key_id in self
+            .user_public_key_tree
+            .scan_prefix(user_label_prefix(username))
+            .values()
+        {
+            let key_id = key_id.with_context(|| "could not access per-user public key index")?;
+            let raw = self
+                .public_key_tree
+                .get(&key_id)
+                .with_context(|| "could not access public key entry")?;
+            if let Some(entry) = raw.and_then(|raw| decode_or_skip(&key_id, &raw)) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn has_user_public_key(&self, username: &str, label: &str) -> Result<bool, anyhow::Error> {
+        self.user_public_key_tree
+            .contains_key(user_label_key(username, label))
+            .with_context(|| "could not access per-user public key index")
+    }
+
+    fn delete_user_public_key(&self, username: &str, label: &str) -> Result<(), anyhow::Error> {
+        let key = user_label_key(username, label);
+
+        (&self.public_key_tree, &self.user_public_key_tree)
+            .transaction(|(public_keys, user_public_keys)| {
+                if let Some(key_id) = user_public_keys.remove(key.as_slice())? {
+                    public_keys.remove(key_id)?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+            .with_context(|| "could not delete public key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        team::TeamMember,
+        test_util::{self, TempDir},
+        token,
+        visibility::Visibility,
+    };
+
+    fn version(vers: &str) -> UploadedPackage {
+        test_util::package("foo", vers, "0")
+    }
+
+    #[test]
+    fn versions_stored_separately() {
+        for kind in StoreKind::ALL {
+            let dir = TempDir::new(&format!("versions-{kind}"));
+            let db = Db::open(kind, &dir).unwrap();
+
+            let header = Entry {
+                versions: Vec::new(),
+                time_of_last_update: Utc::now(),
+                is_local: true,
+                owners: Vec::new(),
+                visibility: Visibility::Public,
+                upstream: None,
+            }
+            .header();
+            assert!(db
+                .insert_crate_version("foo", None, &header, &version("1.10.0"))
+                .unwrap());
+            for vers in ["1.2.0", "1.2.0-beta.1"] {
+                assert!(db
+                    .insert_crate_version("foo", Some(&header), &header, &version(vers))
+                    .unwrap());
+            }
+            // A crate whose name starts with another's must not share its versions
+            assert!(db
+                .insert_crate_version("foo-bar", None, &header, &version("0.1.0"))
+                .unwrap());
+
+            // Versions cannot be published twice, or over a crate that has changed since it was read
+            assert!(!db
+                .insert_crate_version("foo", Some(&header), &header, &version("1.2.0"))
+                .unwrap());
+            assert!(!db
+                .insert_crate_version("foo", None, &header, &version("2.0.0"))
+                .unwrap());
+            let mut updated = header.clone();
+            updated.time_of_last_update = Utc::now();
+            assert!(db.replace_crate_header("foo", &header, &updated).unwrap());
+            assert!(!db.replace_crate_header("foo", &header, &updated).unwrap());
+
+            let mut yanked = db.get_crate_version("foo", "1.2.0").unwrap().unwrap();
+            yanked.pkg.yanked = true;
+            assert!(!db
+                .replace_crate_version("foo", &header, &header, &yanked)
+                .unwrap());
+            assert!(db
+                .replace_crate_version("foo", &updated, &header, &yanked)
+                .unwrap());
+
+            let entry = db.get_crate("foo").unwrap().unwrap();
+            let versions: Vec<_> = entry.versions.iter().map(|v| v.pkg.vers.as_str()).collect();
+            assert_eq!(versions, ["1.2.0-beta.1", "1.2.0", "1.10.0"]);
+            assert!(entry.versions[1].pkg.yanked);
+            assert_eq!(db.iter_crate_headers().count(), 2);
+
+            db.remove_crate("foo").unwrap();
+            assert!(db.get_crate_versions("foo").unwrap().is_empty());
+            assert_eq!(db.get_crate_versions("foo-bar").unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn token_labels_are_unique() {
+        for kind in StoreKind::ALL {
+            let dir = TempDir::new(&format!("token-labels-{kind}"));
+            let db = Db::open(kind, &dir).unwrap();
+
+            let first = token::create_token(&db, "alice", "ci", None, None, None).unwrap();
+            assert!(first.is_some());
+            assert!(token::create_token(&db, "alice", "ci", None, None, None)
+                .unwrap()
+                .is_none());
+            assert!(token::create_token(&db, "bob", "ci", None, None, None)
+                .unwrap()
+                .is_some());
+            assert_eq!(db.get_user_tokens("alice").unwrap().len(), 1);
+            assert_eq!(db.iter_tokens().count(), 2);
+        }
+    }
+
+    #[test]
+    fn user_names_are_unique() {
+        for kind in StoreKind::ALL {
+            let dir = TempDir::new(&format!("user-names-{kind}"));
+            let db = Db::open(kind, &dir).unwrap();
+            let account = |owner: &str| {
+                auth::User::new_service_account(
+                    "ci".to_owned(),
+                    auth::AccountOwner::User(owner.to_owned()),
+                )
+            };
+
+            assert!(db.create_user("ci", &account("alice")).unwrap());
+            assert!(!db.create_user("ci", &account("bob")).unwrap());
+            assert_eq!(
+                db.get_user("ci").unwrap().unwrap().kind,
+                auth::AccountKind::Service {
+                    owner: auth::AccountOwner::User("alice".to_owned())
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn teams_keep_a_maintainer() {
+        for kind in StoreKind::ALL {
+            let dir = TempDir::new(&format!("team-maintainer-{kind}"));
+            let db = Db::open(kind, &dir).unwrap();
+            db.insert_team(&Team {
+                name: "platform".to_owned(),
+                members: vec![TeamMember {
+                    username: "alice".to_owned(),
+                    maintainer: true,
+                }],
+            })
+            .unwrap();
+
+            let demoted = db
+                .modify_team("platform", &mut |team| {
+                    team.members[0].maintainer = false;
+                    team.check_maintained()
+                })
+                .unwrap();
+            assert!(matches!(demoted, Some(Err(_))));
+            assert!(db
+                .get_team("platform")
+                .unwrap()
+                .unwrap()
+                .is_maintainer("alice"));
+            assert!(db.modify_team("other", &mut |_| Ok(())).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn delete_unreadable_token() {
+        let dir = TempDir::new("unreadable-token");
+        let store = SledStore::open(dir.join("db")).unwrap();
+        store.token_tree.insert([1u8; 32], &[0xff; 3]).unwrap();
+        store
+            .user_token_tree
+            .insert(user_label_key("alice", "ci"), &[1u8; 32])
+            .unwrap();
+
+        assert!(store.delete_token(&[1u8; 32]).unwrap().is_none());
+        assert!(store.token_tree.is_empty());
+        assert!(store.user_token_tree.is_empty());
     }
 }
@@ -0,0 +1,220 @@
+//! Encoding of the values stored in the database.
+//!
+//! Values are MessagePack maps with named fields, wrapped in an envelope naming the schema of the record and the
+//! version of the schema that it was written with. Fields with a `#[serde(default)]` can be added to a record without
+//! changing its schema version. Incompatible changes bump the version, and records written with an older version are
+//! read by [`Record::decode_version`].
+
+use anyhow::{anyhow, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    audit::AuditEvent, auth::User, cache::CachedBlob, package::UploadedPackage,
+    paseto::PublicKeyEntry, team::Team, token::TokenEntry, CrateHeader,
+};
+
+/// A type that is stored as a value in the database.
+pub trait Record: Serialize + DeserializeOwned {
+    /// Name of the record's schema, stored with every value
+    const SCHEMA: &'static str;
+    /// Version of the schema that values are written with
+    const VERSION: u32;
+
+    /// Decode a record that was written with an older `version` of the schema.
+    fn decode_version(version: u32, _raw: &[u8]) -> Result<Self, anyhow::Error> {
+        Err(anyhow!(
+            "{} records of schema version {version} are no longer supported",
+            Self::SCHEMA
+        ))
+    }
+}
+
+impl Record for CrateHeader {
+    const SCHEMA: &'static str = "crate-header";
+    const VERSION: u32 = 1;
+}
+
+impl Record for UploadedPackage {
+    const SCHEMA: &'static str = "crate-version";
+    const VERSION: u32 = 1;
+}
+
+impl Record for User {
+    const SCHEMA: &'static str = "user";
+    const VERSION: u32 = 1;
+}
+
+impl Record for TokenEntry {
+    const SCHEMA: &'static str = "token";
+    const VERSION: u32 = 1;
+}
+
+impl Record for PublicKeyEntry {
+    const SCHEMA: &'static str = "public-key";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Team {
+    const SCHEMA: &'static str = "team";
+    const VERSION: u32 = 1;
+}
+
+impl Record for AuditEvent {
+    const SCHEMA: &'static str = "audit-event";
+    const VERSION: u32 = 1;
+}
+
+impl Record for CachedBlob {
+    const SCHEMA: &'static str = "cached-blob";
+    const VERSION: u32 = 1;
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    schema: &'a str,
+    version: u32,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    schema: String,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct Body<T> {
+    value: T,
+}
+
+/// Encode a record with the current version of its schema.
+pub fn encode<T: Record>(value: &T) -> Result<Vec<u8>, anyhow::Error> {
+    encode_as(T::SCHEMA, T::VERSION, value)
+}
+
+/// Encode a value as a record of the given schema and version.
+pub fn encode_as(
+    schema: &str,
+    version: u32,
+    value: &impl Serialize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    rmp_serde::to_vec_named(&Envelope {
+        schema,
+        version,
+        value,
+    })
+    .with_context(|| format!("could not encode {schema} record"))
+}
+
+/// Decode a record, which may have been written with an older version of its schema.
+pub fn decode<T: Record>(raw: &[u8]) -> Result<T, anyhow::Error> {
+    let header: Header = rmp_serde::from_slice(raw)
+        .with_context(|| format!("{} record has no schema tag", T::SCHEMA))?;
+    if header.schema != T::SCHEMA {
+        return Err(anyhow!(
+            "expected a {} record but found a {} record",
+            T::SCHEMA,
+            header.schema
+        ));
+    }
+
+    if header.version == T::VERSION {
+        decode_value(raw)
+    } else if header.version < T::VERSION {
+        T::decode_version(header.version, raw)
+    } else {
+        Err(anyhow!(
+            "{} record was written with a newer schema version {}",
+            T::SCHEMA,
+            header.version
+        ))
+    }
+}
+
+/// Decode the value of a record as `T`, regardless of its schema, for reading older versions of a schema.
+pub fn decode_value<T: DeserializeOwned>(raw: &[u8]) -> Result<T, anyhow::Error> {
+    let body: Body<T> = rmp_serde::from_slice(raw).with_context(|| "could not decode record")?;
+    Ok(body.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record whose `name` field was renamed to `label` in version 2.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Renamed {
+        label: String,
+        #[serde(default)]
+        added: bool,
+    }
+
+    impl Record for Renamed {
+        const SCHEMA: &'static str = "renamed";
+        const VERSION: u32 = 2;
+
+        fn decode_version(version: u32, raw: &[u8]) -> Result<Self, anyhow::Error> {
+            #[derive(Deserialize)]
+            struct RenamedV1 {
+                name: String,
+            }
+
+            match version {
+                1 => {
+                    let old: RenamedV1 = decode_value(raw)?;
+                    Ok(Renamed {
+                        label: old.name,
+                        added: false,
+                    })
+                }
+                _ => Err(anyhow!("unknown version")),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = Renamed {
+            label: "foo".to_owned(),
+            added: true,
+        };
+        assert_eq!(decode::<Renamed>(&encode(&value).unwrap()).unwrap(), value);
+    }
+
+    #[test]
+    fn older_versions() {
+        #[derive(Serialize)]
+        struct RenamedV1 {
+            name: &'static str,
+        }
+
+        let raw = encode_as("renamed", 1, &RenamedV1 { name: "foo" }).unwrap();
+        assert_eq!(
+            decode::<Renamed>(&raw).unwrap(),
+            Renamed {
+                label: "foo".to_owned(),
+                added: false
+            }
+        );
+    }
+
+    #[test]
+    fn added_fields_use_defaults() {
+        #[derive(Serialize)]
+        struct WithoutAdded {
+            label: &'static str,
+        }
+
+        let raw = encode_as("renamed", 2, &WithoutAdded { label: "foo" }).unwrap();
+        assert!(!decode::<Renamed>(&raw).unwrap().added);
+    }
+
+    #[test]
+    fn bad_records_rejected() {
+        let raw = encode_as("other", 2, &"foo").unwrap();
+        assert!(decode::<Renamed>(&raw).is_err());
+        let raw = encode_as("renamed", 3, &"foo").unwrap();
+        assert!(decode::<Renamed>(&raw).is_err());
+        assert!(decode::<Renamed>(&bincode::serialize(&("foo", true)).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,666 @@
+//! Migrations of databases created by older versions of the registry.
+//!
+//! Until version 9, every value in the database was encoded with bincode, which writes a struct as the concatenation
+//! of its fields. A field added to the end of a struct could therefore be migrated by appending the encoding of its
+//! initial value to every existing record, without needing to know the rest of the record's layout. Since version 9,
+//! values are self-describing records (see [`super::codec`]) and new fields rarely need a migration at all. Since
+//! version 10, each version of a crate is a record of its own in `crate_versions`, next to a header in `crates`.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sled::{transaction::TransactionError, IVec, Transactional};
+
+use super::{codec, crate_version_key, user_label_key, DB_VERSION, DB_VERSION_KEY};
+use crate::{
+    audit::{AuditAction, Outcome},
+    auth::AccountKind,
+    owner::CrateOwner,
+    package::UploadedPackage,
+    token::EndpointScope,
+    visibility::Visibility,
+};
+
+/// A tree that migrations are able to change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tree {
+    Crates,
+    Users,
+    Tokens,
+    UserTokens,
+    PublicKeys,
+    Teams,
+    Audit,
+    CrateVersions,
+}
+
+impl Tree {
+    const ALL: [Tree; 8] = [
+        Tree::Crates,
+        Tree::Users,
+        Tree::Tokens,
+        Tree::UserTokens,
+        Tree::PublicKeys,
+        Tree::Teams,
+        Tree::Audit,
+        Tree::CrateVersions,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Tree::Crates => "crates",
+            Tree::Users => "users",
+            Tree::Tokens => "tokens",
+            Tree::UserTokens => "user_tokens",
+            Tree::PublicKeys => "public_keys",
+            Tree::Teams => "teams",
+            Tree::Audit => "audit",
+            Tree::CrateVersions => "crate_versions",
+        }
+    }
+}
+
+/// A single write made by a migration.
+struct Change {
+    tree: Tree,
+    key: IVec,
+    /// New value of the key, or `None` to remove it
+    value: Option<Vec<u8>>,
+}
+
+/// An upgrade of the database from one version to the next.
+struct Migration {
+    /// Version that this migration upgrades from, to `from + 1`
+    from: u32,
+    description: &'static str,
+    /// Work out the changes to make, by reading the database as it is at version `from`
+    plan: fn(&sled::Db) -> Result<Vec<Change>, anyhow::Error>,
+}
+
+/// Every migration, in order of the version they upgrade from.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 2,
+        description: "add endpoint and crate scopes to tokens",
+        plan: add_token_scopes,
+    },
+    Migration {
+        from: 3,
+        description: "track token creation, expiry and last use",
+        plan: add_token_usage,
+    },
+    Migration {
+        from: 4,
+        description: "index tokens by user",
+        plan: rebuild_user_token_index,
+    },
+    Migration {
+        from: 5,
+        description: "add account kinds to users",
+        plan: add_account_kind,
+    },
+    Migration {
+        from: 6,
+        description: "add owners to crates",
+        plan: add_crate_owners,
+    },
+    Migration {
+        from: 7,
+        description: "add visibility to crates",
+        plan: add_crate_visibility,
+    },
+    Migration {
+        from: 8,
+        description: "encode values as records tagged with their schema",
+        plan: encode_records,
+    },
+    Migration {
+        from: 9,
+        description: "store crate versions under their own keys",
+        plan: split_crate_versions,
+    },
+];
+
+/// The result of running a single migration.
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub description: &'static str,
+    /// Number of records written or removed
+    pub changes: usize,
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "migrated database from version {} to {}: {} ({} records changed)",
+            self.from,
+            self.from + 1,
+            self.description,
+            self.changes
+        )
+    }
+}
+
+/// Upgrade a database from version `from` to the current version.
+///
+/// Each migration is applied in its own transaction together with the new database version, so a failed migration
+/// leaves the database at the last version that was completed.
+pub fn migrate(db: &sled::Db, from: u32) -> Result<Vec<MigrationReport>, anyhow::Error> {
+    let mut reports = Vec::new();
+    for version in from..DB_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or_else(|| anyhow!("no migration from database version {version}"))?;
+
+        let changes = (migration.plan)(db)
+            .with_context(|| format!("could not plan migration from version {version}"))?;
+        apply(db, &changes, version + 1)
+            .with_context(|| format!("could not migrate from version {version}"))?;
+
+        reports.push(MigrationReport {
+            from: version,
+            description: migration.description,
+            changes: changes.len(),
+        });
+    }
+    Ok(reports)
+}
+
+/// Run the migrations from version `from` against a temporary copy of the database, leaving it unchanged.
+pub fn dry_run(db: &sled::Db, from: u32) -> Result<Vec<MigrationReport>, anyhow::Error> {
+    let copy = sled::Config::new()
+        .temporary(true)
+        .open()
+        .with_context(|| "could not create temporary database")?;
+    copy.import(db.export());
+    migrate(&copy, from)
+}
+
+/// Path of the backup taken before migrating the database at `path` from `version`.
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".backup-v{version}-{}",
+        Utc::now().format("%Y%m%d%H%M%S")
+    ));
+    path.with_file_name(name)
+}
+
+/// Copy the whole database to a new database at `path`.
+pub fn backup(db: &sled::Db, path: &Path) -> Result<(), anyhow::Error> {
+    if path.exists() {
+        return Err(anyhow!("backup {} already exists", path.display()));
+    }
+    let copy = sled::open(path).with_context(|| "could not create database backup")?;
+    copy.import(db.export());
+    copy.flush()
+        .with_context(|| "could not write database backup")?;
+    Ok(())
+}
+
+/// Apply the changes of a migration and set the database version, in a single transaction.
+fn apply(db: &sled::Db, changes: &[Change], version: u32) -> Result<(), anyhow::Error> {
+    let trees = Tree::ALL
+        .iter()
+        .map(|tree| db.open_tree(tree.name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut all_trees: Vec<&sled::Tree> = vec![db];
+    all_trees.extend(&trees);
+    let version = bincode::serialize(&version)?;
+
+    all_trees[..]
+        .transaction(|all_trees| {
+            for change in changes {
+                // The default tree holding the version comes first
+                let tree = &all_trees[change.tree as usize + 1];
+                match &change.value {
+                    Some(value) => tree.insert(&change.key, value.as_slice())?,
+                    None => tree.remove(&change.key)?,
+                };
+            }
+            all_trees[0].insert(DB_VERSION_KEY, version.as_slice())?;
+            Ok(())
+        })
+        .map_err(|e: TransactionError<()>| anyhow!("{e:?}"))
+}
+
+/// Append the encoding of `suffix` to every value in `tree`, for a field added to the end of its records.
+fn append_field(
+    db: &sled::Db,
+    tree: Tree,
+    suffix: &impl Serialize,
+) -> Result<Vec<Change>, anyhow::Error> {
+    let suffix = bincode::serialize(suffix)?;
+    db.open_tree(tree.name())?
+        .iter()
+        .map(|elem| {
+            let (key, value) = elem?;
+            let mut value = value.to_vec();
+            value.extend_from_slice(&suffix);
+            Ok(Change {
+                tree,
+                key,
+                value: Some(value),
+            })
+        })
+        .collect()
+}
+
+fn add_token_scopes(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    // Existing tokens are unrestricted
+    let scopes: (Option<Vec<EndpointScope>>, Option<Vec<String>>) = (None, None);
+    append_field(db, Tree::Tokens, &scopes)
+}
+
+fn add_token_usage(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    // The creation time of existing tokens is unknown, so use the time of the migration instead
+    let usage = (
+        Utc::now(),
+        None::<DateTime<Utc>>,
+        None::<DateTime<Utc>>,
+        None::<IpAddr>,
+    );
+    append_field(db, Tree::Tokens, &usage)
+}
+
+fn rebuild_user_token_index(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    // The username and label are the leading fields of every version of a token entry
+    #[derive(Deserialize)]
+    struct TokenOwner {
+        username: String,
+        label: String,
+    }
+
+    let mut changes = Vec::new();
+    for elem in db.open_tree(Tree::UserTokens.name())?.iter() {
+        let (key, _) = elem?;
+        changes.push(Change {
+            tree: Tree::UserTokens,
+            key,
+            value: None,
+        });
+    }
+    for elem in db.open_tree(Tree::Tokens.name())?.iter() {
+        let (token, raw) = elem.with_context(|| "could not read token entry")?;
+        let owner: TokenOwner =
+            bincode::deserialize(&raw).with_context(|| "could not deserialise token entry")?;
+        changes.push(Change {
+            tree: Tree::UserTokens,
+            key: user_label_key(&owner.username, &owner.label).into(),
+            value: Some(token.to_vec()),
+        });
+    }
+    Ok(changes)
+}
+
+fn add_account_kind(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    // Service accounts did not exist before this version
+    append_field(db, Tree::Users, &AccountKind::Human)
+}
+
+fn add_crate_owners(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    // The publishers of existing crates were not recorded, so whoever next publishes a version of one claims it, as any
+    // user could publish to it before. Until then the registry admins can add owners to it
+    append_field(db, Tree::Crates, &Vec::<CrateOwner>::new())
+}
+
+fn add_crate_visibility(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    append_field(db, Tree::Crates, &Visibility::Public)
+}
+
+/// Records as they were laid out in version 8, the last version encoded with bincode.
+mod v8 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Entry {
+        versions: Vec<UploadedPackage>,
+        time_of_last_update: DateTime<Utc>,
+        is_local: bool,
+        owners: Vec<CrateOwner>,
+        visibility: Visibility,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        username: String,
+        password: String,
+        blocked: bool,
+        kind: AccountKind,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct TokenEntry {
+        username: String,
+        label: String,
+        endpoint_scopes: Option<Vec<EndpointScope>>,
+        crate_scopes: Option<Vec<String>>,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        last_used_at: Option<DateTime<Utc>>,
+        last_used_ip: Option<IpAddr>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PublicKeyEntry {
+        username: String,
+        label: String,
+        public_key: String,
+        key_id: String,
+        created_at: DateTime<Utc>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Team {
+        name: String,
+        members: Vec<TeamMember>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct TeamMember {
+        username: String,
+        maintainer: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct AuditEvent {
+        timestamp: DateTime<Utc>,
+        actor: Option<String>,
+        actor_kind: Option<String>,
+        token: Option<String>,
+        ip: Option<IpAddr>,
+        action: AuditAction,
+        target: String,
+        outcome: Outcome,
+    }
+}
+
+/// Re-encode every bincode value in `tree` as a `T` record of the first version of `schema`.
+///
+/// Values that cannot be read are left as they are, and are skipped and reported when the tree is read.
+fn reencode<T: Serialize + for<'de> Deserialize<'de>>(
+    db: &sled::Db,
+    tree: Tree,
+    schema: &str,
+) -> Result<Vec<Change>, anyhow::Error> {
+    let mut changes = Vec::new();
+    for elem in db.open_tree(tree.name())?.iter() {
+        let (key, raw) = elem?;
+        let value: T = match bincode::deserialize(&raw) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!(
+                    "leaving unreadable {schema} record {} in {}: {e:?}",
+                    String::from_utf8_lossy(&key),
+                    tree.name()
+                );
+                continue;
+            }
+        };
+        changes.push(Change {
+            tree,
+            key,
+            value: Some(codec::encode_as(schema, 1, &value)?),
+        });
+    }
+    Ok(changes)
+}
+
+fn encode_records(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    let mut changes = reencode::<v8::Entry>(db, Tree::Crates, "crate")?;
+    changes.extend(reencode::<v8::User>(db, Tree::Users, "user")?);
+    changes.extend(reencode::<v8::TokenEntry>(db, Tree::Tokens, "token")?);
+    changes.extend(reencode::<v8::PublicKeyEntry>(
+        db,
+        Tree::PublicKeys,
+        "public-key",
+    )?);
+    changes.extend(reencode::<v8::Team>(db, Tree::Teams, "team")?);
+    changes.extend(reencode::<v8::AuditEvent>(db, Tree::Audit, "audit-event")?);
+    Ok(changes)
+}
+
+/// Crates as they were stored in version 9, as a single record holding every version.
+mod v9 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct Entry {
+        pub versions: Vec<UploadedPackage>,
+        pub time_of_last_update: DateTime<Utc>,
+        pub is_local: bool,
+        pub owners: Vec<CrateOwner>,
+        pub visibility: Visibility,
+    }
+
+    #[derive(Serialize)]
+    pub struct CrateHeader<'a> {
+        pub time_of_last_update: DateTime<Utc>,
+        pub is_local: bool,
+        pub owners: &'a [CrateOwner],
+        pub visibility: &'a Visibility,
+    }
+}
+
+fn split_crate_versions(db: &sled::Db) -> Result<Vec<Change>, anyhow::Error> {
+    let mut changes = Vec::new();
+    for elem in db.open_tree(Tree::Crates.name())?.iter() {
+        let (key, raw) = elem?;
+        let entry: v9::Entry = match codec::decode_value(&raw) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!(
+                    "leaving unreadable crate record {}: {e:?}",
+                    String::from_utf8_lossy(&key)
+                );
+                continue;
+            }
+        };
+
+        let crate_name = String::from_utf8_lossy(&key).to_string();
+        for version in &entry.versions {
+            changes.push(Change {
+                tree: Tree::CrateVersions,
+                key: crate_version_key(&crate_name, &version.pkg.vers).into(),
+                value: Some(codec::encode_as("crate-version", 1, version)?),
+            });
+        }
+        let header = v9::CrateHeader {
+            time_of_last_update: entry.time_of_last_update,
+            is_local: entry.is_local,
+            owners: &entry.owners,
+            visibility: &entry.visibility,
+        };
+        changes.push(Change {
+            tree: Tree::Crates,
+            key,
+            value: Some(codec::encode_as("crate-header", 1, &header)?),
+        });
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        db::{MetadataStore, SledStore, StoreKind},
+        owner,
+        test_util::{self, TempDir},
+    };
+
+    /// Create a database at `path` laid out as it was at `version`, with a crate, a user and a token, and a crate that
+    /// cannot be read.
+    fn create_fixture(path: &Path, version: u32) -> sled::Db {
+        // Databases since version 9 are written with records, so they are built by migrating from bincode
+        if version > 8 {
+            let db = create_fixture(path, 8);
+            for from in 8..version {
+                let migration = &MIGRATIONS[(from - MIGRATIONS[0].from) as usize];
+                apply(&db, &(migration.plan)(&db).unwrap(), from + 1).unwrap();
+            }
+            db.flush().unwrap();
+            return db;
+        }
+
+        // Without a background flusher, the database is closed as soon as the fixture is dropped, so it can be reopened
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+        db.insert(DB_VERSION_KEY, bincode::serialize(&version).unwrap())
+            .unwrap();
+        let timestamp = DateTime::parse_from_rfc3339("2022-10-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let mut entry =
+            bincode::serialize(&(Vec::<UploadedPackage>::new(), timestamp, true)).unwrap();
+        if version >= 7 {
+            entry.extend(bincode::serialize(&vec![CrateOwner::User("alice".to_owned())]).unwrap());
+        }
+        if version >= 8 {
+            entry.extend(bincode::serialize(&Visibility::Public).unwrap());
+        }
+        let crates = db.open_tree("crates").unwrap();
+        crates.insert("foo", entry).unwrap();
+        crates.insert("broken", &[0xff; 3]).unwrap();
+
+        let mut user = bincode::serialize(&("alice", "$argon2id$hash", false)).unwrap();
+        if version >= 6 {
+            user.extend(bincode::serialize(&AccountKind::Human).unwrap());
+        }
+        db.open_tree("users")
+            .unwrap()
+            .insert("alice", user)
+            .unwrap();
+
+        let mut token = bincode::serialize(&("alice", "ci")).unwrap();
+        if version >= 3 {
+            let scopes = (Some(vec![EndpointScope::PublishUpdate]), Some(vec!["foo"]));
+            token.extend(bincode::serialize(&scopes).unwrap());
+        }
+        if version >= 4 {
+            let usage = (
+                timestamp,
+                None::<DateTime<Utc>>,
+                Some(timestamp),
+                None::<IpAddr>,
+            );
+            token.extend(bincode::serialize(&usage).unwrap());
+        }
+        db.open_tree("tokens")
+            .unwrap()
+            .insert([1u8; 32], token.clone())
+            .unwrap();
+        if version >= 7 {
+            let team = ("platform", vec![("alice", true)]);
+            db.open_tree("teams")
+                .unwrap()
+                .insert("platform", bincode::serialize(&team).unwrap())
+                .unwrap();
+        }
+        if version >= 5 {
+            db.open_tree("user_tokens")
+                .unwrap()
+                .insert(user_label_key("alice", "ci"), &[1u8; 32])
+                .unwrap();
+        }
+
+        db.flush().unwrap();
+        db
+    }
+
+    #[test]
+    fn migrations_are_contiguous() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from, MIGRATIONS[0].from + i as u32);
+        }
+        assert_eq!(MIGRATIONS.last().unwrap().from + 1, DB_VERSION);
+    }
+
+    #[test]
+    fn open_old_databases() {
+        for version in MIGRATIONS[0].from..DB_VERSION {
+            let dir = TempDir::new(&format!("migrate-v{version}"));
+            let path = dir.join("db");
+            drop(create_fixture(&path, version));
+
+            let db = SledStore::open(&path).unwrap();
+            let entry = db.get_crate("foo").unwrap().unwrap();
+            assert!(entry.is_local);
+            assert_eq!(entry.visibility, Visibility::Public);
+            assert!(db.get_crate("broken").is_err());
+            assert_eq!(db.iter_crates().count(), 1);
+            let user = db.get_user("alice").unwrap().unwrap();
+            assert!(!user.is_service_account());
+            let tokens = db.get_user_tokens("alice").unwrap();
+            assert_eq!(tokens.len(), 1);
+            assert_eq!(tokens[0].label(), "ci");
+            assert!(!tokens[0].is_expired());
+            assert_eq!(db.iter_teams().count(), usize::from(version >= 7));
+
+            // Migrated databases are backed up first
+            let backups = fs::read_dir(&dir).unwrap().count() - 1;
+            assert_eq!(backups, 1);
+        }
+    }
+
+    #[test]
+    fn publish_to_crate_without_owners() {
+        let dir = TempDir::new("migrate-owners");
+        let path = dir.join("db");
+        drop(create_fixture(&path, 6));
+
+        let db = crate::Db::open(StoreKind::Sled, &dir).unwrap();
+        let expected = db.get_crate_header("foo").unwrap().unwrap();
+        assert!(expected.owners.is_empty());
+
+        // Any user may publish to a crate without owners, not only admins, and claims it by doing so
+        let config = test_util::config(&dir, false);
+        assert!(owner::may_publish(&db, &config, &expected.owners, "alice").unwrap());
+        assert!(!owner::is_owner(&db, &config, &expected.owners, "alice").unwrap());
+
+        let mut header = expected.clone();
+        owner::claim_unowned(&mut header.owners, "alice");
+        let version = test_util::package("foo", "1.0.0", "0");
+        assert!(db
+            .insert_crate_version("foo", Some(&expected), &header, &version)
+            .unwrap());
+        let entry = db.get_crate("foo").unwrap().unwrap();
+        assert_eq!(entry.owners, [CrateOwner::User("alice".to_owned())]);
+        assert_eq!(entry.versions.len(), 1);
+
+        // Claiming only applies to crates without owners
+        assert!(!owner::may_publish(&db, &config, &entry.owners, "bob").unwrap());
+        let mut owners = entry.owners;
+        owner::claim_unowned(&mut owners, "bob");
+        assert_eq!(owners, [CrateOwner::User("alice".to_owned())]);
+    }
+
+    #[test]
+    fn dry_run_leaves_database_unchanged() {
+        let dir = TempDir::new("dry-run");
+        let db = create_fixture(&dir.join("db"), 2);
+
+        let reports = dry_run(&db, 2).unwrap();
+        assert_eq!(reports.len(), MIGRATIONS.len());
+        assert_eq!(reports[0].changes, 1);
+
+        let version: u32 = bincode::deserialize(&db.get(DB_VERSION_KEY).unwrap().unwrap()).unwrap();
+        assert_eq!(version, 2);
+        assert!(db.open_tree("user_tokens").unwrap().is_empty());
+    }
+}
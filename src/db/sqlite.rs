@@ -0,0 +1,1460 @@
+//! A [`MetadataStore`] in an embedded SQLite database.
+//!
+//! Crates, their versions and owners, and team members are stored in their own tables so that they can be queried
+//! directly. Users, tokens, public keys, audit events and cached file usage have the columns that they are looked up
+//! or filtered by, alongside the whole record encoded with [`codec`], as in sled.
+//!
+//! Calls are made directly from the request handlers like the calls to sled. Each takes a connection from a pool, so a
+//! slow query only holds up its own request rather than every request waiting for a single connection, and the audit
+//! log is read a page at a time rather than all at once.
+
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, TransactionBehavior};
+use tracing::{info, warn};
+
+use super::{
+    codec::{self, Record},
+    migrations::{self, MigrationReport},
+    sort_versions, MetadataStore, UnreadableRecord,
+};
+use crate::{
+    audit::AuditEvent,
+    auth,
+    cache::CachedBlob,
+    owner::CrateOwner,
+    package::UploadedPackage,
+    paseto::PublicKeyEntry,
+    team::{Team, TeamMember},
+    token::TokenEntry,
+    visibility::Visibility,
+    CrateHeader, Entry,
+};
+
+/// How long to wait for another connection to release its lock on the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Version of the schema, stored in the database's `user_version`
+const SCHEMA_VERSION: i32 = 4;
+/// Most connections kept open for later calls once they are no longer in use
+const MAX_IDLE_CONNECTIONS: usize = 8;
+/// Number of audit events read at a time
+const AUDIT_PAGE_SIZE: i64 = 256;
+
+static SCHEMA: &str = "
+CREATE TABLE crates (
+    name TEXT PRIMARY KEY,
+    is_local INTEGER NOT NULL,
+    visibility TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    upstream TEXT
+);
+CREATE TABLE crate_versions (
+    crate_name TEXT NOT NULL REFERENCES crates (name),
+    position INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    cksum TEXT NOT NULL,
+    yanked INTEGER NOT NULL,
+    package BLOB NOT NULL,
+    PRIMARY KEY (crate_name, position)
+);
+CREATE INDEX crate_versions_version ON crate_versions (crate_name, version);
+CREATE TABLE crate_owners (
+    crate_name TEXT NOT NULL REFERENCES crates (name),
+    position INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    PRIMARY KEY (crate_name, position)
+);
+CREATE INDEX crate_owners_owner ON crate_owners (owner);
+
+CREATE TABLE users (
+    username TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    blocked INTEGER NOT NULL,
+    record BLOB NOT NULL
+);
+
+CREATE TABLE teams (
+    name TEXT PRIMARY KEY
+);
+CREATE TABLE team_members (
+    team_name TEXT NOT NULL REFERENCES teams (name),
+    position INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    maintainer INTEGER NOT NULL,
+    PRIMARY KEY (team_name, position)
+);
+CREATE INDEX team_members_username ON team_members (username);
+
+CREATE TABLE tokens (
+    hash BLOB PRIMARY KEY,
+    username TEXT NOT NULL,
+    label TEXT NOT NULL,
+    record BLOB NOT NULL,
+    UNIQUE (username, label)
+);
+
+CREATE TABLE public_keys (
+    key_id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    label TEXT NOT NULL,
+    record BLOB NOT NULL,
+    UNIQUE (username, label)
+);
+
+CREATE TABLE audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    actor TEXT,
+    action TEXT NOT NULL,
+    target TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    record BLOB NOT NULL
+);
+CREATE INDEX audit_events_timestamp ON audit_events (timestamp);
+
+CREATE TABLE cached_blobs (
+    key TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    last_access INTEGER NOT NULL,
+    record BLOB NOT NULL
+);
+";
+
+/// A change to the schema that upgrades it from one version to the next.
+type Migration = fn(&Transaction) -> Result<(), anyhow::Error>;
+
+/// Changes to the schema, as the description and the change that upgrades it from each version to the next, starting
+/// from version 1
+static MIGRATIONS: [(&str, Migration); 3] = [
+    ("add the usage of cached upstream crate files", |tx| {
+        tx.execute_batch(
+            "CREATE TABLE cached_blobs (
+                key TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                last_access INTEGER NOT NULL,
+                record TEXT NOT NULL
+            );",
+        )?;
+        Ok(())
+    }),
+    ("record the upstream that crates were cached from", |tx| {
+        tx.execute_batch("ALTER TABLE crates ADD COLUMN upstream TEXT;")?;
+        Ok(())
+    }),
+    ("encode records like the values stored in sled", |tx| {
+        // Columns declared as TEXT keep the encoded records as blobs, since SQLite only converts numbers to text
+        encode_records::<UploadedPackage>(tx, "crate_versions", "package")?;
+        encode_records::<auth::User>(tx, "users", "record")?;
+        encode_records::<TokenEntry>(tx, "tokens", "record")?;
+        encode_records::<PublicKeyEntry>(tx, "public_keys", "record")?;
+        encode_records::<AuditEvent>(tx, "audit_events", "record")?;
+        encode_records::<CachedBlob>(tx, "cached_blobs", "record")
+    }),
+];
+
+/// Re-encode the JSON records in `column` of `table` with [`codec`], leaving any that cannot be decoded as they are.
+fn encode_records<T: Record>(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+) -> Result<(), anyhow::Error> {
+    let rows = tx
+        .prepare(&format!("SELECT rowid, {column} FROM {table}"))?
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, raw_record(row, 1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (rowid, raw) in rows {
+        match serde_json::from_slice::<T>(&raw) {
+            Ok(value) => {
+                tx.execute(
+                    &format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"),
+                    params![codec::encode(&value)?, rowid],
+                )?;
+            }
+            Err(e) => warn!("leaving unreadable record {rowid} in {table} as it is: {e}"),
+        }
+    }
+    Ok(())
+}
+
+pub struct SqliteStore {
+    path: PathBuf,
+    /// Connections that are not in use by any call
+    idle: Mutex<Vec<Connection>>,
+}
+
+/// A connection taken from the store's pool, which returns it to the pool when dropped.
+struct PooledConnection<'a> {
+    store: &'a SqliteStore,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken when dropped")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn
+            .as_mut()
+            .expect("connection is only taken when dropped")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // A panic while holding the lock cannot leave the pool inconsistent, so it is still usable
+        let mut idle = self.store.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Open a connection to the database at `path`.
+fn connect(path: &Path) -> Result<Connection, anyhow::Error> {
+    let conn = Connection::open(path).with_context(|| "unable to open database")?;
+    // Wait for other connections using the database, including those of the pool and the `sqlite3` shell, instead of
+    // failing immediately
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Copy the whole database that `conn` is connected to into a new database at `path`.
+fn snapshot(conn: &Connection, path: &Path) -> Result<(), anyhow::Error> {
+    if path.exists() {
+        return Err(anyhow!("snapshot {} already exists", path.display()));
+    }
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("snapshot path is not valid UTF-8"))?;
+    conn.execute("VACUUM INTO ?", [path])
+        .with_context(|| "could not snapshot database")?;
+    Ok(())
+}
+
+/// Read an encoded record from a column, which holds text if the record could not be re-encoded when migrating.
+fn raw_record(row: &Row, idx: usize) -> rusqlite::Result<Vec<u8>> {
+    let value = row.get_ref(idx)?;
+    value
+        .as_bytes()
+        .map(<[u8]>::to_vec)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, value.data_type(), Box::new(e)))
+}
+
+/// Decode a record found while listing a table, reporting and skipping it if it cannot be decoded.
+fn decode_or_skip<T: Record>(table: &str, key: &str, raw: &[u8]) -> Option<T> {
+    match codec::decode(raw) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("skipping unreadable record {key} in {table}: {e:?}");
+            None
+        }
+    }
+}
+
+/// List the records of a table that cannot be decoded as `T`, where `query` lists the table as `(key, record)` pairs.
+fn unreadable_in<T: Record>(
+    conn: &Connection,
+    kind: &'static str,
+    query: &str,
+) -> Result<Vec<UnreadableRecord>, anyhow::Error> {
+    let rows = conn
+        .prepare(query)?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, raw_record(row, 1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows
+        .into_iter()
+        .filter(|(_, raw)| codec::decode::<T>(raw).is_err())
+        .map(|(key, _)| UnreadableRecord { kind, key })
+        .collect())
+}
+
+/// Whether an insert added a row, rather than failing because a row with the same key or unique columns exists.
+fn inserted(result: rusqlite::Result<usize>) -> Result<bool, rusqlite::Error> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Names of all crates in order.
+fn crate_names(conn: &Connection) -> Result<Vec<String>, anyhow::Error> {
+    Ok(conn
+        .prepare("SELECT name FROM crates ORDER BY name")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn read_header(conn: &Connection, crate_name: &str) -> Result<Option<CrateHeader>, anyhow::Error> {
+    let Some((is_local, visibility, updated_at, upstream)) = conn
+        .query_row(
+            "SELECT is_local, visibility, updated_at, upstream FROM crates WHERE name = ?",
+            [crate_name],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                ))
+            },
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let owners = conn
+        .prepare("SELECT owner FROM crate_owners WHERE crate_name = ? ORDER BY position")?
+        .query_map([crate_name], |row| row.get::<_, String>(0))?
+        .map(|owner| Ok(CrateOwner::parse(&owner?)))
+        .collect::<Result<_, anyhow::Error>>()?;
+
+    Ok(Some(CrateHeader {
+        time_of_last_update: updated_at,
+        is_local,
+        owners,
+        visibility: Visibility::try_from(visibility).map_err(|e| anyhow!(e))?,
+        upstream,
+    }))
+}
+
+fn read_versions(
+    conn: &Connection,
+    crate_name: &str,
+) -> Result<Vec<UploadedPackage>, anyhow::Error> {
+    let mut versions = conn
+        .prepare("SELECT package FROM crate_versions WHERE crate_name = ? ORDER BY position")?
+        .query_map([crate_name], |row| raw_record(row, 0))?
+        .map(|raw| codec::decode::<UploadedPackage>(&raw?))
+        .collect::<Result<Vec<_>, _>>()?;
+    sort_versions(&mut versions);
+    Ok(versions)
+}
+
+fn read_crate(conn: &Connection, crate_name: &str) -> Result<Option<Entry>, anyhow::Error> {
+    let Some(header) = read_header(conn, crate_name)? else {
+        return Ok(None);
+    };
+    Ok(Some(Entry::from_parts(
+        header,
+        read_versions(conn, crate_name)?,
+    )))
+}
+
+fn delete_crate(tx: &Transaction, crate_name: &str) -> Result<(), anyhow::Error> {
+    tx.execute(
+        "DELETE FROM crate_versions WHERE crate_name = ?",
+        [crate_name],
+    )?;
+    tx.execute(
+        "DELETE FROM crate_owners WHERE crate_name = ?",
+        [crate_name],
+    )?;
+    tx.execute("DELETE FROM crates WHERE name = ?", [crate_name])?;
+    Ok(())
+}
+
+/// Create or replace a crate's header, leaving its versions as they are.
+fn write_header(
+    tx: &Transaction,
+    crate_name: &str,
+    header: &CrateHeader,
+) -> Result<(), anyhow::Error> {
+    tx.execute(
+        "INSERT INTO crates (name, is_local, visibility, updated_at, upstream) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                is_local = excluded.is_local,
+                visibility = excluded.visibility,
+                updated_at = excluded.updated_at,
+                upstream = excluded.upstream",
+        params![
+            crate_name,
+            header.is_local,
+            header.visibility.to_string(),
+            header.time_of_last_update,
+            header.upstream
+        ],
+    )?;
+    tx.execute(
+        "DELETE FROM crate_owners WHERE crate_name = ?",
+        [crate_name],
+    )?;
+    for (position, owner) in header.owners.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO crate_owners (crate_name, position, owner) VALUES (?, ?, ?)",
+            params![crate_name, position, owner.login()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Add or replace a single version of a crate, keeping its position if it already exists.
+fn write_version(
+    tx: &Transaction,
+    crate_name: &str,
+    version: &UploadedPackage,
+) -> Result<(), anyhow::Error> {
+    let position: i64 = tx.query_row(
+        "SELECT COALESCE(
+            (SELECT position FROM crate_versions WHERE crate_name = ?1 AND version = ?2),
+            (SELECT MAX(position) + 1 FROM crate_versions WHERE crate_name = ?1),
+            0
+        )",
+        params![crate_name, version.pkg.vers],
+        |row| row.get(0),
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO crate_versions (crate_name, position, version, cksum, yanked, package)
+            VALUES (?, ?, ?, ?, ?, ?)",
+        params![
+            crate_name,
+            position,
+            version.pkg.vers,
+            version.pkg.cksum,
+            version.pkg.yanked,
+            codec::encode(version)?
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_crate(tx: &Transaction, crate_name: &str, entry: &Entry) -> Result<(), anyhow::Error> {
+    delete_crate(tx, crate_name)?;
+    write_header(tx, crate_name, &entry.header())?;
+    for version in &entry.versions {
+        write_version(tx, crate_name, version)?;
+    }
+    Ok(())
+}
+
+fn read_team(conn: &Connection, name: &str) -> Result<Option<Team>, anyhow::Error> {
+    let exists = conn
+        .query_row("SELECT 1 FROM teams WHERE name = ?", [name], |_| Ok(()))
+        .optional()?
+        .is_some();
+    if !exists {
+        return Ok(None);
+    }
+
+    let members = conn
+        .prepare(
+            "SELECT username, maintainer FROM team_members WHERE team_name = ? ORDER BY position",
+        )?
+        .query_map([name], |row| {
+            Ok(TeamMember {
+                username: row.get(0)?,
+                maintainer: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Team {
+        name: name.to_owned(),
+        members,
+    }))
+}
+
+fn write_team(tx: &Transaction, team: &Team) -> Result<(), anyhow::Error> {
+    tx.execute("DELETE FROM team_members WHERE team_name = ?", [&team.name])?;
+    tx.execute(
+        "INSERT OR IGNORE INTO teams (name) VALUES (?)",
+        [&team.name],
+    )?;
+    for (position, member) in team.members.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO team_members (team_name, position, username, maintainer)
+                VALUES (?, ?, ?, ?)",
+            params![team.name, position, member.username, member.maintainer],
+        )?;
+    }
+    Ok(())
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let mut conn = connect(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        match version {
+            0 => {
+                // Database was empty
+                let tx = conn.transaction()?;
+                tx.execute_batch(SCHEMA)?;
+                tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+                tx.commit()
+                    .with_context(|| "could not create database schema")?;
+            }
+            SCHEMA_VERSION => {}
+            version if version < SCHEMA_VERSION => {
+                warn!("database was created in an older version of the registry (schema version {version})");
+                // Keep a copy of the database as it was, in case a migration goes wrong
+                let backup_path = migrations::backup_path(path, version as u32);
+                info!("backing up database to {}", backup_path.display());
+                snapshot(&conn, &backup_path).with_context(|| "could not back up database")?;
+
+                for report in Self::migrations(version) {
+                    let (_, migrate) = MIGRATIONS[report.from as usize - 1];
+                    let tx = conn.transaction()?;
+                    migrate(&tx)?;
+                    tx.pragma_update(None, "user_version", report.from + 1)?;
+                    tx.commit()
+                        .with_context(|| format!("could not migrate from version {}", report.from))?;
+                    info!("{report}");
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "database was created in a newer version of the registry (schema version {version})"
+                ))
+            }
+        }
+
+        Ok(SqliteStore {
+            path: path.to_owned(),
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    /// The migrations that upgrade a database from schema version `from` to the current version.
+    fn migrations(from: i32) -> Vec<MigrationReport> {
+        (from.max(1)..SCHEMA_VERSION)
+            .map(|version| MigrationReport {
+                from: version as u32,
+                description: MIGRATIONS[version as usize - 1].0,
+                changes: 0,
+            })
+            .collect()
+    }
+
+    /// Report the migrations that opening the database at `path` would run, without changing it.
+    pub fn dry_run_migrations(
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<MigrationReport>, anyhow::Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let conn = Connection::open(path).with_context(|| "unable to open database")?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        match version {
+            0 => Ok(Vec::new()),
+            version if version > SCHEMA_VERSION => Err(anyhow!(
+                "database was created in a newer version of the registry (schema version {version})"
+            )),
+            version => Ok(Self::migrations(version)),
+        }
+    }
+
+    /// Take an idle connection from the pool, or open a new one if every connection is in use.
+    fn conn(&self) -> Result<PooledConnection<'_>, anyhow::Error> {
+        // A panic while holding the lock cannot leave the pool inconsistent, so it is still usable
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => connect(&self.path)?,
+        };
+        Ok(PooledConnection {
+            store: self,
+            conn: Some(conn),
+        })
+    }
+
+    /// List the records in a table, as `(key, record)` pairs, skipping any that cannot be decoded.
+    fn list<T: Record>(
+        &self,
+        table: &str,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<T>, anyhow::Error> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(query)?;
+        let rows = stmt
+            .query_map(params, |row| {
+                Ok((row.get::<_, String>(0)?, raw_record(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows
+            .iter()
+            .filter_map(|(key, raw)| decode_or_skip(table, key, raw))
+            .collect())
+    }
+
+    /// Read from a table with `read`, logging errors and returning nothing if it cannot be read.
+    fn read_or_log<T>(
+        &self,
+        table: &str,
+        read: impl FnOnce(&Connection) -> Result<Vec<T>, anyhow::Error>,
+    ) -> Vec<T> {
+        self.conn()
+            .and_then(|conn| read(&conn))
+            .unwrap_or_else(|e| {
+                warn!("could not read {table}: {e:?}");
+                Vec::new()
+            })
+    }
+
+    /// List the records in a table, logging errors and returning nothing if it cannot be read.
+    fn list_or_log<T: Record>(&self, table: &str, query: &str) -> Vec<T> {
+        self.list(table, query, []).unwrap_or_else(|e| {
+            warn!("could not read {table}: {e:?}");
+            Vec::new()
+        })
+    }
+}
+
+impl MetadataStore for SqliteStore {
+    fn snapshot(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let conn = self.conn()?;
+        snapshot(&conn, path)
+    }
+
+    fn get_crate(&self, crate_name: &str) -> Result<Option<Entry>, anyhow::Error> {
+        let conn = self.conn()?;
+        read_crate(&conn, crate_name).with_context(|| "could not read crate entry")
+    }
+
+    fn remove_crate(&self, crate_name: &str) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        delete_crate(&tx, crate_name)?;
+        tx.commit().with_context(|| "could not remove crate")
+    }
+
+    fn insert_crate(&self, crate_name: &str, entry: &Entry) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        write_crate(&tx, crate_name, entry)?;
+        tx.commit().with_context(|| "could not insert crate")
+    }
+
+    fn get_crate_header(&self, crate_name: &str) -> Result<Option<CrateHeader>, anyhow::Error> {
+        let conn = self.conn()?;
+        read_header(&conn, crate_name).with_context(|| "could not read crate entry")
+    }
+
+    fn replace_crate_header(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+    ) -> Result<bool, anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if read_header(&tx, crate_name)?.as_ref() != Some(expected) {
+            return Ok(false);
+        }
+        write_header(&tx, crate_name, header)?;
+        tx.commit().with_context(|| "could not update crate")?;
+        Ok(true)
+    }
+
+    fn get_crate_version(
+        &self,
+        crate_name: &str,
+        version: &str,
+    ) -> Result<Option<UploadedPackage>, anyhow::Error> {
+        self.conn()?
+            .query_row(
+                "SELECT package FROM crate_versions WHERE crate_name = ? AND version = ?",
+                [crate_name, version],
+                |row| raw_record(row, 0),
+            )
+            .optional()
+            .with_context(|| "could not access crate version")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+    }
+
+    fn get_crate_versions(&self, crate_name: &str) -> Result<Vec<UploadedPackage>, anyhow::Error> {
+        let conn = self.conn()?;
+        read_versions(&conn, crate_name).with_context(|| "could not read crate versions")
+    }
+
+    fn insert_crate_version(
+        &self,
+        crate_name: &str,
+        expected: Option<&CrateHeader>,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error> {
+        let mut conn = self.conn()?;
+        // Taking the write lock up front keeps other connections from changing the crate between the checks and writes
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if read_header(&tx, crate_name)?.as_ref() != expected {
+            return Ok(false);
+        }
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM crate_versions WHERE crate_name = ? AND version = ?",
+                [crate_name, &version.pkg.vers],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if exists {
+            return Ok(false);
+        }
+        write_header(&tx, crate_name, header)?;
+        write_version(&tx, crate_name, version)?;
+        tx.commit()
+            .with_context(|| "could not insert crate version")?;
+        Ok(true)
+    }
+
+    fn replace_crate_version(
+        &self,
+        crate_name: &str,
+        expected: &CrateHeader,
+        header: &CrateHeader,
+        version: &UploadedPackage,
+    ) -> Result<bool, anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if read_header(&tx, crate_name)?.as_ref() != Some(expected) {
+            return Ok(false);
+        }
+        write_header(&tx, crate_name, header)?;
+        write_version(&tx, crate_name, version)?;
+        tx.commit()
+            .with_context(|| "could not update crate version")?;
+        Ok(true)
+    }
+
+    fn unreadable_records(&self) -> Result<Vec<UnreadableRecord>, anyhow::Error> {
+        let conn = self.conn()?;
+        let mut unreadable = Vec::new();
+        let names = conn
+            .prepare("SELECT name FROM crates ORDER BY name")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for name in names {
+            if read_header(&conn, &name).is_err() {
+                unreadable.push(UnreadableRecord {
+                    kind: "crate-header",
+                    key: name,
+                });
+            }
+        }
+        unreadable.extend(unreadable_in::<UploadedPackage>(
+            &conn,
+            "crate-version",
+            "SELECT crate_name || '@' || version, package FROM crate_versions ORDER BY crate_name, position",
+        )?);
+        unreadable.extend(unreadable_in::<auth::User>(
+            &conn,
+            "user",
+            "SELECT username, record FROM users ORDER BY username",
+        )?);
+        unreadable.extend(unreadable_in::<TokenEntry>(
+            &conn,
+            "token",
+            "SELECT lower(hex(hash)), record FROM tokens ORDER BY hash",
+        )?);
+        unreadable.extend(unreadable_in::<PublicKeyEntry>(
+            &conn,
+            "public-key",
+            "SELECT key_id, record FROM public_keys ORDER BY key_id",
+        )?);
+        unreadable.extend(unreadable_in::<AuditEvent>(
+            &conn,
+            "audit-event",
+            "SELECT CAST(id AS TEXT), record FROM audit_events ORDER BY timestamp, id",
+        )?);
+        unreadable.extend(unreadable_in::<CachedBlob>(
+            &conn,
+            "cached-blob",
+            "SELECT key, record FROM cached_blobs ORDER BY key",
+        )?);
+        Ok(unreadable)
+    }
+
+    fn iter_crates(&self) -> Box<dyn Iterator<Item = (String, Entry)> + Send + '_> {
+        let crates = self.read_or_log("crates", |conn| {
+            Ok(crate_names(conn)?
+                .into_iter()
+                .filter_map(|name| match read_crate(conn, &name) {
+                    Ok(entry) => Some((name, entry?)),
+                    Err(e) => {
+                        warn!("skipping unreadable crate {name}: {e:?}");
+                        None
+                    }
+                })
+                .collect())
+        });
+        Box::new(crates.into_iter())
+    }
+
+    fn iter_crate_headers(&self) -> Box<dyn Iterator<Item = (String, CrateHeader)> + Send + '_> {
+        let headers = self.read_or_log("crates", |conn| {
+            Ok(crate_names(conn)?
+                .into_iter()
+                .filter_map(|name| match read_header(conn, &name) {
+                    Ok(header) => Some((name, header?)),
+                    Err(e) => {
+                        warn!("skipping unreadable crate {name}: {e:?}");
+                        None
+                    }
+                })
+                .collect())
+        });
+        Box::new(headers.into_iter())
+    }
+
+    fn get_user(&self, username: &str) -> Result<Option<auth::User>, anyhow::Error> {
+        self.conn()?
+            .query_row(
+                "SELECT record FROM users WHERE username = ?",
+                [username],
+                |row| raw_record(row, 0),
+            )
+            .optional()?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode user entry")
+    }
+
+    fn insert_user(&self, username: &str, user: &auth::User) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO users (username, kind, blocked, record) VALUES (?, ?, ?, ?)",
+                params![username, user.kind_label(), user.blocked, codec::encode(user)?],
+            )
+            .with_context(|| "could not insert user")
+            .map(|_| ())
+    }
+
+    fn create_user(&self, username: &str, user: &auth::User) -> Result<bool, anyhow::Error> {
+        let inserted = self
+            .conn()?
+            .execute(
+                "INSERT OR IGNORE INTO users (username, kind, blocked, record) VALUES (?, ?, ?, ?)",
+                params![
+                    username,
+                    user.kind_label(),
+                    user.blocked,
+                    codec::encode(user)?
+                ],
+            )
+            .with_context(|| "could not insert user")?;
+        Ok(inserted == 1)
+    }
+
+    fn delete_user(&self, username: &str) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute("DELETE FROM users WHERE username = ?", [username])
+            .with_context(|| "could not remove user")
+            .map(|_| ())
+    }
+
+    fn iter_users(&self) -> Box<dyn Iterator<Item = auth::User> + Send + '_> {
+        let users = self.list_or_log(
+            "users",
+            "SELECT username, record FROM users ORDER BY username",
+        );
+        Box::new(users.into_iter())
+    }
+
+    fn get_team(&self, name: &str) -> Result<Option<Team>, anyhow::Error> {
+        let conn = self.conn()?;
+        read_team(&conn, name).with_context(|| "could not read team entry")
+    }
+
+    fn insert_team(&self, team: &Team) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        write_team(&tx, team)?;
+        tx.commit().with_context(|| "could not insert team")
+    }
+
+    fn modify_team(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&mut Team) -> Result<(), String>,
+    ) -> Result<Option<Result<(), String>>, anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let Some(mut team) = read_team(&tx, name)? else {
+            return Ok(None);
+        };
+        if let Err(e) = f(&mut team) {
+            return Ok(Some(Err(e)));
+        }
+        write_team(&tx, &team)?;
+        tx.commit().with_context(|| "could not modify team")?;
+        Ok(Some(Ok(())))
+    }
+
+    fn iter_teams(&self) -> Box<dyn Iterator<Item = Team> + Send + '_> {
+        let teams = self.read_or_log("teams", |conn| {
+            Ok(conn
+                .prepare("SELECT name FROM teams ORDER BY name")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter_map(|name| read_team(conn, &name).ok().flatten())
+                .collect())
+        });
+        Box::new(teams.into_iter())
+    }
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "INSERT INTO audit_events (timestamp, actor, action, target, outcome, record)
+                    VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    event.timestamp.timestamp_millis(),
+                    event.actor,
+                    event.action.as_str(),
+                    event.target,
+                    event.outcome.as_str(),
+                    codec::encode(event)?
+                ],
+            )
+            .with_context(|| "could not insert audit event")
+            .map(|_| ())
+    }
+
+    fn iter_audit_events(&self) -> Box<dyn DoubleEndedIterator<Item = AuditEvent> + Send + '_> {
+        Box::new(AuditEvents {
+            store: self,
+            front: VecDeque::new(),
+            front_bound: (i64::MIN, i64::MIN),
+            back: VecDeque::new(),
+            back_bound: (i64::MAX, i64::MAX),
+        })
+    }
+
+    fn prune_audit_events(&self, cutoff: DateTime<Utc>) -> Result<usize, anyhow::Error> {
+        self.conn()?
+            .execute(
+                "DELETE FROM audit_events WHERE timestamp < ?",
+                [cutoff.timestamp_millis()],
+            )
+            .with_context(|| "could not remove audit events")
+    }
+
+    fn get_cached_blob(&self, key: &str) -> Result<Option<CachedBlob>, anyhow::Error> {
+        self.conn()?
+            .query_row(
+                "SELECT record FROM cached_blobs WHERE key = ?",
+                [key],
+                |row| raw_record(row, 0),
+            )
+            .optional()
+            .with_context(|| "could not access cached file entry")?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+    }
+
+    fn insert_cached_blob(&self, key: &str, blob: &CachedBlob) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO cached_blobs (key, size, last_access, record) VALUES (?, ?, ?, ?)",
+                params![
+                    key,
+                    blob.size,
+                    blob.last_access.timestamp_millis(),
+                    codec::encode(blob)?
+                ],
+            )
+            .with_context(|| "could not insert cached file entry")
+            .map(|_| ())
+    }
+
+    fn remove_cached_blob(&self, key: &str) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute("DELETE FROM cached_blobs WHERE key = ?", [key])
+            .with_context(|| "could not remove cached file entry")
+            .map(|_| ())
+    }
+
+    fn iter_cached_blobs(&self) -> Box<dyn Iterator<Item = (String, CachedBlob)> + Send + '_> {
+        let rows = self.read_or_log("cached_blobs", |conn| {
+            Ok(conn
+                .prepare("SELECT key, record FROM cached_blobs ORDER BY key")?
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, raw_record(row, 1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?)
+        });
+        Box::new(rows.into_iter().filter_map(|(key, raw)| {
+            let blob = decode_or_skip("cached_blobs", &key, &raw)?;
+            Some((key, blob))
+        }))
+    }
+
+    fn get_token_user(
+        &self,
+        token: &[u8],
+    ) -> Result<Option<(TokenEntry, auth::User)>, anyhow::Error> {
+        let raw = self
+            .conn()?
+            .query_row("SELECT record FROM tokens WHERE hash = ?", [token], |row| {
+                raw_record(row, 0)
+            })
+            .optional()
+            .with_context(|| "could not access token entry")?;
+        let Some(raw) = raw else {
+            return Ok(None);
+        };
+
+        let entry: TokenEntry =
+            codec::decode(&raw).with_context(|| "could not decode token entry")?;
+        Ok(self
+            .get_user(entry.username())
+            .with_context(|| "could not get user entry")?
+            .map(|user| (entry, user)))
+    }
+
+    fn insert_token(&self, token: &[u8], entry: &TokenEntry) -> Result<bool, anyhow::Error> {
+        let result = self.conn()?.execute(
+            "INSERT INTO tokens (hash, username, label, record) VALUES (?, ?, ?, ?)",
+            params![
+                token,
+                entry.username(),
+                entry.label(),
+                codec::encode(entry)?
+            ],
+        );
+        inserted(result).with_context(|| "could not insert token")
+    }
+
+    fn iter_tokens(&self) -> Box<dyn Iterator<Item = (Vec<u8>, TokenEntry)> + Send + '_> {
+        let tokens = self
+            .read_or_log("tokens", |conn| {
+                Ok(conn
+                    .prepare("SELECT hash, record FROM tokens ORDER BY hash")?
+                    .query_map([], |row| {
+                        Ok((row.get::<_, Vec<u8>>(0)?, raw_record(row, 1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?)
+            })
+            .into_iter()
+            .filter_map(|(token, raw)| {
+                let key = token
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>();
+                Some((token, decode_or_skip("tokens", &key, &raw)?))
+            })
+            .collect::<Vec<_>>();
+        Box::new(tokens.into_iter())
+    }
+
+    fn get_user_tokens(&self, username: &str) -> Result<Vec<TokenEntry>, anyhow::Error> {
+        self.list(
+            "tokens",
+            "SELECT label, record FROM tokens WHERE username = ? ORDER BY label",
+            [username],
+        )
+    }
+
+    fn update_token(&self, token: &[u8], entry: &TokenEntry) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "UPDATE tokens SET record = ? WHERE hash = ?",
+                params![codec::encode(entry)?, token],
+            )
+            .with_context(|| "could not update token")
+            .map(|_| ())
+    }
+
+    fn delete_token(&self, token: &[u8]) -> Result<Option<TokenEntry>, anyhow::Error> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let raw = tx
+            .query_row("SELECT record FROM tokens WHERE hash = ?", [token], |row| {
+                raw_record(row, 0)
+            })
+            .optional()?;
+        let key = token
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let entry = raw.and_then(|raw| decode_or_skip("tokens", &key, &raw));
+        tx.execute("DELETE FROM tokens WHERE hash = ?", [token])?;
+        tx.commit().with_context(|| "could not delete token")?;
+        Ok(entry)
+    }
+
+    fn delete_user_token(&self, username: &str, label: &str) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "DELETE FROM tokens WHERE username = ? AND label = ?",
+                [username, label],
+            )
+            .with_context(|| "could not delete token")
+            .map(|_| ())
+    }
+
+    fn insert_public_key(&self, entry: &PublicKeyEntry) -> Result<bool, anyhow::Error> {
+        let result = self.conn()?.execute(
+            "INSERT INTO public_keys (key_id, username, label, record) VALUES (?, ?, ?, ?)",
+            params![
+                entry.key_id(),
+                entry.username(),
+                entry.label(),
+                codec::encode(entry)?
+            ],
+        );
+        inserted(result).with_context(|| "could not insert public key")
+    }
+
+    fn get_public_key(&self, key_id: &str) -> Result<Option<PublicKeyEntry>, anyhow::Error> {
+        self.conn()?
+            .query_row(
+                "SELECT record FROM public_keys WHERE key_id = ?",
+                [key_id],
+                |row| raw_record(row, 0),
+            )
+            .optional()?
+            .map(|raw| codec::decode(&raw))
+            .transpose()
+            .with_context(|| "could not decode public key entry")
+    }
+
+    fn iter_public_keys(&self) -> Box<dyn Iterator<Item = PublicKeyEntry> + Send + '_> {
+        let keys = self.list_or_log(
+            "public_keys",
+            "SELECT key_id, record FROM public_keys ORDER BY key_id",
+        );
+        Box::new(keys.into_iter())
+    }
+
+    fn get_user_public_keys(&self, username: &str) -> Result<Vec<PublicKeyEntry>, anyhow::Error> {
+        self.list(
+            "public_keys",
+            "SELECT label, record FROM public_keys WHERE username = ? ORDER BY label",
+            [username],
+        )
+    }
+
+    fn has_user_public_key(&self, username: &str, label: &str) -> Result<bool, anyhow::Error> {
+        self.conn()?
+            .query_row(
+                "SELECT 1 FROM public_keys WHERE username = ? AND label = ?",
+                [username, label],
+                |_| Ok(()),
+            )
+            .optional()
+            .with_context(|| "could not access public key entry")
+            .map(|found| found.is_some())
+    }
+
+    fn delete_user_public_key(&self, username: &str, label: &str) -> Result<(), anyhow::Error> {
+        self.conn()?
+            .execute(
+                "DELETE FROM public_keys WHERE username = ? AND label = ?",
+                [username, label],
+            )
+            .with_context(|| "could not delete public key")
+            .map(|_| ())
+    }
+}
+
+/// Iterator over the audit log, which reads it a page at a time from whichever end it is iterated from.
+struct AuditEvents<'a> {
+    store: &'a SqliteStore,
+    /// Events read from the front that have not been returned yet
+    front: VecDeque<AuditEvent>,
+    /// Timestamp and id of the last event read from the front
+    front_bound: (i64, i64),
+    /// Events read from the back that have not been returned yet, in order
+    back: VecDeque<AuditEvent>,
+    /// Timestamp and id of the last event read from the back
+    back_bound: (i64, i64),
+}
+
+impl AuditEvents<'_> {
+    /// Read the next page of events between the bounds, from the back if `reverse`, moving that bound past it.
+    ///
+    /// Returns `None` once no events are left between the bounds, and skips events that cannot be decoded.
+    fn read_page(&mut self, reverse: bool) -> Option<Vec<AuditEvent>> {
+        let order = if reverse { "DESC" } else { "ASC" };
+        let query = format!(
+            "SELECT timestamp, id, record FROM audit_events
+                WHERE (timestamp, id) > (?, ?) AND (timestamp, id) < (?, ?)
+                ORDER BY timestamp {order}, id {order} LIMIT ?"
+        );
+        let (after, before) = (self.front_bound, self.back_bound);
+        let rows = self.store.read_or_log("audit_events", |conn| {
+            Ok(conn
+                .prepare(&query)?
+                .query_map(
+                    params![after.0, after.1, before.0, before.1, AUDIT_PAGE_SIZE],
+                    |row| Ok(((row.get(0)?, row.get(1)?), raw_record(row, 2)?)),
+                )?
+                .collect::<Result<Vec<((i64, i64), Vec<u8>)>, _>>()?)
+        });
+
+        let (bound, _) = rows.last()?;
+        if reverse {
+            self.back_bound = *bound;
+        } else {
+            self.front_bound = *bound;
+        }
+        Some(
+            rows.iter()
+                .filter_map(|((_, id), raw)| decode_or_skip("audit_events", &id.to_string(), raw))
+                .collect(),
+        )
+    }
+}
+
+impl Iterator for AuditEvents<'_> {
+    type Item = AuditEvent;
+
+    fn next(&mut self) -> Option<AuditEvent> {
+        loop {
+            if let Some(event) = self.front.pop_front() {
+                return Some(event);
+            }
+            match self.read_page(false) {
+                Some(events) => self.front.extend(events),
+                // Everything left was already read from the back
+                None => return self.back.pop_front(),
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for AuditEvents<'_> {
+    fn next_back(&mut self) -> Option<AuditEvent> {
+        loop {
+            if let Some(event) = self.back.pop_back() {
+                return Some(event);
+            }
+            match self.read_page(true) {
+                Some(events) => {
+                    for event in events {
+                        self.back.push_front(event);
+                    }
+                }
+                // Everything left was already read from the front
+                None => return self.front.pop_back(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::AuditAction,
+        auth::{AccountOwner, User},
+        db::{copy_store, Db, StoreKind},
+        test_util::TempDir,
+        token,
+    };
+
+    #[test]
+    fn copy_from_sled() {
+        let dir = TempDir::new("sqlite-copy");
+        let sled = Db::open(StoreKind::Sled, &dir).unwrap();
+        let entry = Entry {
+            versions: Vec::new(),
+            time_of_last_update: Utc::now(),
+            is_local: true,
+            owners: vec![
+                CrateOwner::User("alice".to_owned()),
+                CrateOwner::Team("platform".to_owned()),
+            ],
+            visibility: Visibility::Team("platform".to_owned()),
+            upstream: None,
+        };
+        sled.insert_crate("foo", &entry).unwrap();
+        let account =
+            User::new_service_account("ci".to_owned(), AccountOwner::User("alice".to_owned()));
+        sled.insert_user("ci", &account).unwrap();
+        sled.insert_team(&Team {
+            name: "platform".to_owned(),
+            members: vec![TeamMember {
+                username: "alice".to_owned(),
+                maintainer: true,
+            }],
+        })
+        .unwrap();
+        token::create_token(&sled, "ci", "deploy", None, None, None)
+            .unwrap()
+            .unwrap();
+        sled.insert_audit_event(&AuditEvent::anonymous(AuditAction::Login, "first"))
+            .unwrap();
+        sled.insert_audit_event(&AuditEvent::anonymous(AuditAction::Login, "second"))
+            .unwrap();
+
+        let sqlite = Db::open(StoreKind::Sqlite, &dir).unwrap();
+        let report = copy_store(&sled, &sqlite).unwrap();
+        assert_eq!(
+            (report.crates, report.tokens, report.audit_events),
+            (1, 1, 2)
+        );
+        drop(sqlite);
+        let sqlite = Db::open(StoreKind::Sqlite, &dir).unwrap();
+
+        let copied = sqlite.get_crate("foo").unwrap().unwrap();
+        assert_eq!(copied.owners, entry.owners);
+        assert_eq!(copied.visibility, entry.visibility);
+        assert!(sqlite.get_user("ci").unwrap().unwrap().is_service_account());
+        assert!(sqlite
+            .get_team("platform")
+            .unwrap()
+            .unwrap()
+            .is_maintainer("alice"));
+
+        let (hash, _) = sqlite.iter_tokens().next().unwrap();
+        let (token, user) = sqlite.get_token_user(&hash).unwrap().unwrap();
+        assert_eq!((token.label(), user.username.as_str()), ("deploy", "ci"));
+        assert!(sqlite.delete_token(&hash).unwrap().is_some());
+        assert!(sqlite.get_user_tokens("ci").unwrap().is_empty());
+
+        let latest = sqlite.iter_audit_events().next_back().unwrap();
+        assert_eq!(latest.target, "second");
+        let cutoff = Utc::now() + chrono::Duration::seconds(1);
+        assert_eq!(sqlite.prune_audit_events(cutoff).unwrap(), 2);
+    }
+
+    #[test]
+    fn copy_reports_unreadable_records() {
+        let dir = TempDir::new("sqlite-unreadable");
+        let sqlite = Db::open(StoreKind::Sqlite, &dir).unwrap();
+        let account =
+            User::new_service_account("ci".to_owned(), AccountOwner::User("alice".to_owned()));
+        sqlite.insert_user("ci", &account).unwrap();
+        Connection::open(dir.join(StoreKind::Sqlite.file_name()))
+            .unwrap()
+            .execute(
+                "INSERT INTO users (username, kind, blocked, record) VALUES ('broken', 'user', 0, '{')",
+                [],
+            )
+            .unwrap();
+
+        let sled = Db::open(StoreKind::Sled, &dir).unwrap();
+        let report = copy_store(&sqlite, &sled).unwrap();
+        assert_eq!(report.users, 1);
+        assert_eq!(
+            report.unreadable,
+            [UnreadableRecord {
+                kind: "user",
+                key: "broken".to_owned()
+            }]
+        );
+        assert!(sled.unreadable_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrates_schema() {
+        let dir = TempDir::new("sqlite-migrate");
+        let path = dir.join(StoreKind::Sqlite.file_name());
+        // Roll a new database back to the first version of the schema, which stored records as JSON
+        drop(SqliteStore::open(&path).unwrap());
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "DROP TABLE cached_blobs; ALTER TABLE crates DROP COLUMN upstream; PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        let account =
+            User::new_service_account("ci".to_owned(), AccountOwner::User("alice".to_owned()));
+        conn.execute(
+            "INSERT INTO users (username, kind, blocked, record) VALUES ('ci', 'service', 0, ?)",
+            [serde_json::to_string(&account).unwrap()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let reports = SqliteStore::dry_run_migrations(&path).unwrap();
+        assert_eq!(reports.len(), 3);
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.get_user("ci").unwrap().unwrap().is_service_account());
+        assert!(store.unreadable_records().unwrap().is_empty());
+        // Migrated databases are backed up first
+        let backups = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains(".backup-v1-")
+            })
+            .count();
+        assert_eq!(backups, 1);
+
+        let blob = CachedBlob {
+            size: 10,
+            last_access: Utc::now(),
+            hits: 0,
+        };
+        store
+            .insert_cached_blob("crates/foo/1.0.0.crate", &blob)
+            .unwrap();
+        assert_eq!(
+            store.get_cached_blob("crates/foo/1.0.0.crate").unwrap(),
+            Some(blob)
+        );
+        assert!(SqliteStore::dry_run_migrations(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn audit_events_in_pages() {
+        let dir = TempDir::new("sqlite-audit");
+        let sqlite = Db::open(StoreKind::Sqlite, &dir).unwrap();
+        let count = AUDIT_PAGE_SIZE as usize * 2 + 1;
+        for i in 0..count {
+            sqlite
+                .insert_audit_event(&AuditEvent::anonymous(AuditAction::Login, i.to_string()))
+                .unwrap();
+        }
+
+        let targets = |events: Vec<AuditEvent>| -> Vec<usize> {
+            events
+                .iter()
+                .map(|event| event.target.parse().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            targets(sqlite.iter_audit_events().collect()),
+            (0..count).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            targets(sqlite.iter_audit_events().rev().collect()),
+            (0..count).rev().collect::<Vec<_>>()
+        );
+
+        // Reading from both ends meets in the middle without repeating or missing events
+        let mut events = sqlite.iter_audit_events();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        while let Some(event) = events.next() {
+            front.push(event);
+            back.extend(events.next_back());
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(targets(front), (0..count).collect::<Vec<_>>());
+    }
+}
@@ -1,53 +1,89 @@
-use anyhow::anyhow;
 use axum::{
     body::Bytes,
     extract::{Path, State},
+    response::{IntoResponse, Redirect, Response},
     routing::get,
     Router,
 };
 use reqwest::StatusCode;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-};
 
-use crate::{config::Config, crate_path, mirror, AppState, InternalError};
+use crate::{
+    blob::{self, Blobs},
+    cache::CacheStats,
+    config::Config,
+    mirror,
+    visibility::{self, IndexReader},
+    AppState, InternalError,
+};
 
 pub fn router() -> Router<AppState> {
     Router::new().route("/crates/:crate_name/:version/download", get(crate_download))
 }
 
 async fn crate_download(
+    reader: IndexReader,
     Path((crate_name, version)): Path<(String, String)>,
+    State(db): State<crate::Db>,
     State(state): State<Config>,
-) -> Result<(StatusCode, Bytes), InternalError> {
-    let cache_path = crate_path(state.data_dir, &crate_name, &version);
-    if cache_path.exists() {
-        tracing::info!("using cached {crate_name}@{version}");
-        let mut file = File::open(cache_path).await?;
-        let mut buf = Vec::with_capacity(file.metadata().await?.len() as usize);
-        file.read_to_end(&mut buf).await?;
+    State(blobs): State<Blobs>,
+    State(cache_stats): State<CacheStats>,
+) -> Result<Response, InternalError> {
+    let (mut is_local, mut cached_from) = (false, None);
+    if let Some(entry) = db.get_crate_header(&crate_name)? {
+        if !visibility::can_read(&db, &state, &entry, reader.username())? {
+            return Ok((StatusCode::NOT_FOUND, Bytes::new()).into_response());
+        }
+        is_local = entry.is_local;
+        cached_from = entry.upstream;
+    }
 
-        Ok((StatusCode::OK, buf.into()))
+    let key = blob::crate_key(&crate_name, &version);
+    let cached = if let Some(url) = blobs.presigned_url(&key) {
+        // Let the client fetch cached crates straight from the blob store
+        blobs
+            .exists(&key)
+            .await?
+            .then(|| Redirect::temporary(&url).into_response())
     } else {
-        if state.offline {
-            return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+        blobs
+            .get(&key)
+            .await?
+            .map(|bytes| (StatusCode::OK, bytes).into_response())
+    };
+    if let Some(response) = cached {
+        tracing::info!("using cached {crate_name}@{version}");
+        if !is_local {
+            if let Err(e) = cache_stats.record_hit(&db, &key) {
+                tracing::warn!("could not record use of cached {crate_name}@{version}: {e:?}");
+            }
         }
+        return Ok(response);
+    }
 
-        let bytes = match mirror::download_crate(&crate_name, &version).await? {
+    // Local crates are only ever stored here, so a missing file must not be looked up upstream
+    if is_local {
+        tracing::warn!("crate file of local crate {crate_name}@{version} is missing");
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()).into_response());
+    }
+    if state.offline {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()).into_response());
+    }
+
+    let Some(upstream) =
+        mirror::upstream_for(&state.upstreams, &crate_name, cached_from.as_deref())
+    else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()).into_response());
+    };
+    let cksum = db
+        .get_crate_version(&crate_name, &version)?
+        .map(|uploaded| uploaded.pkg.cksum);
+    let bytes =
+        match mirror::download_crate(upstream, &crate_name, &version, cksum.as_deref()).await? {
             Some(bytes) => bytes,
-            None => return Ok((StatusCode::NOT_FOUND, Bytes::new())),
+            None => return Ok((StatusCode::NOT_FOUND, Bytes::new()).into_response()),
         };
+    blobs.put(&key, bytes.clone()).await?;
+    cache_stats.record_miss(&db, &key, bytes.len() as u64)?;
 
-        let parent = cache_path
-            .parent()
-            .ok_or_else(|| anyhow!("invalid cache path"))?;
-        if !parent.exists() {
-            tokio::fs::create_dir_all(parent).await?;
-        }
-        let mut file = File::create(cache_path).await?;
-        file.write_all(&bytes).await?;
-
-        Ok((StatusCode::OK, bytes))
-    }
+    Ok((StatusCode::OK, bytes).into_response())
 }
@@ -1,94 +1,135 @@
-use std::{fs, io, path::Path, thread};
+use std::{fs, path::Path, thread};
 
-use rustwide::{cmd::SandboxBuilder, AlternativeRegistry, Crate, Toolchain, WorkspaceBuilder};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tracing::{debug, info};
+use anyhow::{anyhow, Context};
+use flate2::read::GzDecoder;
+use rustwide::{cmd::SandboxBuilder, Crate, Toolchain, Workspace, WorkspaceBuilder};
+use tokio::{runtime::Handle, sync::mpsc::UnboundedReceiver};
+use tracing::{debug, info, warn};
 
-pub fn start_background_thread(
-    data_dir: impl AsRef<Path>,
-    mut work_queue: UnboundedReceiver<(String, String)>,
-) {
-    let data_dir = data_dir.as_ref().to_owned();
+use crate::blob::{self, Blobs};
+
+/// Where crate files are unpacked to be built
+const SOURCES_DIR: &str = ".workspaces/docs-sources";
+
+pub fn start_background_thread(blobs: Blobs, mut work_queue: UnboundedReceiver<(String, String)>) {
+    // Uploads to the blob store are async, so are run on the server's runtime
+    let runtime = Handle::current();
     thread::spawn(move || {
         debug!("preparing docs build environment");
-        // Create a new workspace in .workspaces/docs-builder
-        let workspace =
-            WorkspaceBuilder::new(Path::new(".workspaces/docs-builder"), "altreg-docs-builder")
-                .sparse_registries(true)
-                .init()
-                .unwrap();
-        workspace.purge_all_build_dirs().unwrap();
-
-        // Run the builds on stable
-        let toolchain = Toolchain::dist("nightly");
-        toolchain.install(&workspace).unwrap();
-
+        let (workspace, toolchain) = match prepare() {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                warn!("could not prepare docs build environment, docs will not be built: {e:?}");
+                return;
+            }
+        };
         info!("docs builder ready");
 
-        loop {
-            let (name, version) = work_queue.blocking_recv().unwrap();
+        while let Some((name, version)) = work_queue.blocking_recv() {
             info!("building docs for {name}@{version}");
+            match build_docs(&workspace, &toolchain, &runtime, &blobs, &name, &version) {
+                Ok(()) => debug!("built crate"),
+                Err(e) => warn!("could not build docs for {name}@{version}: {e:?}"),
+            }
+        }
+    });
+}
+
+fn prepare() -> Result<(Workspace, Toolchain), anyhow::Error> {
+    // Create a new workspace in .workspaces/docs-builder
+    let workspace =
+        WorkspaceBuilder::new(Path::new(".workspaces/docs-builder"), "altreg-docs-builder")
+            .sparse_registries(true)
+            .init()?;
+    workspace.purge_all_build_dirs()?;
+
+    // Run the builds on stable
+    let toolchain = Toolchain::dist("nightly");
+    toolchain.install(&workspace)?;
+    Ok((workspace, toolchain))
+}
 
-            // Configure a sandbox with 1GB of RAM and no network access
-            let sandbox = SandboxBuilder::new()
-                .memory_limit(Some(1024 * 1024 * 1024))
-                .enable_networking(false);
-
-            // Create a build directory for this build
-            let mut build_dir = workspace.build_dir(&format!("{}-{}", name, version));
-            build_dir.purge().unwrap();
-
-            // Fetch crate from registry
-            let mut registry = AlternativeRegistry::new("sparse+https://localhost:1491/index/");
-            registry.sparse();
-            let krate = Crate::registry(registry, &name, &version);
-            krate.fetch(&workspace).unwrap();
-
-            debug!("building crate docs");
-            build_dir
-                .build(&toolchain, &krate, sandbox)
-                .run(|build| {
-                    // Build docs
-                    build
-                        .cargo()
-                        .args(&[
-                            "doc",
-                            "--offline",
-                            "--no-deps",
-                            "-Zsparse-registry",
-                            "-Zrustdoc-map",
-                            //r#"--config=doc.extern-map.registries.this_registry="http://localhost:1479/docs/""#,
-                        ])
-                        .run()?;
-
-                    // Copy docs to data directory
-                    let source_dir = build.host_target_dir().join("doc");
-                    let dest_dir = data_dir.join("docs").join(name).join(version);
-                    copy_dir_all(source_dir, dest_dir).unwrap();
-
-                    Ok(())
-                })
-                .unwrap();
-
-            // Clean up
-            build_dir.purge().unwrap();
-            krate.purge_from_cache(&workspace).unwrap();
-            debug!("built crate");
+/// Build the docs of a published version and upload them to the blob store.
+fn build_docs(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    runtime: &Handle,
+    blobs: &Blobs,
+    name: &str,
+    version: &str,
+) -> Result<(), anyhow::Error> {
+    // Configure a sandbox with 1GB of RAM and no network access
+    let sandbox = SandboxBuilder::new()
+        .memory_limit(Some(1024 * 1024 * 1024))
+        .enable_networking(false);
+
+    // Create a build directory for this build
+    let mut build_dir = workspace.build_dir(&format!("{}-{}", name, version));
+    build_dir.purge()?;
+
+    // The crate is read from the blob store rather than through the index, which private crates and registries that
+    // require auth do not serve anonymously
+    let data = runtime
+        .block_on(blobs.get(&blob::crate_key(name, version)))?
+        .ok_or_else(|| anyhow!("crate file is missing"))?;
+    let sources = Path::new(SOURCES_DIR).join(format!("{name}-{version}"));
+    unpack_crate(&data, &sources)?;
+    let krate = Crate::local(&sources.join(format!("{name}-{version}")));
+
+    debug!("building crate docs");
+    let built = build_dir.build(toolchain, &krate, sandbox).run(|build| {
+        // Build docs
+        build
+            .cargo()
+            .args(&[
+                "doc",
+                "--offline",
+                "--no-deps",
+                "-Zsparse-registry",
+                "-Zrustdoc-map",
+                //r#"--config=doc.extern-map.registries.this_registry="http://localhost:1479/docs/""#,
+            ])
+            .run()?;
+
+        // Upload docs to the blob store
+        let source_dir = build.host_target_dir().join("doc");
+        for (path, data) in read_dir_all(&source_dir, &source_dir)? {
+            let key = blob::docs_key(name, version, &path);
+            runtime.block_on(blobs.put(&key, data.into()))?;
         }
+
+        Ok(())
     });
+
+    // Clean up
+    build_dir.purge()?;
+    fs::remove_dir_all(&sources)?;
+    built
+}
+
+/// Unpack a crate file into an empty directory `dir`, which it creates as `<name>-<version>/`.
+fn unpack_crate(data: &[u8], dir: &Path) -> Result<(), anyhow::Error> {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    tar::Archive::new(GzDecoder::new(data))
+        .unpack(dir)
+        .with_context(|| format!("could not unpack crate file into {}", dir.display()))
 }
 
-fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
-    let dst = dst.as_ref();
-    fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let filename = entry.file_name();
-        if entry.file_type()?.is_dir() {
-            copy_dir_all(entry.path(), dst.join(filename))?;
+/// Read every file under `dir`, with its `/` separated path relative to `root`.
+fn read_dir_all(root: &Path, dir: &Path) -> Result<Vec<(String, Vec<u8>)>, anyhow::Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("could not read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(read_dir_all(root, &path)?);
         } else {
-            fs::copy(entry.path(), dst.join(filename))?;
+            let relative = path
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            files.push((relative, fs::read(&path)?));
         }
     }
-    Ok(())
+    Ok(files)
 }
@@ -0,0 +1,446 @@
+//! Consistency checks between the index and the blob store.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    blob::{self, Blobs},
+    db::{Db, UnreadableRecord},
+    mirror::{self, UpstreamConfig},
+};
+
+/// A way in which the index and the blob store disagree.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The `.crate` file of an indexed version of a local crate does not exist
+    MissingCrate {
+        name: String,
+        version: String,
+        is_local: bool,
+    },
+    /// The `.crate` file of an indexed version does not match the checksum in the index
+    ChecksumMismatch {
+        name: String,
+        version: String,
+        is_local: bool,
+    },
+    InvalidVersion {
+        name: String,
+        version: String,
+    },
+    /// A version that the index lists before another version that it does not precede, such as two versions that only
+    /// differ in build metadata
+    MisorderedVersions {
+        name: String,
+        before: String,
+        after: String,
+    },
+    /// A blob under `crates/` that is not the file of any indexed version
+    OrphanedBlob {
+        key: String,
+    },
+    /// Docs for a version that is not in the index
+    OrphanedDocs {
+        name: String,
+        version: String,
+    },
+    /// A record in the metadata store that cannot be decoded, which the rest of the registry ignores
+    UnreadableRecord(UnreadableRecord),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = |is_local: &bool| if *is_local { "local" } else { "upstream" };
+        match self {
+            Problem::MissingCrate {
+                name,
+                version,
+                is_local,
+            } => write!(f, "{} crate {name}@{version} is missing", origin(is_local)),
+            Problem::ChecksumMismatch {
+                name,
+                version,
+                is_local,
+            } => write!(
+                f,
+                "{} crate {name}@{version} does not match its checksum",
+                origin(is_local)
+            ),
+            Problem::InvalidVersion { name, version } => {
+                write!(f, "{name} has invalid version {version}")
+            }
+            Problem::MisorderedVersions {
+                name,
+                before,
+                after,
+            } => write!(
+                f,
+                "{name} lists {before} before {after}, which it does not precede"
+            ),
+            Problem::OrphanedBlob { key } => write!(f, "{key} has no index entry"),
+            Problem::OrphanedDocs { name, version } => {
+                write!(f, "docs for {name}@{version} have no index entry")
+            }
+            Problem::UnreadableRecord(record) => write!(f, "{record} cannot be decoded"),
+        }
+    }
+}
+
+/// The result of checking a registry.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of versions checked
+    pub versions: usize,
+    /// Problems that were found and not repaired
+    pub problems: Vec<Problem>,
+    pub repaired: Vec<Problem>,
+}
+
+/// Check that every record in the metadata store can be decoded, that every version in the index is valid semver and
+/// strictly follows the version listed before it, that every version has an intact `.crate` file, where upstream crate
+/// files only need to be intact if they are cached, and that every crate file and set of docs in the blob store belongs
+/// to a version in the index. Versions are checked in the order that the index lists them in, which the stores sort
+/// them into when they are read.
+///
+/// With `repair`, orphaned blobs and docs are moved under `quarantine/` in the blob store, and broken upstream crate
+/// files are downloaded again from `upstreams`, which is empty if the registry is offline. Local crate files cannot be
+/// recovered, so problems with them are only reported, as are the files of crates with unreadable records, which would
+/// otherwise look orphaned.
+pub async fn check(
+    db: &Db,
+    blobs: &Blobs,
+    repair: bool,
+    upstreams: &[UpstreamConfig],
+) -> Result<FsckReport, anyhow::Error> {
+    let mut report = FsckReport::default();
+    let quarantine = format!("quarantine/{}", Utc::now().format("%Y%m%d%H%M%S"));
+
+    // Unreadable records are skipped when iterating over the store, so they are looked for separately
+    let unreadable = db.unreadable_records()?;
+    let unreadable_crates: HashSet<String> = unreadable
+        .iter()
+        .filter_map(|record| match record.kind {
+            "crate-header" => Some(record.key.clone()),
+            "crate-version" => record.key.split_once('@').map(|(name, _)| name.to_owned()),
+            _ => None,
+        })
+        .collect();
+    report
+        .problems
+        .extend(unreadable.into_iter().map(Problem::UnreadableRecord));
+
+    let mut indexed: HashMap<String, HashSet<String>> = HashMap::new();
+    for (name, entry) in db.iter_crates() {
+        let parsed: Vec<_> = entry
+            .versions
+            .iter()
+            .filter_map(|uploaded| {
+                Some((&uploaded.pkg.vers, Version::parse(&uploaded.pkg.vers).ok()?))
+            })
+            .collect();
+        for pair in parsed.windows(2) {
+            let ((before, first), (after, second)) = (&pair[0], &pair[1]);
+            if first.cmp_precedence(second) != Ordering::Less {
+                report.problems.push(Problem::MisorderedVersions {
+                    name: name.clone(),
+                    before: (*before).clone(),
+                    after: (*after).clone(),
+                });
+            }
+        }
+
+        for uploaded in &entry.versions {
+            let version = &uploaded.pkg.vers;
+            report.versions += 1;
+            indexed
+                .entry(name.clone())
+                .or_default()
+                .insert(version.clone());
+            if Version::parse(version).is_err() {
+                report.problems.push(Problem::InvalidVersion {
+                    name: name.clone(),
+                    version: version.clone(),
+                });
+            }
+
+            let key = blob::crate_key(&name, version);
+            let problem = match blobs.get(&key).await? {
+                // Upstream crate files are only cached, and may have been evicted
+                None if !entry.is_local => continue,
+                None => Problem::MissingCrate {
+                    name: name.clone(),
+                    version: version.clone(),
+                    is_local: entry.is_local,
+                },
+                Some(data) if format!("{:x}", Sha256::digest(&data)) != uploaded.pkg.cksum => {
+                    Problem::ChecksumMismatch {
+                        name: name.clone(),
+                        version: version.clone(),
+                        is_local: entry.is_local,
+                    }
+                }
+                Some(_) => continue,
+            };
+
+            let upstream = mirror::upstream_for(upstreams, &name, entry.upstream.as_deref());
+            if let (true, false, Some(upstream)) = (repair, entry.is_local, upstream) {
+                match redownload(blobs, upstream, &name, version, &uploaded.pkg.cksum).await {
+                    Ok(()) => {
+                        report.repaired.push(problem);
+                        continue;
+                    }
+                    Err(e) => warn!("could not download {name}@{version} again: {e:#}"),
+                }
+            }
+            report.problems.push(problem);
+        }
+    }
+    let is_indexed = |name: &str, version: &str| {
+        indexed
+            .get(name)
+            .is_some_and(|versions| versions.contains(version))
+    };
+
+    for key in blobs.list("crates/").await? {
+        let version = key
+            .strip_prefix("crates/")
+            .and_then(|path| path.strip_suffix(".crate"))
+            .and_then(|path| path.split_once('/'));
+        if matches!(version, Some((name, version)) if is_indexed(name, version)) {
+            continue;
+        }
+
+        let problem = Problem::OrphanedBlob { key: key.clone() };
+        let crate_name = version.map(|(name, _)| name);
+        if repair && !crate_name.is_some_and(|name| unreadable_crates.contains(name)) {
+            move_blob(blobs, &key, &format!("{quarantine}/{key}")).await?;
+            report.repaired.push(problem);
+        } else {
+            report.problems.push(problem);
+        }
+    }
+
+    // Docs are grouped by version, so that each set of docs is reported once
+    let mut orphaned_docs: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for key in blobs.list("docs/").await? {
+        let mut parts = key.splitn(4, '/').skip(1);
+        let (Some(name), Some(version)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        if !is_indexed(name, version) {
+            orphaned_docs
+                .entry((name.to_owned(), version.to_owned()))
+                .or_default()
+                .push(key.clone());
+        }
+    }
+    for ((name, version), keys) in orphaned_docs {
+        let readable = !unreadable_crates.contains(&name);
+        let problem = Problem::OrphanedDocs { name, version };
+        if repair && readable {
+            for key in keys {
+                move_blob(blobs, &key, &format!("{quarantine}/{key}")).await?;
+            }
+            report.repaired.push(problem);
+        } else {
+            report.problems.push(problem);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Replace the `.crate` file of an upstream version with a fresh copy, which must match the checksum in the index.
+async fn redownload(
+    blobs: &Blobs,
+    upstream: &UpstreamConfig,
+    name: &str,
+    version: &str,
+    cksum: &str,
+) -> Result<(), anyhow::Error> {
+    let data = mirror::download_crate(upstream, name, version, Some(cksum))
+        .await?
+        .ok_or_else(|| anyhow!("no longer available upstream"))?;
+    blobs.put(&blob::crate_key(name, version), data).await?;
+    info!("downloaded {name}@{version} again");
+    Ok(())
+}
+
+async fn move_blob(blobs: &Blobs, from: &str, to: &str) -> Result<(), anyhow::Error> {
+    if let Some(data) = blobs.get(from).await? {
+        blobs.put(to, data).await?;
+    }
+    blobs.delete(from).await?;
+    info!("quarantined {from}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::*;
+    use crate::{
+        blob::FsBlobStore,
+        db::StoreKind,
+        test_util::{self, TempDir},
+        visibility::Visibility,
+        Entry,
+    };
+
+    fn entry(versions: &[(&str, &str)], is_local: bool) -> Entry {
+        let versions = versions
+            .iter()
+            .map(|(vers, cksum)| test_util::package("foo", vers, cksum))
+            .collect();
+        Entry {
+            versions,
+            time_of_last_update: Utc::now(),
+            is_local,
+            owners: Vec::new(),
+            visibility: Visibility::Public,
+            upstream: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_and_repairs_problems() {
+        let dir = TempDir::new("fsck");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+
+        let cksum = format!("{:x}", Sha256::digest(b"contents"));
+        db.insert_crate(
+            "foo",
+            &entry(&[("1.1.0", &cksum), ("1.0.0", &cksum), ("x", "0")], true),
+        )
+        .unwrap();
+        db.insert_crate("bar", &entry(&[("2.0.0", &cksum)], false))
+            .unwrap();
+        let contents = Bytes::from_static(b"contents");
+        blobs
+            .put(&blob::crate_key("foo", "1.1.0"), contents.clone())
+            .await
+            .unwrap();
+        blobs
+            .put(
+                &blob::crate_key("foo", "1.0.0"),
+                Bytes::from_static(b"other"),
+            )
+            .await
+            .unwrap();
+        blobs
+            .put(&blob::crate_key("baz", "1.0.0"), contents.clone())
+            .await
+            .unwrap();
+        blobs
+            .put(&blob::docs_key("foo", "0.1.0", "foo/index.html"), contents)
+            .await
+            .unwrap();
+
+        let report = check(&db, &blobs, false, &[]).await.unwrap();
+        assert_eq!(report.versions, 4);
+        assert_eq!(report.problems.len(), 5);
+        assert!(report.problems.contains(&Problem::InvalidVersion {
+            name: "foo".to_owned(),
+            version: "x".to_owned(),
+        }));
+        assert!(report.problems.contains(&Problem::ChecksumMismatch {
+            name: "foo".to_owned(),
+            version: "1.0.0".to_owned(),
+            is_local: true,
+        }));
+
+        // Offline, only the orphans can be repaired
+        let report = check(&db, &blobs, true, &[]).await.unwrap();
+        assert_eq!(
+            report.repaired,
+            [
+                Problem::OrphanedBlob {
+                    key: "crates/baz/1.0.0.crate".to_owned()
+                },
+                Problem::OrphanedDocs {
+                    name: "foo".to_owned(),
+                    version: "0.1.0".to_owned()
+                },
+            ]
+        );
+        assert!(blobs.list("docs/").await.unwrap().is_empty());
+        assert!(dir.join("quarantine").exists());
+        assert_eq!(
+            check(&db, &blobs, false, &[]).await.unwrap().problems.len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unreadable_records() {
+        let dir = TempDir::new("fsck-unreadable");
+        let db = Db::open(StoreKind::Sqlite, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+        db.insert_crate("foo", &entry(&[], true)).unwrap();
+        rusqlite::Connection::open(dir.join(StoreKind::Sqlite.file_name()))
+            .unwrap()
+            .execute(
+                "INSERT INTO crate_versions (crate_name, position, version, cksum, yanked, package)
+                    VALUES ('foo', 0, '1.0.0', '0', 0, '{')",
+                [],
+            )
+            .unwrap();
+        // The file of the unreadable version must not be quarantined as an orphan
+        let key = blob::crate_key("foo", "1.0.0");
+        blobs
+            .put(&key, Bytes::from_static(b"contents"))
+            .await
+            .unwrap();
+
+        let report = check(&db, &blobs, true, &[]).await.unwrap();
+        assert_eq!(report.versions, 0);
+        assert_eq!(
+            report.problems,
+            [
+                Problem::UnreadableRecord(UnreadableRecord {
+                    kind: "crate-version",
+                    key: "foo@1.0.0".to_owned(),
+                }),
+                Problem::OrphanedBlob { key: key.clone() },
+            ]
+        );
+        assert!(report.repaired.is_empty());
+        assert!(blobs.exists(&key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_misordered_versions() {
+        let dir = TempDir::new("fsck-order");
+        let db = Db::open(StoreKind::Sqlite, &dir).unwrap();
+        let blobs = Blobs::from_store(FsBlobStore::new(dir.to_path_buf()));
+        db.insert_crate(
+            "foo",
+            &entry(
+                &[("1.0.0", "0"), ("1.0.0+build", "0"), ("0.9.0", "0")],
+                false,
+            ),
+        )
+        .unwrap();
+
+        let report = check(&db, &blobs, false, &[]).await.unwrap();
+        assert_eq!(
+            report.problems,
+            [Problem::MisorderedVersions {
+                name: "foo".to_owned(),
+                before: "1.0.0".to_owned(),
+                after: "1.0.0+build".to_owned(),
+            }]
+        );
+    }
+}
@@ -1,20 +1,118 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
 use anyhow::Context;
 use axum::{
     extract::{Path, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
-use reqwest::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{header, StatusCode};
 use serde_json::{json, Value};
-use tracing::info;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
 
 use crate::{
     config::Config,
     mirror,
-    package::{Package, UploadedPackage},
-    AppState, Entry, InternalError,
+    package::UploadedPackage,
+    visibility::{self, IndexReader, Visibility},
+    AppState, CrateHeader, Db, InternalError,
 };
 
+#[derive(Default)]
+struct UpstreamState {
+    /// Crates whose index files are being fetched in the background
+    refreshing: Mutex<HashSet<String>>,
+    /// Crates that did not exist upstream, and when they were looked up
+    missing: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+/// Background refreshes of cached upstream index files, and crates that were recently found not to exist upstream.
+#[derive(Clone, Default)]
+pub struct UpstreamIndex(Arc<UpstreamState>);
+
+impl UpstreamIndex {
+    /// Whether a crate was found not to exist upstream within the last `ttl`.
+    fn is_missing(&self, crate_name: &str, ttl: Duration) -> bool {
+        let mut missing = self.0.missing.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = Utc::now() - ttl;
+        missing.retain(|_, checked_at| *checked_at > oldest);
+        missing.contains_key(crate_name)
+    }
+
+    fn mark_missing(&self, crate_name: &str) {
+        self.0
+            .missing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(crate_name.to_owned(), Utc::now());
+    }
+
+    /// Fetch a crate's index file from upstream in the background, unless it is already being fetched.
+    fn start_refresh(&self, db: Db, config: Config, crate_name: &str) {
+        let mut refreshing = self.0.refreshing.lock().unwrap_or_else(|e| e.into_inner());
+        if !refreshing.insert(crate_name.to_owned()) {
+            return;
+        }
+        drop(refreshing);
+
+        let upstream = self.clone();
+        let crate_name = crate_name.to_owned();
+        tokio::spawn(async move {
+            match upstream.refresh(&db, &config, &crate_name).await {
+                Ok(_) => info!(crate = crate_name, "refreshed index file from upstream"),
+                Err(e) => warn!("could not refresh index file of {crate_name}: {e:?}"),
+            }
+            upstream
+                .0
+                .refreshing
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&crate_name);
+        });
+    }
+
+    /// Fetch a crate's index file from upstream and cache it, returning its new header, or `None` if the crate does not
+    /// exist upstream.
+    async fn refresh(
+        &self,
+        db: &Db,
+        config: &Config,
+        crate_name: &str,
+    ) -> Result<Option<CrateHeader>, anyhow::Error> {
+        info!(crate = crate_name, "pulling crate metadata from upstream");
+        let Some(entry) = mirror::fetch_entry(&config.upstreams, crate_name).await? else {
+            // Crates are only cached while they exist upstream
+            self.mark_missing(crate_name);
+            if db
+                .get_crate_header(crate_name)?
+                .is_some_and(|entry| !entry.is_local)
+            {
+                db.remove_crate(crate_name)?;
+            }
+            return Ok(None);
+        };
+
+        // A crate may have been published locally while upstream was being queried
+        if db
+            .get_crate_header(crate_name)?
+            .is_some_and(|existing| existing.is_local)
+        {
+            return Ok(None);
+        }
+
+        // Insert binary representation into database
+        db.insert_crate(crate_name, &entry)?;
+        Ok(Some(entry.header()))
+    }
+}
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/config.json", get(index_config))
@@ -23,80 +121,225 @@ pub fn router() -> Router<AppState> {
         .route("/:a/:b/:crate_name", get(crate_metadata))
 }
 
-async fn index_config(State(config): State<Config>) -> Json<Value> {
-    Json(json!({ "dl": config.external_url.clone() + "/crates", "api": config.external_url }))
+async fn index_config(_reader: IndexReader, State(config): State<Config>) -> Json<Value> {
+    Json(json!({
+        "dl": config
+            .dl_template
+            .clone()
+            .unwrap_or_else(|| config.external_url.clone() + "/crates"),
+        "api": config.external_url,
+        "auth-required": config.auth_required,
+    }))
 }
 
 async fn crate_metadata(
+    reader: IndexReader,
     Path(parts): Path<Vec<String>>,
-    State(db): State<crate::Db>,
+    headers: HeaderMap,
+    State(db): State<Db>,
     State(config): State<Config>,
-) -> Result<(StatusCode, String), InternalError> {
+    State(upstream): State<UpstreamIndex>,
+) -> Result<Response, InternalError> {
     let crate_name = parts.last().expect("invalid route to crate_metadata");
     info!(crate = crate_name, "pulling crate metadata");
+    let ttl = config.upstream_index_ttl();
+    let max_stale = config.upstream_index_max_stale();
+
+    if let Some(entry) = db.get_crate_header(crate_name)? {
+        // Hidden crates must look the same as ones that don't exist, and must not be looked up upstream
+        if !visibility::can_read(&db, &config, &entry, reader.username())? {
+            return Ok((StatusCode::NOT_FOUND, "not found").into_response());
+        }
 
-    if let Some(entry) = db.get_crate(crate_name)? {
-        let has_expired =
-            chrono::Utc::now() - entry.time_of_last_update > chrono::Duration::minutes(30);
-        if config.offline || entry.is_local || !has_expired {
+        let age = Utc::now() - entry.time_of_last_update;
+        if config.offline || entry.is_local || age <= ttl {
             info!(crate = crate_name, "returning metadata from cache");
-            return entry
-                .versions
-                .into_iter()
-                .map(|version| serde_json::to_string(&version.pkg))
-                .try_fold(String::new(), |mut acc, item| {
-                    acc.push_str(
-                        &item.with_context(|| "could not convert version metadata to json")?,
-                    );
-                    acc.push('\n');
-                    Ok(acc)
-                })
-                .map(|metadata| (StatusCode::OK, metadata));
+        } else if age <= ttl + max_stale {
+            // Serve the stale file straight away, and have the next request see the refreshed one
+            info!(
+                crate = crate_name,
+                "crate in cache is stale, refreshing in the background"
+            );
+            upstream.start_refresh(db.clone(), config.clone(), crate_name);
         } else {
-            // Expired crate
             info!(crate = crate_name, "crate in cache has expired");
-            db.remove_crate(crate_name)?;
+            match upstream.refresh(&db, &config, crate_name).await {
+                Ok(Some(entry)) => {
+                    let versions = db.get_crate_versions(crate_name)?;
+                    return index_file(&headers, &config, &entry, &versions);
+                }
+                Ok(None) => return local_index_file(&db, &config, &reader, &headers, crate_name),
+                // Stale data is better than none while upstream is unreachable
+                Err(e) => warn!("serving expired index file of {crate_name}: {e:?}"),
+            }
         }
+
+        let versions = db.get_crate_versions(crate_name)?;
+        return index_file(&headers, &config, &entry, &versions);
     };
 
-    if config.offline {
-        return Ok((StatusCode::NOT_FOUND, "not found".to_owned()));
+    let negative_ttl = config.upstream_negative_ttl();
+    if config.offline || upstream.is_missing(crate_name, negative_ttl) {
+        return Ok((StatusCode::NOT_FOUND, "not found").into_response());
+    }
+
+    match upstream.refresh(&db, &config, crate_name).await? {
+        // Serve the file as it will be served from the cache, so that its ETag does not change when it is next
+        // requested
+        Some(entry) => {
+            let versions = db.get_crate_versions(crate_name)?;
+            index_file(&headers, &config, &entry, &versions)
+        }
+        None => local_index_file(&db, &config, &reader, &headers, crate_name),
     }
+}
 
-    info!(crate = crate_name, "pulling crate metadata from upstream");
-    let upstream = mirror::get_package(crate_name)
-        .await
-        .with_context(|| "could not get package from upstream")?;
+/// Respond with the index file of a crate that was not found upstream if it was published locally in the meantime, or
+/// with 404 Not Found.
+fn local_index_file(
+    db: &Db,
+    config: &Config,
+    reader: &IndexReader,
+    headers: &HeaderMap,
+    crate_name: &str,
+) -> Result<Response, InternalError> {
+    match db.get_crate_header(crate_name)? {
+        Some(entry)
+            if entry.is_local && visibility::can_read(db, config, &entry, reader.username())? =>
+        {
+            let versions = db.get_crate_versions(crate_name)?;
+            index_file(headers, config, &entry, &versions)
+        }
+        _ => Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+    }
+}
 
-    let upstream = match upstream {
-        Some(value) => value,
-        None => return Ok((StatusCode::NOT_FOUND, "not found".to_owned())),
-    };
+/// Respond with the index file of a crate, or with 304 Not Modified if the client's copy is still current.
+fn index_file(
+    headers: &HeaderMap,
+    config: &Config,
+    entry: &CrateHeader,
+    versions: &[UploadedPackage],
+) -> Result<Response, InternalError> {
+    let mut body = String::new();
+    for version in versions {
+        body.push_str(
+            &serde_json::to_string(&version.pkg)
+                .with_context(|| "could not convert version metadata to json")?,
+        );
+        body.push('\n');
+    }
+
+    // The ETag is a hash of the file itself, so it only changes when the file does
+    let etag = format!("\"{:x}\"", Sha256::digest(&body));
+    let last_modified = entry.time_of_last_update;
 
-    // Upstream JSON format to binary representation
-    let versions: Vec<UploadedPackage> = upstream
-        .lines()
-        // Deserialise each version from upstream
-        .map(serde_json::from_str)
-        // Add null upload timestamps
-        .map(|pkg: Result<Package, _>| {
-            pkg.map(|pkg| UploadedPackage {
-                pkg,
-                upload_meta: None,
-                upload_timestamp: None,
-            })
-        })
-        .collect::<Result<_, _>>()
-        .with_context(|| "could not parse upstream json metadata")?;
-
-    let entry = Entry {
-        versions,
-        time_of_last_update: chrono::Utc::now(),
-        is_local: false,
+    // Only the registry and the client may keep files that not everyone can read, and local files are always
+    // revalidated so that new versions are seen as soon as they are published
+    let scope = if config.auth_required || entry.visibility != Visibility::Public {
+        "private"
+    } else {
+        "public"
+    };
+    let cache_control = if entry.is_local || config.offline {
+        format!("{scope}, no-cache")
+    } else {
+        let ttl = config.upstream_index_ttl();
+        let remaining = (last_modified + ttl - Utc::now()).num_seconds().max(0);
+        format!("{scope}, max-age={remaining}")
     };
 
-    // Insert binary representation into database
-    db.insert_crate(crate_name, &entry)?;
+    let headers_out = [
+        (header::ETAG, etag.clone()),
+        (header::LAST_MODIFIED, http_date(last_modified)),
+        (header::CACHE_CONTROL, cache_control),
+    ];
+    if is_not_modified(headers, &etag, last_modified) {
+        Ok((StatusCode::NOT_MODIFIED, headers_out).into_response())
+    } else {
+        Ok((StatusCode::OK, headers_out, body).into_response())
+    }
+}
+
+/// Whether the conditional headers of a request show that the client already has the current file.
+///
+/// `If-None-Match` takes precedence, and `If-Modified-Since` is only used when it is absent.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        });
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        // HTTP dates only have whole seconds
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp())
+}
+
+/// Format a timestamp as an HTTP date.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
 
-    Ok((StatusCode::OK, upstream))
+    use super::*;
+
+    #[test]
+    fn conditional_requests() {
+        let etag = "\"abc\"";
+        let modified = DateTime::parse_from_rfc3339("2022-10-01T12:00:00.5Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let request = |name, value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert!(!is_not_modified(&HeaderMap::new(), etag, modified));
+        assert!(is_not_modified(
+            &request(header::IF_NONE_MATCH, "\"xyz\", W/\"abc\""),
+            etag,
+            modified
+        ));
+        assert!(!is_not_modified(
+            &request(header::IF_NONE_MATCH, "\"xyz\""),
+            etag,
+            modified
+        ));
+
+        let since = http_date(modified);
+        assert_eq!(since, "Sat, 01 Oct 2022 12:00:00 GMT");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MODIFIED_SINCE, since.parse().unwrap());
+        assert!(is_not_modified(&headers, etag, modified));
+        assert!(!is_not_modified(
+            &headers,
+            etag,
+            modified + Duration::seconds(1)
+        ));
+        // A stale ETag wins over a current date
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+        assert!(!is_not_modified(&headers, etag, modified));
+    }
+
+    #[test]
+    fn missing_crates_expire() {
+        let upstream = UpstreamIndex::default();
+        upstream.mark_missing("foo");
+        assert!(upstream.is_missing("foo", Duration::minutes(5)));
+        assert!(!upstream.is_missing("bar", Duration::minutes(5)));
+        assert!(!upstream.is_missing("foo", Duration::zero()));
+        // Expired entries are forgotten
+        assert!(upstream.0.missing.lock().unwrap().is_empty());
+    }
 }
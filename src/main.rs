@@ -1,28 +1,38 @@
 mod api;
+mod audit;
 mod auth;
+mod backup;
+mod blob;
+mod cache;
 mod config;
 mod db;
 mod dl;
 mod docs;
+mod fsck;
 mod index;
 mod mirror;
+mod owner;
 mod package;
+mod paseto;
+mod service_account;
+mod team;
+#[cfg(test)]
+mod test_util;
 mod token;
 mod ui;
+mod vendor;
+mod visibility;
 
 use axum_extra::extract::cookie;
 use axum_server::{tls_rustls::RustlsConfig, HttpConfig};
 use db::Db;
 
-use std::{
-    fs,
-    net::SocketAddr,
-    path::{Path, PathBuf},
-};
+use std::{fs, net::SocketAddr, path::Path};
 
 use anyhow::Context;
 use axum::{extract::FromRef, http::StatusCode, response::IntoResponse, Router};
 use config::Config;
+use owner::CrateOwner;
 use package::UploadedPackage;
 use serde::{Deserialize, Serialize};
 use tera::Tera;
@@ -32,12 +42,55 @@ use tower_http::{
     trace::{DefaultMakeSpan, TraceLayer},
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use visibility::Visibility;
 
 #[derive(Serialize, Deserialize)]
 pub struct Entry {
     versions: Vec<UploadedPackage>,
     time_of_last_update: chrono::DateTime<chrono::Utc>,
     is_local: bool,
+    /// Owners of a local crate, which is empty for upstream crates
+    owners: Vec<CrateOwner>,
+    /// Who can see the crate, which is always public for upstream crates
+    visibility: Visibility,
+    /// Name of the upstream registry that an upstream crate was cached from, which is unknown for crates cached before
+    /// there could be several
+    #[serde(default)]
+    upstream: Option<String>,
+}
+
+/// The parts of a crate's [`Entry`] other than its versions, which are stored separately.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrateHeader {
+    time_of_last_update: chrono::DateTime<chrono::Utc>,
+    is_local: bool,
+    owners: Vec<CrateOwner>,
+    visibility: Visibility,
+    #[serde(default)]
+    upstream: Option<String>,
+}
+
+impl Entry {
+    pub fn from_parts(header: CrateHeader, versions: Vec<UploadedPackage>) -> Self {
+        Entry {
+            versions,
+            time_of_last_update: header.time_of_last_update,
+            is_local: header.is_local,
+            owners: header.owners,
+            visibility: header.visibility,
+            upstream: header.upstream,
+        }
+    }
+
+    pub fn header(&self) -> CrateHeader {
+        CrateHeader {
+            time_of_last_update: self.time_of_last_update,
+            is_local: self.is_local,
+            owners: self.owners.clone(),
+            visibility: self.visibility.clone(),
+            upstream: self.upstream.clone(),
+        }
+    }
 }
 
 struct InternalError(anyhow::Error);
@@ -64,8 +117,12 @@ pub struct AppState {
     cookie_key: cookie::Key,
     config: Config,
     db: db::Db,
+    blobs: blob::Blobs,
+    cache_stats: cache::CacheStats,
     templates: Tera,
     docs_queue_tx: UnboundedSender<(String, String)>,
+    replay_cache: paseto::ReplayCache,
+    upstream_index: index::UpstreamIndex,
 }
 
 #[tokio::main]
@@ -84,16 +141,30 @@ async fn main() -> Result<(), anyhow::Error> {
     if !config.data_dir.exists() {
         fs::create_dir(&config.data_dir).with_context(|| "unable to create data dir")?;
     }
-    let crates_dir = config.data_dir.join("crates");
-    if !crates_dir.exists() {
-        fs::create_dir(&crates_dir).with_context(|| "unable to create crate cache dir")?;
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {}
+        Some("migrate") => return migrate(&config, args.any(|arg| arg == "--dry-run")),
+        Some("backup") => return backup(&config, args.next()).await,
+        Some("restore") => return restore(&config, args.next()).await,
+        Some("migrate-store") => return migrate_store(&config, args.next(), args.next()),
+        Some("fsck") => return fsck(&config, args.any(|arg| arg == "--repair")).await,
+        Some("mirror") => return mirror(&config, args.collect()).await,
+        Some("vendor") => return vendor(&config, args.next(), args.next()).await,
+        Some(command) => anyhow::bail!("unknown command {command}"),
     }
 
-    let db = db::Db::open(config.data_dir.join("db"))?;
+    let db = db::Db::open(config.metadata_store, &config.data_dir)?;
+    let blobs = blob::Blobs::open(&config)?;
+    audit::start_retention_task(db.clone(), config.audit_retention_days);
+    let cache_stats = cache::CacheStats::default();
+    cache::start_eviction_task(db.clone(), blobs.clone(), cache_stats.clone(), &config);
+    backup::start_backup_listener(db.clone(), blobs.clone(), config.data_dir.clone())?;
 
     // Docs generator thread
     let (docs_queue_tx, docs_queue_rx) = mpsc::unbounded_channel();
-    docs::start_background_thread(config.data_dir.clone(), docs_queue_rx);
+    docs::start_background_thread(blobs.clone(), docs_queue_rx);
 
     let tera =
         Tera::new("templates/**.html").with_context(|| "unable to load templates".to_owned())?;
@@ -104,9 +175,13 @@ async fn main() -> Result<(), anyhow::Error> {
         .unwrap();
 
     let app = Router::new()
-        .merge(ui::router(&config.data_dir))
+        .merge(ui::router())
         .merge(dl::router())
         .merge(auth::router())
+        .merge(service_account::router())
+        .merge(team::router())
+        .merge(audit::router())
+        .merge(cache::router())
         .nest("/index", index::router())
         .nest("/api", api::router())
         .nest_service(
@@ -123,9 +198,13 @@ async fn main() -> Result<(), anyhow::Error> {
         .with_state(AppState {
             config,
             db,
+            blobs,
+            cache_stats,
             templates: tera,
             docs_queue_tx,
             cookie_key: cookie::Key::generate(),
+            replay_cache: paseto::ReplayCache::default(),
+            upstream_index: index::UpstreamIndex::default(),
         })
         .layer(
             TraceLayer::new_for_http()
@@ -136,16 +215,207 @@ async fn main() -> Result<(), anyhow::Error> {
 
     axum_server::bind_rustls(listen_addr, tls_config)
         .http_config(http_config)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
 }
 
-fn crate_path(data_dir: impl AsRef<Path>, name: &str, version: &str) -> PathBuf {
-    data_dir
-        .as_ref()
-        .join("crates")
-        .join(name)
-        .join(version.to_owned() + ".crate")
+/// Migrate the database to the current version, or with `dry_run`, report the migrations that would be run.
+fn migrate(config: &Config, dry_run: bool) -> Result<(), anyhow::Error> {
+    if dry_run {
+        let reports = db::Db::dry_run_migrations(config.metadata_store, &config.data_dir)?;
+        if reports.is_empty() {
+            println!("database is up to date");
+        }
+        for report in reports {
+            println!("{report}");
+        }
+    } else {
+        // Opening the database runs any outstanding migrations
+        db::Db::open(config.metadata_store, &config.data_dir)?;
+    }
+    Ok(())
+}
+
+/// Copy all metadata from the store of kind `from` into a new store of kind `to`.
+fn migrate_store(
+    config: &Config,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let (Some(from), Some(to)) = (from, to) else {
+        anyhow::bail!("usage: altreg migrate-store <sled|sqlite> <sled|sqlite>");
+    };
+    let (from, to): (db::StoreKind, db::StoreKind) = (from.parse()?, to.parse()?);
+    if from == to {
+        anyhow::bail!("cannot migrate the {from} store into itself");
+    }
+    let to_path = config.data_dir.join(to.file_name());
+    if to_path.exists() {
+        anyhow::bail!("{} already exists", to_path.display());
+    }
+
+    let report = db::copy_store(
+        &db::Db::open(from, &config.data_dir)?,
+        &db::Db::open(to, &config.data_dir)?,
+    )?;
+    println!("copied {report} from the {from} store to the {to} store");
+    for record in &report.unreadable {
+        println!("skipped unreadable {record}");
+    }
+    println!("set metadata_store = \"{to}\" in config.toml to use it");
+    Ok(())
+}
+
+/// Write a backup of the registry to `output`, through the server if it is running.
+async fn backup(config: &Config, output: Option<String>) -> Result<(), anyhow::Error> {
+    let output = output.ok_or_else(|| anyhow::anyhow!("usage: altreg backup <file>"))?;
+    // The server may be running in a different directory
+    let output = std::env::current_dir()?.join(output);
+
+    let files = match backup::request_backup(&config.data_dir, &output)? {
+        Some(files) => files,
+        None => {
+            let db = db::Db::open(config.metadata_store, &config.data_dir)?;
+            let blobs = blob::Blobs::open(config)?;
+            backup::create(&db, &blobs, &config.data_dir, &output)
+                .await?
+                .files
+                .len()
+        }
+    };
+    println!("backed up {files} files to {}", output.display());
+    Ok(())
+}
+
+/// Rebuild the data directory from the backup `archive`.
+async fn restore(config: &Config, archive: Option<String>) -> Result<(), anyhow::Error> {
+    let archive = archive.ok_or_else(|| anyhow::anyhow!("usage: altreg restore <file>"))?;
+    let blobs = blob::Blobs::open(config)?;
+    let report = backup::restore(
+        Path::new(&archive),
+        &blobs,
+        &config.data_dir,
+        config.metadata_store,
+    )
+    .await?;
+    for missing in &report.missing {
+        println!("no crate file for {missing}");
+    }
+    println!(
+        "restored {} files to {}, {} crate files verified",
+        report.files,
+        config.data_dir.display(),
+        report.crates
+    );
+    Ok(())
+}
+
+/// Check that the index and the blob store agree, and with `repair`, fix what can be fixed.
+async fn fsck(config: &Config, repair: bool) -> Result<(), anyhow::Error> {
+    let db = db::Db::open(config.metadata_store, &config.data_dir)?;
+    let blobs = blob::Blobs::open(config)?;
+    let upstreams = if config.offline {
+        &[]
+    } else {
+        config.upstreams.as_slice()
+    };
+    let report = fsck::check(&db, &blobs, repair, upstreams).await?;
+
+    for problem in &report.repaired {
+        println!("repaired: {problem}");
+    }
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    println!(
+        "checked {} versions, {} problems repaired, {} remaining",
+        report.versions,
+        report.repaired.len(),
+        report.problems.len()
+    );
+    if !report.problems.is_empty() {
+        anyhow::bail!("registry failed integrity check");
+    }
+    Ok(())
+}
+
+/// Fetch the crates described by `args` from upstream into the cache, so that they can be served offline.
+async fn mirror(config: &Config, args: Vec<String>) -> Result<(), anyhow::Error> {
+    const USAGE: &str =
+        "usage: altreg mirror [--concurrency <n>] (--crates <file> | --lockfile <file> | --reachable <crate>...)";
+
+    let mut concurrency = 8;
+    let mut source = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--concurrency" => {
+                concurrency = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| anyhow::anyhow!(USAGE))?;
+            }
+            "--crates" => {
+                let path = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("unable to read {path}"))?;
+                source = Some(mirror::MirrorSource::from_crate_list(&contents));
+            }
+            "--lockfile" => {
+                let path = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("unable to read {path}"))?;
+                source = Some(mirror::MirrorSource::from_lockfile(&contents)?);
+            }
+            "--reachable" => {
+                source = Some(mirror::MirrorSource::Reachable(args.by_ref().collect()));
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    let source = source.ok_or_else(|| anyhow::anyhow!(USAGE))?;
+
+    let db = db::Db::open(config.metadata_store, &config.data_dir)?;
+    let blobs = blob::Blobs::open(config)?;
+    let stats = cache::CacheStats::default();
+    let report = mirror::populate(&db, &blobs, config, &stats, source, concurrency).await?;
+    println!("{report}");
+    if !report.failed.is_empty() {
+        anyhow::bail!("some crates could not be mirrored");
+    }
+    Ok(())
+}
+
+/// Write the crates of the `lockfile` to `output` as a tarball in the layout of `cargo vendor`.
+async fn vendor(
+    config: &Config,
+    lockfile: Option<String>,
+    output: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let (Some(lockfile), Some(output)) = (lockfile, output) else {
+        anyhow::bail!("usage: altreg vendor <Cargo.lock> <file>");
+    };
+    let contents =
+        fs::read_to_string(&lockfile).with_context(|| format!("unable to read {lockfile}"))?;
+    let packages = mirror::lockfile_packages(&contents)?;
+
+    let db = db::Db::open(config.metadata_store, &config.data_dir)?;
+    let blobs = blob::Blobs::open(config)?;
+    let stats = cache::CacheStats::default();
+    // Whoever can open the data directory can read every crate
+    let vendored = match vendor::vendor(&db, &blobs, config, &stats, packages, |_| Ok(true)).await? {
+        Ok(vendored) => vendored,
+        Err(vendor::VendorError::Unavailable(problems)) => {
+            for problem in problems {
+                println!("{problem}");
+            }
+            anyhow::bail!("some packages could not be vendored");
+        }
+        Err(vendor::VendorError::TooLarge(reason)) => anyhow::bail!(reason),
+    };
+    fs::write(&output, &vendored.archive).with_context(|| format!("unable to write {output}"))?;
+    println!("vendored {} packages to {output}", vendored.packages);
+    Ok(())
 }
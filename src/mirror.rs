@@ -1,51 +1,318 @@
-use anyhow::anyhow;
+mod populate;
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
 use axum::body::Bytes;
-use reqwest::StatusCode;
+use chrono::Utc;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    package::{Package, UploadedPackage},
+    visibility::Visibility,
+    Entry,
+};
+
+pub use populate::{lockfile_packages, populate, LockedPackage, MirrorReport, MirrorSource};
 
 static CRATES_IO_INDEX: &str = "https://index.crates.io";
-static CRATES_IO_INDEX_DL: &str = "https://crates.io/api/v1/crates";
 
-pub async fn get_package(name: &str) -> Result<Option<String>, anyhow::Error> {
-    tracing::info!("checking {name} in crates.io index");
+/// How long the `config.json` of an upstream is used for before it is fetched again
+const REGISTRY_CONFIG_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The `config.json` of each upstream index, keyed by the URL of the index, and when it was fetched
+static REGISTRY_CONFIGS: OnceLock<Mutex<HashMap<String, (Instant, RegistryConfig)>>> =
+    OnceLock::new();
+
+/// The parts of a registry's `config.json` that are needed to fetch crates from it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistryConfig {
+    /// Template of the URLs that crate files are downloaded from
+    pub dl: String,
+}
+
+/// A sparse registry that crates which are not published locally are fetched from.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfig {
+    /// Name of the upstream, recorded with the crates that are cached from it
+    pub name: String,
+    /// URL of the sparse index, without the `sparse+` prefix
+    pub index: String,
+    /// Template of the URLs that crate files are downloaded from, in place of the `dl` template of the upstream's
+    /// `config.json`
+    #[serde(default)]
+    pub dl: Option<String>,
+    /// Token sent in the `Authorization` header of every request, such as a token of another altreg instance
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Patterns of the crate names fetched from this upstream, where `*` matches any characters, or every name if empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Patterns of crate names that are never fetched from this upstream, even if they are allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl UpstreamConfig {
+    pub fn crates_io() -> Self {
+        UpstreamConfig {
+            name: "crates-io".to_owned(),
+            index: CRATES_IO_INDEX.to_owned(),
+            dl: None,
+            token: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// Whether crates with this name may be fetched from this upstream.
+    pub fn serves(&self, crate_name: &str) -> bool {
+        (self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| matches_pattern(pattern, crate_name)))
+            && !self
+                .deny
+                .iter()
+                .any(|pattern| matches_pattern(pattern, crate_name))
+    }
+
+    async fn get(&self, url: String) -> Result<Option<reqwest::Response>, anyhow::Error> {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, token);
+        }
+
+        request
+            .send()
+            .await?
+            .error_for_status()
+            .map(Some)
+            .or_else(|e| match e.status() {
+                Some(StatusCode::NOT_FOUND) => Ok(None),
+                Some(_) => Err(e.into()),
+                None => Err(anyhow!("unable to decode status code")),
+            })
+    }
+}
+
+/// Match a crate name against a pattern where `*` matches any number of characters, ignoring case like Cargo does.
+fn matches_pattern(pattern: &str, crate_name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let crate_name = crate_name.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = crate_name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, so the whole name must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The upstream that a crate is fetched from: the one it was cached from if it is known, or otherwise the first one
+/// that serves its name.
+pub fn upstream_for<'a>(
+    upstreams: &'a [UpstreamConfig],
+    crate_name: &str,
+    cached_from: Option<&str>,
+) -> Option<&'a UpstreamConfig> {
+    match cached_from {
+        Some(cached_from) => upstreams
+            .iter()
+            .find(|upstream| upstream.name == cached_from && upstream.serves(crate_name)),
+        None => upstreams
+            .iter()
+            .find(|upstream| upstream.serves(crate_name)),
+    }
+}
+
+/// Look up a crate in each upstream that serves its name in order, returning the name of the first upstream that has
+/// it together with its index file.
+///
+/// An upstream that cannot be reached is an error rather than being skipped, so that a crate is never fetched from a
+/// later upstream in place of the one that it belongs to.
+pub async fn get_package(
+    upstreams: &[UpstreamConfig],
+    name: &str,
+) -> Result<Option<(String, String)>, anyhow::Error> {
     let prefix = crate_prefix(name);
-    let url = format!("{}/{}/{}", CRATES_IO_INDEX, prefix, name);
-
-    let response = reqwest::get(url)
-        .await?
-        .error_for_status()
-        .map(Some)
-        .or_else(|e| match e.status() {
-            Some(StatusCode::NOT_FOUND) => Ok(None),
-            Some(_) => Err(e.into()),
-            None => Err(anyhow!("unable to decode status code")),
-        })?;
-
-    if let Some(response) = response {
-        Ok(Some(response.text().await?))
-    } else {
-        Ok(None)
+    for upstream in upstreams.iter().filter(|upstream| upstream.serves(name)) {
+        tracing::info!("checking {name} in {} index", upstream.name);
+        let url = format!(
+            "{}/{}/{}",
+            upstream.index.trim_end_matches('/'),
+            prefix,
+            name
+        );
+        if let Some(response) = upstream.get(url).await? {
+            return Ok(Some((upstream.name.clone(), response.text().await?)));
+        }
+    }
+    Ok(None)
+}
+
+/// Get the `config.json` of an upstream, which is fetched at most once an hour while it can be reached.
+pub async fn registry_config(upstream: &UpstreamConfig) -> Result<RegistryConfig, anyhow::Error> {
+    let configs = REGISTRY_CONFIGS.get_or_init(Default::default);
+    let cached = configs
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&upstream.index)
+        .cloned();
+    if let Some((fetched_at, config)) = &cached {
+        if fetched_at.elapsed() < REGISTRY_CONFIG_TTL {
+            return Ok(config.clone());
+        }
+    }
+
+    tracing::info!("fetching config.json of {}", upstream.name);
+    let url = format!("{}/config.json", upstream.index.trim_end_matches('/'));
+    let fetched = match upstream.get(url).await {
+        Ok(Some(response)) => response
+            .json::<RegistryConfig>()
+            .await
+            .with_context(|| format!("could not decode config.json of {}", upstream.name)),
+        Ok(None) => Err(anyhow!("{} has no config.json", upstream.name)),
+        Err(e) => Err(e),
+    };
+    match (fetched, cached) {
+        (Ok(config), _) => {
+            configs
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(upstream.index.clone(), (Instant::now(), config.clone()));
+            Ok(config)
+        }
+        // An outdated config is still the best guess of where crates are while the upstream is unreachable
+        (Err(e), Some((_, config))) => {
+            tracing::warn!("using outdated config.json of {}: {e:?}", upstream.name);
+            Ok(config)
+        }
+        (Err(e), None) => Err(e),
     }
 }
 
-pub async fn download_crate(name: &str, version: &str) -> Result<Option<Bytes>, anyhow::Error> {
-    tracing::info!("downloading {name}@{version} from crates.io");
-    let url = format!("{}/{}/{}/download", CRATES_IO_INDEX_DL, name, version);
-
-    let response = reqwest::get(url)
-        .await?
-        .error_for_status()
-        .map(Some)
-        .or_else(|e| match e.status() {
-            Some(StatusCode::NOT_FOUND) => Ok(None),
-            Some(_) => Err(e.into()),
-            None => Err(anyhow!("unable to decode status code")),
-        })?;
-
-    if let Some(response) = response {
-        Ok(Some(response.bytes().await?))
-    } else {
-        Ok(None)
+/// Expand the markers of a `dl` template into the URL of a crate file, as Cargo does.
+///
+/// A template without any markers has `/{crate}/{version}/download` appended to it. The checksum is only needed if the
+/// template contains `{sha256-checksum}`.
+pub fn expand_dl_template(
+    template: &str,
+    name: &str,
+    version: &str,
+    cksum: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    const MARKERS: [&str; 5] = [
+        "{crate}",
+        "{version}",
+        "{prefix}",
+        "{lowerprefix}",
+        "{sha256-checksum}",
+    ];
+    if !MARKERS.iter().any(|marker| template.contains(marker)) {
+        return Ok(format!(
+            "{}/{name}/{version}/download",
+            template.trim_end_matches('/')
+        ));
+    }
+
+    let mut url = template
+        .replace("{crate}", name)
+        .replace("{version}", version)
+        .replace("{prefix}", &crate_prefix(name))
+        .replace("{lowerprefix}", &crate_prefix(&name.to_lowercase()));
+    if url.contains("{sha256-checksum}") {
+        let cksum =
+            cksum.ok_or_else(|| anyhow!("the checksum of {name}@{version} is not known"))?;
+        url = url.replace("{sha256-checksum}", cksum);
     }
+    Ok(url)
+}
+
+/// Fetch a crate's index file from the first upstream that has it, as an entry to cache, or `None` if no upstream has it.
+pub async fn fetch_entry(
+    upstreams: &[UpstreamConfig],
+    name: &str,
+) -> Result<Option<Entry>, anyhow::Error> {
+    let Some((upstream_name, index_file)) = get_package(upstreams, name)
+        .await
+        .with_context(|| "could not get package from upstream")?
+    else {
+        return Ok(None);
+    };
+
+    // Upstream JSON format to binary representation
+    let versions: Vec<UploadedPackage> = index_file
+        .lines()
+        // Deserialise each version from upstream
+        .map(serde_json::from_str)
+        // Add null upload timestamps
+        .map(|pkg: Result<Package, _>| {
+            pkg.map(|pkg| UploadedPackage {
+                pkg,
+                upload_meta: None,
+                upload_timestamp: None,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .with_context(|| "could not parse upstream json metadata")?;
+
+    Ok(Some(Entry {
+        versions,
+        time_of_last_update: Utc::now(),
+        is_local: false,
+        owners: Vec::new(),
+        visibility: Visibility::Public,
+        upstream: Some(upstream_name),
+    }))
+}
+
+/// Download a crate file from an upstream, where `cksum` is the checksum of the version in the upstream's index if it
+/// is known. Files that do not match a known checksum are rejected, so that they are never cached.
+pub async fn download_crate(
+    upstream: &UpstreamConfig,
+    name: &str,
+    version: &str,
+    cksum: Option<&str>,
+) -> Result<Option<Bytes>, anyhow::Error> {
+    tracing::info!("downloading {name}@{version} from {}", upstream.name);
+    let template = match &upstream.dl {
+        Some(dl) => dl.clone(),
+        None => registry_config(upstream).await?.dl,
+    };
+    let url = expand_dl_template(&template, name, version, cksum)?;
+
+    let Some(response) = upstream.get(url).await? else {
+        return Ok(None);
+    };
+    let data = response.bytes().await?;
+    if let Some(cksum) = cksum {
+        if format!("{:x}", Sha256::digest(&data)) != cksum {
+            return Err(anyhow!(
+                "{name}@{version} from {} does not match its checksum",
+                upstream.name
+            ));
+        }
+    }
+    Ok(Some(data))
 }
 
 fn crate_prefix(name: &str) -> String {
@@ -71,4 +338,49 @@ mod tests {
         assert_eq!(crate_prefix("abc"), "3/a");
         assert_eq!(crate_prefix("cargo"), "ca/rg");
     }
+
+    #[test]
+    fn name_patterns() {
+        assert!(matches_pattern("serde", "serde"));
+        assert!(!matches_pattern("serde", "serde_json"));
+        assert!(matches_pattern("serde*", "serde_json"));
+        assert!(matches_pattern("*-sys", "openssl-sys"));
+        assert!(matches_pattern("acme-*-internal", "Acme-Billing-Internal"));
+        assert!(!matches_pattern("acme-*-internal", "acme-internal"));
+        assert!(matches_pattern("*", "anything"));
+
+        let upstream = UpstreamConfig {
+            allow: vec!["acme-*".to_owned()],
+            deny: vec!["acme-public-*".to_owned()],
+            ..UpstreamConfig::crates_io()
+        };
+        assert!(upstream.serves("acme-billing"));
+        assert!(!upstream.serves("acme-public-api"));
+        assert!(!upstream.serves("serde"));
+        assert!(UpstreamConfig::crates_io().serves("serde"));
+    }
+
+    #[test]
+    fn dl_templates() {
+        let expand = |template| expand_dl_template(template, "Serde", "1.0.0", Some("abc"));
+        assert_eq!(
+            expand("https://crates.io/api/v1/crates/").unwrap(),
+            "https://crates.io/api/v1/crates/Serde/1.0.0/download"
+        );
+        assert_eq!(
+            expand("https://static.crates.io/crates/{crate}/{crate}-{version}.crate").unwrap(),
+            "https://static.crates.io/crates/Serde/Serde-1.0.0.crate"
+        );
+        assert_eq!(
+            expand("https://cdn.example.com/{prefix}/{lowerprefix}/{sha256-checksum}").unwrap(),
+            "https://cdn.example.com/Se/rd/se/rd/abc"
+        );
+        assert!(expand_dl_template(
+            "https://cdn.example.com/{sha256-checksum}",
+            "a",
+            "1.0.0",
+            None
+        )
+        .is_err());
+    }
 }
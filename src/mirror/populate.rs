@@ -0,0 +1,549 @@
+//! Populating the cache with whole sets of upstream crates, so that a registry can be used offline.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use super::{download_crate, fetch_entry, upstream_for, UpstreamConfig, CRATES_IO_INDEX};
+use crate::{
+    blob::{self, Blobs},
+    cache::CacheStats,
+    config::Config,
+    db::Db,
+    package::{DependencyKind, UploadedPackage},
+};
+
+/// Source of crates.io packages in a `Cargo.lock`
+const CRATES_IO_GIT_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// The crates to populate the cache with.
+pub enum MirrorSource {
+    /// Crates with a version to fetch, or every version if it is `None`
+    Crates(Vec<(String, Option<String>)>),
+    /// The registry packages of a `Cargo.lock`, which must come from the upstream that their source refers to and match
+    /// their checksum
+    Lockfile(Vec<LockedPackage>),
+    /// Every version of the roots, and every version of their dependencies that any of those versions could use
+    Reachable(Vec<String>),
+}
+
+impl MirrorSource {
+    /// Read a list of crates with one crate per line, as `name` for every version or `name@version` for one version.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn from_crate_list(contents: &str) -> Self {
+        let crates = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once('@') {
+                Some((name, version)) => (name.to_owned(), Some(version.to_owned())),
+                None => (line.to_owned(), None),
+            })
+            .collect();
+        MirrorSource::Crates(crates)
+    }
+
+    /// Every package in a `Cargo.lock` that comes from a registry.
+    pub fn from_lockfile(contents: &str) -> Result<Self, anyhow::Error> {
+        Ok(MirrorSource::Lockfile(lockfile_packages(contents)?))
+    }
+}
+
+/// A package in a `Cargo.lock`.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    /// Where the package comes from, which is `None` for packages in the workspace
+    pub source: Option<String>,
+    /// Checksum of the crate file, which is only known for packages from a registry
+    pub checksum: Option<String>,
+}
+
+/// Every package in a `Cargo.lock` that comes from a registry, rather than from a path or a git repository. Fails if any
+/// of them does not have a valid crate name and version.
+pub fn lockfile_packages(contents: &str) -> Result<Vec<LockedPackage>, anyhow::Error> {
+    #[derive(Deserialize)]
+    struct Lockfile {
+        #[serde(default)]
+        package: Vec<LockedPackage>,
+    }
+
+    let lockfile: Lockfile =
+        toml::from_str(contents).with_context(|| "could not parse Cargo.lock")?;
+    let packages: Vec<_> = lockfile
+        .package
+        .into_iter()
+        .filter(|package| {
+            package.source.as_deref().is_some_and(|source| {
+                source.starts_with("registry+") || source.starts_with("sparse+")
+            })
+        })
+        .collect();
+    for package in &packages {
+        let valid_name = !package.name.is_empty()
+            && package
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name || Version::parse(&package.version).is_err() {
+            return Err(anyhow!(
+                "invalid package {}@{} in Cargo.lock",
+                package.name,
+                package.version
+            ));
+        }
+    }
+    Ok(packages)
+}
+
+/// The configured upstream that a `Cargo.lock` source refers to, where crates.io's git index is the same registry as its
+/// sparse index.
+fn upstream_for_source<'a>(
+    upstreams: &'a [UpstreamConfig],
+    source: &str,
+) -> Option<&'a UpstreamConfig> {
+    let index = match source.strip_prefix("sparse+") {
+        Some(index) => index,
+        None if source == CRATES_IO_GIT_SOURCE => CRATES_IO_INDEX,
+        None => return None,
+    };
+    upstreams
+        .iter()
+        .find(|upstream| upstream.index.trim_end_matches('/') == index.trim_end_matches('/'))
+}
+
+/// What happened to each crate version while populating the cache.
+#[derive(Debug, Default, Serialize)]
+pub struct MirrorReport {
+    /// Number of index files fetched from upstream
+    pub index_files: usize,
+    /// Versions whose crate files were already cached, or are published locally
+    pub already_cached: Vec<String>,
+    /// Versions whose crate files were downloaded
+    pub fetched: Vec<String>,
+    /// Crates and versions that no upstream has
+    pub missing: Vec<String>,
+    /// Crates and versions that could not be fetched, with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+impl fmt::Display for MirrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for missing in &self.missing {
+            writeln!(f, "missing upstream: {missing}")?;
+        }
+        for (name, reason) in &self.failed {
+            writeln!(f, "failed: {name}: {reason}")?;
+        }
+        write!(
+            f,
+            "{} index files fetched, {} crate files fetched, {} already cached, {} missing, {} failed",
+            self.index_files,
+            self.fetched.len(),
+            self.already_cached.len(),
+            self.missing.len(),
+            self.failed.len()
+        )
+    }
+}
+
+/// A version whose crate file should be cached.
+struct Wanted {
+    name: String,
+    version: UploadedPackage,
+    is_local: bool,
+    upstream: Option<String>,
+}
+
+/// Fetch the index files and crate files of `source` from upstream into the cache, with at most `concurrency` requests
+/// to upstream at once.
+///
+/// Crate files that are already cached are skipped, and index files are only fetched again once they have expired, so an
+/// interrupted run can be resumed by running it again.
+pub async fn populate(
+    db: &Db,
+    blobs: &Blobs,
+    config: &Config,
+    stats: &CacheStats,
+    source: MirrorSource,
+    concurrency: usize,
+) -> Result<MirrorReport, anyhow::Error> {
+    let mut report = MirrorReport::default();
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    // Upstream and checksum that each version requested by a lockfile must have
+    let mut locked: HashMap<(String, String), (String, Option<String>)> = HashMap::new();
+
+    // Requirements on each crate, where `None` means every version
+    let (mut pending, follow_dependencies): (Vec<(String, Option<VersionReq>)>, _) = match source {
+        MirrorSource::Crates(crates) => {
+            let mut pending = Vec::new();
+            for (name, version) in crates {
+                let req = match version {
+                    Some(version) => match exact_req(&mut report, &name, &version) {
+                        Some(req) => Some(req),
+                        None => continue,
+                    },
+                    None => None,
+                };
+                pending.push((name, req));
+            }
+            (pending, false)
+        }
+        MirrorSource::Lockfile(packages) => {
+            let mut pending = Vec::new();
+            for package in packages {
+                let (name, version) = (package.name, package.version);
+                let source = package.source.unwrap_or_default();
+                let Some(upstream) = upstream_for_source(&config.upstreams, &source) else {
+                    report.failed.push((
+                        format!("{name}@{version}"),
+                        format!("{source} is not a configured upstream"),
+                    ));
+                    continue;
+                };
+                let Some(req) = exact_req(&mut report, &name, &version) else {
+                    continue;
+                };
+                locked.insert(
+                    (name.clone(), version),
+                    (upstream.name.clone(), package.checksum),
+                );
+                pending.push((name, Some(req)));
+            }
+            (pending, false)
+        }
+        MirrorSource::Reachable(roots) => {
+            (roots.into_iter().map(|root| (root, None)).collect(), true)
+        }
+    };
+
+    let mut wanted: HashMap<(String, String), Wanted> = HashMap::new();
+    let mut seen_requirements = HashSet::new();
+    // Crates that were missing or failed in an earlier round, which are not looked up again
+    let mut unavailable = HashSet::new();
+    while !pending.is_empty() {
+        pending.retain(|(name, _)| !unavailable.contains(name));
+        // Fetch the index files of a whole round of crates at once
+        let names: BTreeSet<_> = pending.iter().map(|(name, _)| name.clone()).collect();
+        let mut tasks = Vec::new();
+        for name in names {
+            let (db, config, semaphore) = (db.clone(), config.clone(), semaphore.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                let fetched = cached_entry(&db, &config, &name).await;
+                Ok::<_, anyhow::Error>((name, fetched))
+            }));
+        }
+        let mut entries = HashMap::new();
+        for task in tasks {
+            let (name, fetched) = task.await??;
+            match fetched {
+                Ok(Some((entry, was_fetched))) => {
+                    report.index_files += usize::from(was_fetched);
+                    entries.insert(name, entry);
+                }
+                Ok(None) => {
+                    unavailable.insert(name.clone());
+                    report.missing.push(name);
+                }
+                Err(e) => {
+                    unavailable.insert(name.clone());
+                    report.failed.push((name, format!("{e:#}")));
+                }
+            }
+        }
+
+        let mut next = Vec::new();
+        for (name, req) in pending.drain(..) {
+            let Some(entry) = entries.get(&name) else {
+                continue;
+            };
+            let matching: Vec<_> = entry
+                .versions
+                .iter()
+                .filter(|version| match (&req, Version::parse(&version.pkg.vers)) {
+                    (None, _) => true,
+                    (Some(req), Ok(parsed)) => req.matches(&parsed),
+                    (Some(_), Err(_)) => false,
+                })
+                // Dependencies never resolve to yanked versions, but a requested version may have been yanked
+                .filter(|version| !follow_dependencies || req.is_none() || !version.pkg.yanked)
+                .collect();
+            if matching.is_empty() {
+                report.missing.push(match req {
+                    Some(req) => format!("{name} {req}"),
+                    None => name,
+                });
+                continue;
+            }
+
+            for version in matching {
+                let key = (name.clone(), version.pkg.vers.clone());
+                if wanted.contains_key(&key) {
+                    continue;
+                }
+                if let Some((upstream, checksum)) = locked.get(&key) {
+                    let served_by =
+                        match upstream_for(&config.upstreams, &name, entry.upstream.as_deref()) {
+                            _ if entry.is_local => "this registry",
+                            Some(served_by) => served_by.name.as_str(),
+                            None => "an unconfigured upstream",
+                        };
+                    let problem = if served_by != upstream {
+                        Some(format!(
+                            "Cargo.lock expects it from {upstream}, but it comes from {served_by}"
+                        ))
+                    } else if checksum
+                        .as_ref()
+                        .is_some_and(|checksum| *checksum != version.pkg.cksum)
+                    {
+                        Some(format!(
+                            "checksum in Cargo.lock does not match the index of {upstream}"
+                        ))
+                    } else {
+                        None
+                    };
+                    if let Some(problem) = problem {
+                        report
+                            .failed
+                            .push((format!("{name}@{}", version.pkg.vers), problem));
+                        continue;
+                    }
+                }
+                if follow_dependencies {
+                    for dep in &version.pkg.deps {
+                        if matches!(dep.kind, DependencyKind::Dev) {
+                            continue;
+                        }
+                        let dep_name = dep.package.clone().unwrap_or_else(|| dep.name.clone());
+                        let Ok(dep_req) = VersionReq::parse(&dep.req) else {
+                            warn!(
+                                "skipping dependency {dep_name} {} of {name}@{}",
+                                dep.req, version.pkg.vers
+                            );
+                            continue;
+                        };
+                        if seen_requirements.insert((dep_name.clone(), dep_req.to_string())) {
+                            next.push((dep_name, Some(dep_req)));
+                        }
+                    }
+                }
+                wanted.insert(
+                    key,
+                    Wanted {
+                        name: name.clone(),
+                        version: version.clone(),
+                        is_local: entry.is_local,
+                        upstream: entry.upstream.clone(),
+                    },
+                );
+            }
+        }
+        pending = next;
+    }
+
+    // Download every crate file that is not cached yet
+    let mut tasks = Vec::new();
+    for (_, wanted) in wanted {
+        let label = format!("{}@{}", wanted.name, wanted.version.pkg.vers);
+        let key = blob::crate_key(&wanted.name, &wanted.version.pkg.vers);
+        if wanted.is_local || blobs.exists(&key).await? {
+            report.already_cached.push(label);
+            continue;
+        }
+
+        let (db, blobs, config, stats, semaphore) = (
+            db.clone(),
+            blobs.clone(),
+            config.clone(),
+            stats.clone(),
+            semaphore.clone(),
+        );
+        tasks.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let result = fetch_crate(&db, &blobs, &config.upstreams, &stats, &wanted, &key).await;
+            Ok::<_, anyhow::Error>((label, result))
+        }));
+    }
+    for task in tasks {
+        match task.await?? {
+            (label, Ok(true)) => report.fetched.push(label),
+            (label, Ok(false)) => report.missing.push(label),
+            (label, Err(e)) => report.failed.push((label, format!("{e:#}"))),
+        }
+    }
+
+    report.already_cached.sort();
+    report.fetched.sort();
+    report.missing.sort();
+    report.failed.sort();
+    Ok(report)
+}
+
+/// A requirement on exactly `version` of a crate, or `None` after reporting that it is not a version.
+fn exact_req(report: &mut MirrorReport, name: &str, version: &str) -> Option<VersionReq> {
+    match VersionReq::parse(&format!("={version}")) {
+        Ok(req) => Some(req),
+        Err(e) => {
+            report
+                .failed
+                .push((format!("{name}@{version}"), e.to_string()));
+            None
+        }
+    }
+}
+
+/// A crate's cached index entry, fetching it from upstream first unless it is local or has not expired yet, together
+/// with whether it was fetched.
+async fn cached_entry(
+    db: &Db,
+    config: &Config,
+    name: &str,
+) -> Result<Option<(crate::Entry, bool)>, anyhow::Error> {
+    let ttl = config.upstream_index_ttl();
+    if let Some(header) = db.get_crate_header(name)? {
+        if header.is_local || Utc::now() - header.time_of_last_update <= ttl {
+            return Ok(db.get_crate(name)?.map(|entry| (entry, false)));
+        }
+    }
+
+    info!(crate = name, "mirroring index file");
+    let Some(entry) = fetch_entry(&config.upstreams, name).await? else {
+        return Ok(None);
+    };
+    db.insert_crate(name, &entry)?;
+    Ok(Some((entry, true)))
+}
+
+/// Download a crate file into the blob store, checking it against the index, or return `false` if upstream does not
+/// have it.
+async fn fetch_crate(
+    db: &Db,
+    blobs: &Blobs,
+    upstreams: &[UpstreamConfig],
+    stats: &CacheStats,
+    wanted: &Wanted,
+    key: &str,
+) -> Result<bool, anyhow::Error> {
+    let upstream = upstream_for(upstreams, &wanted.name, wanted.upstream.as_deref())
+        .ok_or_else(|| anyhow!("no upstream serves {}", wanted.name))?;
+    let pkg = &wanted.version.pkg;
+    let Some(data) = download_crate(upstream, &wanted.name, &pkg.vers, Some(&pkg.cksum)).await?
+    else {
+        return Ok(false);
+    };
+
+    let size = data.len() as u64;
+    blobs.put(key, data).await?;
+    stats.record_miss(db, key, size)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sources() {
+        let lockfile = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.152"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "bb7d1f0d3021d347a83e556fc4683dea2ea09d87bccdf88ff5c12545d89d5efb"
+
+[[package]]
+name = "internal"
+version = "2.0.0"
+source = "sparse+https://altreg.example.com/"
+
+[[package]]
+name = "patched"
+version = "0.3.0"
+source = "git+https://github.com/example/patched#abc"
+"#;
+        let packages: Vec<_> = lockfile_packages(lockfile)
+            .unwrap()
+            .into_iter()
+            .map(|package| (package.name, package.version, package.checksum))
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                (
+                    "serde".to_owned(),
+                    "1.0.152".to_owned(),
+                    Some(
+                        "bb7d1f0d3021d347a83e556fc4683dea2ea09d87bccdf88ff5c12545d89d5efb"
+                            .to_owned()
+                    )
+                ),
+                ("internal".to_owned(), "2.0.0".to_owned(), None)
+            ]
+        );
+
+        // Only sources with a configured upstream can be warmed
+        let mut upstreams = vec![UpstreamConfig::crates_io()];
+        let upstream_names = |upstreams: &[UpstreamConfig]| {
+            lockfile_packages(lockfile)
+                .unwrap()
+                .into_iter()
+                .map(|package| {
+                    upstream_for_source(upstreams, &package.source.unwrap())
+                        .map(|upstream| upstream.name.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            upstream_names(&upstreams),
+            [Some("crates-io".to_owned()), None]
+        );
+        upstreams.push(UpstreamConfig {
+            name: "internal".to_owned(),
+            index: "https://altreg.example.com".to_owned(),
+            ..UpstreamConfig::crates_io()
+        });
+        assert_eq!(
+            upstream_names(&upstreams),
+            [Some("crates-io".to_owned()), Some("internal".to_owned())]
+        );
+
+        for invalid in [("../x", "1.0.0"), ("x", "../1.0.0")] {
+            let lockfile = format!(
+                "[[package]]\nname = \"{}\"\nversion = \"{}\"\nsource = \"{CRATES_IO_GIT_SOURCE}\"\n",
+                invalid.0, invalid.1
+            );
+            assert!(lockfile_packages(&lockfile).is_err());
+        }
+
+        let MirrorSource::Crates(crates) =
+            MirrorSource::from_crate_list("# tools\nserde\n\ntokio@1.22.0\n")
+        else {
+            panic!("crate lists are lists of crates");
+        };
+        assert_eq!(
+            crates,
+            [
+                ("serde".to_owned(), None),
+                ("tokio".to_owned(), Some("1.22.0".to_owned()))
+            ]
+        );
+    }
+}
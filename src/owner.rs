@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, team};
+
+/// Prefix used by `cargo owner` to refer to a team rather than a user
+static TEAM_PREFIX: &str = "team:";
+
+/// An owner of a local crate, who may publish new versions and yank existing ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrateOwner {
+    User(String),
+    /// Every member of the team is an owner
+    Team(String),
+}
+
+impl CrateOwner {
+    /// Parse an owner as given to `cargo owner`, where teams are written as `team:<name>`.
+    pub fn parse(login: &str) -> Self {
+        match login.strip_prefix(TEAM_PREFIX) {
+            Some(team) => CrateOwner::Team(team.to_owned()),
+            None => CrateOwner::User(login.to_owned()),
+        }
+    }
+
+    /// The owner as it would be given to `cargo owner`.
+    pub fn login(&self) -> String {
+        match self {
+            CrateOwner::User(username) => username.clone(),
+            CrateOwner::Team(team) => format!("{TEAM_PREFIX}{team}"),
+        }
+    }
+}
+
+/// An owner of a crate as displayed in owner lists.
+#[derive(Serialize)]
+pub struct OwnerView {
+    pub login: String,
+    pub name: String,
+    pub kind: &'static str,
+}
+
+/// Check whether `username` may act as an owner of a crate owned by `owners`.
+///
+/// Team ownership is resolved against the current membership of the team, so membership changes take effect
+/// immediately. Registry admins may act as the owner of any crate.
+pub fn is_owner(
+    db: &crate::Db,
+    config: &Config,
+    owners: &[CrateOwner],
+    username: &str,
+) -> Result<bool, anyhow::Error> {
+    if config.is_admin(username) {
+        return Ok(true);
+    }
+
+    for owner in owners {
+        let is_owner = match owner {
+            CrateOwner::User(owner) => owner == username,
+            CrateOwner::Team(team) => team::is_member(db, team, username)?,
+        };
+        if is_owner {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Check whether `username` may publish a new version of a crate owned by `owners`.
+///
+/// Crates published before owners were recorded have none, so anyone who could publish to them before can still do so.
+pub fn may_publish(
+    db: &crate::Db,
+    config: &Config,
+    owners: &[CrateOwner],
+    username: &str,
+) -> Result<bool, anyhow::Error> {
+    Ok(owners.is_empty() || is_owner(db, config, owners, username)?)
+}
+
+/// Make `username` the owner of a local crate that has no owners.
+///
+/// Crates published before owners were recorded have none, so only registry admins can manage them. Whoever next
+/// publishes a version of such a crate claims it, unless an owner has been added to it first.
+pub fn claim_unowned(owners: &mut Vec<CrateOwner>, username: &str) {
+    if owners.is_empty() {
+        owners.push(CrateOwner::User(username.to_owned()));
+    }
+}
+
+/// Remove `username` from the owners of every crate, so that an account created later with the same name does not
+/// inherit its crates. Crates that it was the only owner of are handed to `successor` rather than left without owners,
+/// which anyone could claim.
+pub fn remove_user(
+    db: &crate::Db,
+    username: &str,
+    successor: &CrateOwner,
+) -> Result<(), anyhow::Error> {
+    let owner = CrateOwner::User(username.to_owned());
+    let owned: Vec<_> = db
+        .iter_crate_headers()
+        .filter(|(_, header)| header.owners.contains(&owner))
+        .map(|(crate_name, _)| crate_name)
+        .collect();
+    for crate_name in owned {
+        // Retry until the crate is not changed by another request between reading and writing it
+        while let Some(expected) = db.get_crate_header(&crate_name)? {
+            let mut header = expected.clone();
+            header.owners.retain(|existing| *existing != owner);
+            if header.owners.is_empty() {
+                header.owners.push(successor.clone());
+            }
+            if header == expected || db.replace_crate_header(&crate_name, &expected, &header)? {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Describe the owners of a crate, labelling teams and service accounts.
+pub fn describe(db: &crate::Db, owners: &[CrateOwner]) -> Result<Vec<OwnerView>, anyhow::Error> {
+    owners
+        .iter()
+        .map(|owner| {
+            Ok(match owner {
+                CrateOwner::User(username) => {
+                    let kind = db
+                        .get_user(username)?
+                        .map_or("user", |user| user.kind_label());
+                    OwnerView {
+                        login: owner.login(),
+                        name: username.clone(),
+                        kind,
+                    }
+                }
+                CrateOwner::Team(team) => OwnerView {
+                    login: owner.login(),
+                    name: team.clone(),
+                    kind: "team",
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        db::{Db, StoreKind},
+        team::{Team, TeamMember},
+        test_util::TempDir,
+        visibility::Visibility,
+        Entry,
+    };
+
+    #[test]
+    fn owner_login_round_trip() {
+        assert_eq!(
+            CrateOwner::parse("team:platform"),
+            CrateOwner::Team("platform".to_owned())
+        );
+        assert_eq!(
+            CrateOwner::parse("alice"),
+            CrateOwner::User("alice".to_owned())
+        );
+        assert_eq!(
+            CrateOwner::Team("platform".to_owned()).login(),
+            "team:platform"
+        );
+    }
+
+    #[test]
+    fn removed_users_own_nothing() {
+        let dir = TempDir::new("owner-remove");
+        let db = Db::open(StoreKind::Sled, &dir).unwrap();
+        for owners in [vec!["ci", "alice"], vec!["alice"], vec!["ci"]] {
+            let entry = Entry {
+                versions: Vec::new(),
+                time_of_last_update: Utc::now(),
+                is_local: true,
+                owners: owners
+                    .iter()
+                    .map(|owner| CrateOwner::User(owner.to_string()))
+                    .collect(),
+                visibility: Visibility::Public,
+                upstream: None,
+            };
+            db.insert_crate(&owners.join("-"), &entry).unwrap();
+        }
+        db.insert_team(&Team {
+            name: "platform".to_owned(),
+            members: vec![
+                TeamMember {
+                    username: "alice".to_owned(),
+                    maintainer: true,
+                },
+                TeamMember {
+                    username: "ci".to_owned(),
+                    maintainer: false,
+                },
+            ],
+        })
+        .unwrap();
+
+        remove_user(&db, "ci", &CrateOwner::User("alice".to_owned())).unwrap();
+        team::remove_member(&db, "ci").unwrap();
+        for (_, header) in db.iter_crate_headers() {
+            assert_eq!(header.owners, [CrateOwner::User("alice".to_owned())]);
+        }
+        let team = db.get_team("platform").unwrap().unwrap();
+        assert!(!team.is_member("ci"));
+        assert!(team.is_maintainer("alice"));
+    }
+}
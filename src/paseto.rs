@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Duration, Utc};
+use p384::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384};
+
+use crate::db;
+
+static TOKEN_HEADER: &str = "v3.public.";
+static PUBLIC_KEY_HEADER: &str = "k3.public.";
+static KEY_ID_HEADER: &str = "k3.pid.";
+/// Length of an ECDSA P-384 signature
+const SIGNATURE_LENGTH: usize = 96;
+/// Length of a compressed P-384 public key
+const PUBLIC_KEY_LENGTH: usize = 49;
+/// Maximum age of a token before it is no longer accepted
+const MAX_TOKEN_AGE: i64 = 5 * 60;
+/// Maximum amount that a token's issue time may be in the future, to allow for clock skew
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// A public key registered by a user, used to verify asymmetric tokens signed by cargo.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicKeyEntry {
+    username: String,
+    label: String,
+    /// PASERK `k3.public` encoding of the key
+    public_key: String,
+    /// PASERK `k3.pid` identifier of the key
+    key_id: String,
+    created_at: DateTime<Utc>,
+}
+
+impl PublicKeyEntry {
+    pub fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub fn label(&self) -> &str {
+        self.label.as_ref()
+    }
+
+    pub fn key_id(&self) -> &str {
+        self.key_id.as_ref()
+    }
+}
+
+/// The message of an asymmetric token, as defined by cargo.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    iat: String,
+    mutation: Option<String>,
+    name: Option<String>,
+    vers: Option<String>,
+    cksum: Option<String>,
+}
+
+impl Claims {
+    /// Whether this token was issued for a mutation, and so may only be used once.
+    pub fn is_mutation(&self) -> bool {
+        self.mutation.is_some()
+    }
+
+    /// Check that the token was issued for exactly this mutation.
+    pub fn allows_mutation(&self, mutation: &Mutation) -> bool {
+        let (kind, name, vers, cksum) = match mutation {
+            Mutation::Publish { name, vers, cksum } => ("publish", name, Some(vers), Some(cksum)),
+            Mutation::Yank { name, vers } => ("yank", name, Some(vers), None),
+            Mutation::Unyank { name, vers } => ("unyank", name, Some(vers), None),
+            Mutation::Owners { name } => ("owners", name, None, None),
+        };
+
+        self.mutation.as_deref() == Some(kind)
+            && self.name.as_deref() == Some(*name)
+            && self.vers.as_deref() == vers.copied()
+            && self.cksum.as_deref() == cksum.copied()
+    }
+}
+
+/// A registry operation that changes the state of a crate.
+pub enum Mutation<'a> {
+    Publish {
+        name: &'a str,
+        vers: &'a str,
+        cksum: &'a str,
+    },
+    Yank {
+        name: &'a str,
+        vers: &'a str,
+    },
+    Unyank {
+        name: &'a str,
+        vers: &'a str,
+    },
+    Owners {
+        name: &'a str,
+    },
+}
+
+#[derive(Deserialize)]
+struct Footer {
+    url: String,
+    kip: String,
+}
+
+/// Recently used mutation tokens, so that they cannot be replayed.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayCache(Arc<Mutex<HashMap<[u8; 32], DateTime<Utc>>>>);
+
+impl ReplayCache {
+    /// Record a token as used, returning false if it has already been used.
+    fn insert(&self, token: &str, issued_at: DateTime<Utc>) -> bool {
+        // A panic while holding the lock cannot leave the cache inconsistent, so it is still usable
+        let mut seen = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Tokens older than the maximum age will be rejected anyway, so they no longer need tracking
+        let oldest = Utc::now() - Duration::seconds(MAX_TOKEN_AGE);
+        seen.retain(|_, issued_at| *issued_at >= oldest);
+
+        seen.insert(Sha256::digest(token).into(), issued_at)
+            .is_none()
+    }
+}
+
+/// Check whether an authorization header contains an asymmetric token rather than a bearer token.
+pub fn is_asymmetric_token(token: &str) -> bool {
+    token.starts_with(TOKEN_HEADER)
+}
+
+/// Parse a PASERK `k3.public` public key, returning its key id.
+pub fn parse_public_key(public_key: &str) -> Result<String, anyhow::Error> {
+    let public_key = public_key.trim();
+    let raw = public_key
+        .strip_prefix(PUBLIC_KEY_HEADER)
+        .ok_or_else(|| anyhow!("public key must be a k3.public PASERK"))?;
+    let raw = base64::decode_config(raw, base64::URL_SAFE_NO_PAD)?;
+    if raw.len() != PUBLIC_KEY_LENGTH {
+        return Err(anyhow!("public key has incorrect length"));
+    }
+    VerifyingKey::from_sec1_bytes(&raw).map_err(|_| anyhow!("invalid public key"))?;
+
+    Ok(key_id(public_key))
+}
+
+/// Calculate the PASERK `k3.pid` identifier of a `k3.public` public key.
+fn key_id(public_key: &str) -> String {
+    let mut hasher = Sha384::new();
+    hasher.update(KEY_ID_HEADER);
+    hasher.update(public_key);
+    let digest = hasher.finalize();
+
+    format!(
+        "{KEY_ID_HEADER}{}",
+        base64::encode_config(&digest[..33], base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Register a new public key for the user.
+///
+/// Returns a warning to be displayed to the user if the key is invalid, the user already has a key with this label, or
+/// the key is already registered.
+pub fn add_public_key(
+    db: &db::Db,
+    username: &str,
+    label: &str,
+    public_key: &str,
+) -> Result<Result<(), String>, anyhow::Error> {
+    if db.has_user_public_key(username, label)? {
+        return Ok(Err("a key with this label already exists".to_owned()));
+    }
+
+    let key_id = match parse_public_key(public_key) {
+        Ok(key_id) => key_id,
+        Err(e) => return Ok(Err(format!("invalid public key: {e}"))),
+    };
+    let inserted = db.insert_public_key(&PublicKeyEntry {
+        username: username.to_owned(),
+        label: label.to_owned(),
+        public_key: public_key.trim().to_owned(),
+        key_id,
+        created_at: Utc::now(),
+    })?;
+    if !inserted {
+        return Ok(Err("this key is already registered".to_owned()));
+    }
+    Ok(Ok(()))
+}
+
+pub fn get_user_public_keys(
+    db: &db::Db,
+    username: &str,
+) -> Result<Vec<PublicKeyEntry>, anyhow::Error> {
+    db.get_user_public_keys(username)
+}
+
+pub fn delete(db: &db::Db, username: &str, label: &str) -> Result<(), anyhow::Error> {
+    db.delete_user_public_key(username, label)
+}
+
+/// Verify a `v3.public` PASETO signed by cargo.
+///
+/// The token must have been signed by a registered key, be intended for this registry's index at `index_url`, be
+/// recently issued, and if it is for a mutation, not have been used before.
+pub fn verify(
+    db: &db::Db,
+    replay_cache: &ReplayCache,
+    index_url: &str,
+    token: &str,
+) -> Result<(PublicKeyEntry, Claims), anyhow::Error> {
+    let body = token
+        .strip_prefix(TOKEN_HEADER)
+        .ok_or_else(|| anyhow!("not a v3.public token"))?;
+    let (payload, footer) = match body.split_once('.') {
+        Some((payload, footer)) => (payload, footer),
+        None => return Err(anyhow!("token is missing footer")),
+    };
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)?;
+    let footer = base64::decode_config(footer, base64::URL_SAFE_NO_PAD)?;
+    if payload.len() <= SIGNATURE_LENGTH {
+        return Err(anyhow!("token is too short"));
+    }
+    let (message, signature) = payload.split_at(payload.len() - SIGNATURE_LENGTH);
+
+    // Find the key that the token claims to be signed with
+    let footer_claims: Footer =
+        serde_json::from_slice(&footer).with_context(|| "invalid token footer")?;
+    if normalise_url(&footer_claims.url) != normalise_url(index_url) {
+        return Err(anyhow!("token was issued for a different registry"));
+    }
+    let entry = db
+        .get_public_key(&footer_claims.kip)?
+        .ok_or_else(|| anyhow!("token was signed by an unknown key"))?;
+
+    // Verify the signature over the pre-authentication encoding of the token
+    let raw_key = base64::decode_config(
+        entry.public_key.trim_start_matches(PUBLIC_KEY_HEADER),
+        base64::URL_SAFE_NO_PAD,
+    )?;
+    let verifying_key =
+        VerifyingKey::from_sec1_bytes(&raw_key).map_err(|_| anyhow!("invalid public key"))?;
+    let signature =
+        Signature::try_from(signature).map_err(|_| anyhow!("malformed token signature"))?;
+    let signed = pre_auth_encode(&[&raw_key, TOKEN_HEADER.as_bytes(), message, &footer, b""]);
+    verifying_key
+        .verify(&signed, &signature)
+        .map_err(|_| anyhow!("invalid token signature"))?;
+
+    // Check that the token is current
+    let claims: Claims = serde_json::from_slice(message).with_context(|| "invalid token claims")?;
+    let issued_at = DateTime::parse_from_rfc3339(&claims.iat)
+        .with_context(|| "invalid token issue time")?
+        .with_timezone(&Utc);
+    let age = Utc::now() - issued_at;
+    if age > Duration::seconds(MAX_TOKEN_AGE) {
+        return Err(anyhow!("token has expired"));
+    }
+    if age < -Duration::seconds(MAX_CLOCK_SKEW) {
+        return Err(anyhow!("token was issued in the future"));
+    }
+
+    if claims.is_mutation() && !replay_cache.insert(token, issued_at) {
+        return Err(anyhow!("token has already been used"));
+    }
+
+    Ok((entry, claims))
+}
+
+/// Normalise an index URL so that equivalent forms compare equal.
+fn normalise_url(url: &str) -> &str {
+    url.trim_start_matches("sparse+").trim_end_matches('/')
+}
+
+/// PASETO pre-authentication encoding.
+fn pre_auth_encode(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend_from_slice(&(pieces.len() as u64).to_le_bytes());
+    for piece in pieces {
+        output.extend_from_slice(&(piece.len() as u64).to_le_bytes());
+        output.extend_from_slice(piece);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::StoreKind, test_util::TempDir};
+
+    #[test]
+    fn pre_auth_encode_samples() {
+        // Test vectors from the PASETO specification
+        assert_eq!(pre_auth_encode(&[]), b"\x00\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(
+            pre_auth_encode(&[b""]),
+            b"\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"
+        );
+        assert_eq!(
+            pre_auth_encode(&[b"test"]),
+            b"\x01\x00\x00\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00test"
+        );
+    }
+
+    #[test]
+    fn public_key_parsing() {
+        let signing_key = p384::ecdsa::SigningKey::from_bytes(&[1u8; 48]).unwrap();
+        let raw = signing_key.verifying_key().to_encoded_point(true);
+        let public_key = format!(
+            "{PUBLIC_KEY_HEADER}{}",
+            base64::encode_config(raw.as_bytes(), base64::URL_SAFE_NO_PAD)
+        );
+
+        let key_id = parse_public_key(&public_key).unwrap();
+        assert!(key_id.starts_with(KEY_ID_HEADER));
+        assert!(parse_public_key("k4.public.AAAA").is_err());
+        assert!(parse_public_key("k3.public.AAAA").is_err());
+    }
+
+    #[test]
+    fn mutation_claims() {
+        let claims = Claims {
+            iat: Utc::now().to_rfc3339(),
+            mutation: Some("yank".to_owned()),
+            name: Some("foo".to_owned()),
+            vers: Some("1.0.0".to_owned()),
+            cksum: None,
+        };
+        assert!(claims.allows_mutation(&Mutation::Yank {
+            name: "foo",
+            vers: "1.0.0"
+        }));
+        assert!(!claims.allows_mutation(&Mutation::Unyank {
+            name: "foo",
+            vers: "1.0.0"
+        }));
+        assert!(!claims.allows_mutation(&Mutation::Yank {
+            name: "foo",
+            vers: "1.0.1"
+        }));
+    }
+
+    #[test]
+    fn replay_rejected() {
+        let cache = ReplayCache::default();
+        assert!(cache.insert("v3.public.token", Utc::now()));
+        assert!(!cache.insert("v3.public.token", Utc::now()));
+        assert!(cache.insert("v3.public.other", Utc::now()));
+    }
+
+    #[test]
+    fn public_keys_cannot_be_taken_over() {
+        let signing_key = p384::ecdsa::SigningKey::from_bytes(&[1u8; 48]).unwrap();
+        let raw = signing_key.verifying_key().to_encoded_point(true);
+        let public_key = format!(
+            "{PUBLIC_KEY_HEADER}{}",
+            base64::encode_config(raw.as_bytes(), base64::URL_SAFE_NO_PAD)
+        );
+        let key_id = parse_public_key(&public_key).unwrap();
+
+        for kind in StoreKind::ALL {
+            let dir = TempDir::new(&format!("public-keys-{kind}"));
+            let db = db::Db::open(kind, &dir).unwrap();
+
+            assert_eq!(
+                add_public_key(&db, "victim", "laptop", &public_key).unwrap(),
+                Ok(())
+            );
+            assert_eq!(
+                add_public_key(&db, "attacker", "laptop", &public_key).unwrap(),
+                Err("this key is already registered".to_owned())
+            );
+            assert_eq!(
+                db.get_public_key(&key_id).unwrap().unwrap().username(),
+                "victim"
+            );
+            assert!(get_user_public_keys(&db, "attacker").unwrap().is_empty());
+            assert_eq!(get_user_public_keys(&db, "victim").unwrap().len(), 1);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{self, AccountKind, AccountOwner, AuthSession, TokenCreateParams, TokenParams, User},
+    config::Config,
+    owner::{self, CrateOwner},
+    team, token, AppState, InternalError,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/service-accounts",
+            get(service_accounts_index).post(service_account_create),
+        )
+        .route("/service-accounts/:name", get(service_account_view))
+        .route(
+            "/service-accounts/:name/delete",
+            post(service_account_delete),
+        )
+        .route(
+            "/service-accounts/:name/tokens",
+            post(service_account_token_create),
+        )
+        .route(
+            "/service-accounts/:name/tokens/delete",
+            post(service_account_token_delete),
+        )
+}
+
+/// Check whether `username` may manage the service account `account`.
+fn can_manage(
+    db: &crate::Db,
+    config: &Config,
+    username: &str,
+    account: &User,
+) -> Result<bool, anyhow::Error> {
+    let AccountKind::Service { owner } = &account.kind else {
+        return Ok(false);
+    };
+
+    if config.is_admin(username) {
+        return Ok(true);
+    }
+    match owner {
+        AccountOwner::User(owner) => Ok(owner == username),
+        AccountOwner::Team(team) => team::is_maintainer(db, team, username),
+    }
+}
+
+/// Get a service account that `username` is allowed to manage.
+fn get_managed_account(
+    db: &crate::Db,
+    config: &Config,
+    username: &str,
+    name: &str,
+) -> Result<Option<User>, InternalError> {
+    match db.get_user(name)? {
+        Some(account) if can_manage(db, config, username, &account)? => Ok(Some(account)),
+        _ => Ok(None),
+    }
+}
+
+/// A service account as displayed on the service accounts page.
+#[derive(Serialize)]
+struct ServiceAccountView {
+    name: String,
+    owner: String,
+    blocked: bool,
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, "service account not found").into_response()
+}
+
+async fn service_accounts_index(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+) -> Result<impl IntoResponse, InternalError> {
+    let body = render_service_accounts_page(&db, &config, &tera, &username, None)?;
+    Ok((jar, body))
+}
+
+fn render_service_accounts_page(
+    db: &crate::Db,
+    config: &Config,
+    tera: &tera::Tera,
+    username: &str,
+    warning: Option<String>,
+) -> Result<Html<String>, InternalError> {
+    let mut accounts = Vec::new();
+    for account in db.iter_users() {
+        if !can_manage(db, config, username, &account)? {
+            continue;
+        }
+        if let AccountKind::Service { owner } = account.kind {
+            accounts.push(ServiceAccountView {
+                name: account.username,
+                owner: owner.to_string(),
+                blocked: account.blocked,
+            });
+        }
+    }
+
+    let mut context = tera::Context::new();
+    if let Some(warning) = warning {
+        context.insert("warning", &warning);
+    }
+    // Admins can own service accounts themselves, and team maintainers can create them for their teams
+    let mut owner_teams = Vec::new();
+    for team in db.iter_teams() {
+        if team.is_maintainer(username) {
+            owner_teams.push(team.name);
+        }
+    }
+
+    context.insert("accounts", &accounts);
+    context.insert("is_admin", &config.is_admin(username));
+    context.insert("owner_teams", &owner_teams);
+
+    let body = tera.render("service_accounts.html", &context)?;
+    Ok(Html(body))
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountParams {
+    name: String,
+    /// Team to own the account, or empty for the account to be owned by the creating admin
+    #[serde(default)]
+    team: String,
+}
+
+async fn service_account_create(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Form(params): Form<ServiceAccountParams>,
+) -> Result<Response, InternalError> {
+    let owner = if params.team.is_empty() {
+        if !config.is_admin(&username) {
+            return Ok((
+                StatusCode::FORBIDDEN,
+                "only admins can create service accounts without a team",
+            )
+                .into_response());
+        }
+        AccountOwner::User(username.clone())
+    } else {
+        if !config.is_admin(&username) && !team::is_maintainer(&db, &params.team, &username)? {
+            return Ok((
+                StatusCode::FORBIDDEN,
+                "only team maintainers can create service accounts for a team",
+            )
+                .into_response());
+        }
+        AccountOwner::Team(params.team)
+    };
+
+    let team_exists = match &owner {
+        AccountOwner::Team(team) => db.get_team(team)?.is_some(),
+        AccountOwner::User(_) => true,
+    };
+    let warning = if !auth::is_valid_username(&params.name) {
+        Some("account names may only contain letters, numbers, - and _".to_owned())
+    } else if !team_exists {
+        Some("the team does not exist".to_owned())
+    } else {
+        let account = User::new_service_account(params.name, owner);
+        if db.create_user(&account.username, &account)? {
+            info!(
+                "user {username} created service account {}",
+                account.username
+            );
+            None
+        } else {
+            Some("an account with this name already exists".to_owned())
+        }
+    };
+
+    let body = render_service_accounts_page(&db, &config, &tera, &username, warning)?;
+    Ok((jar, body).into_response())
+}
+
+async fn service_account_delete(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<Response, InternalError> {
+    let Some(account) = get_managed_account(&db, &config, &username, &name)? else {
+        return Ok(not_found());
+    };
+    // Crates that only the account owns are handed to whoever manages it
+    let successor = match &account.kind {
+        AccountKind::Service {
+            owner: AccountOwner::Team(team),
+        } => CrateOwner::Team(team.clone()),
+        AccountKind::Service {
+            owner: AccountOwner::User(user),
+        } => CrateOwner::User(user.clone()),
+        _ => CrateOwner::User(username.clone()),
+    };
+
+    // Remove everything granted to the account before the account itself, so that its name cannot be registered again
+    // while anything would still be inherited with it
+    owner::remove_user(&db, &name, &successor)?;
+    team::remove_member(&db, &name)?;
+    // Revoke all of the account's tokens before removing it
+    for entry in token::get_user_tokens(&db, &name)? {
+        token::delete(&db, &name, entry.label())?;
+        let target = format!("{name}/{}", entry.label());
+        let event = AuditEvent::session(&username, AuditAction::TokenDelete, target).ip(addr.ip());
+        audit::record(&db, &event)?;
+    }
+    db.delete_user(&name)?;
+    info!("user {username} deleted service account {name}");
+
+    Ok((jar, Redirect::to("/service-accounts")).into_response())
+}
+
+async fn service_account_view(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Path(name): Path<String>,
+) -> Result<Response, InternalError> {
+    if get_managed_account(&db, &config, &username, &name)?.is_none() {
+        return Ok(not_found());
+    }
+
+    let token_base = format!("/service-accounts/{name}/tokens");
+    let body = auth::render_tokens_page(&db, &tera, &name, &token_base, None, None)?;
+    Ok((jar, body).into_response())
+}
+
+async fn service_account_token_create(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Form(params): Form<TokenCreateParams>,
+) -> Result<Response, InternalError> {
+    if get_managed_account(&db, &config, &username, &name)?.is_none() {
+        return Ok(not_found());
+    }
+
+    let result = auth::create_token_from_form(&db, &name, &params)?;
+    auth::audit_token_create(&db, &username, &name, addr.ip(), &params, &result)?;
+    let (token, warning) = match result {
+        Ok(token) => (token, None),
+        Err(warning) => (None, Some(warning)),
+    };
+    if token.is_some() {
+        info!("user {username} created a token for service account {name}");
+    }
+
+    let token_base = format!("/service-accounts/{name}/tokens");
+    let body = auth::render_tokens_page(&db, &tera, &name, &token_base, token, warning)?;
+    Ok((jar, body).into_response())
+}
+
+async fn service_account_token_delete(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Form(params): Form<TokenParams>,
+) -> Result<Response, InternalError> {
+    if get_managed_account(&db, &config, &username, &name)?.is_none() {
+        return Ok(not_found());
+    }
+
+    token::delete(&db, &name, &params.label)?;
+    let target = format!("{name}/{}", params.label);
+    let event = AuditEvent::session(&username, AuditAction::TokenDelete, target).ip(addr.ip());
+    audit::record(&db, &event)?;
+
+    Ok((jar, Redirect::to(&format!("/service-accounts/{name}"))).into_response())
+}
@@ -0,0 +1,331 @@
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{auth::AuthSession, config::Config, AppState, InternalError};
+
+/// A named group of users that can own crates and service accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Team {
+    pub name: String,
+    pub members: Vec<TeamMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamMember {
+    pub username: String,
+    /// Maintainers can add and remove members of the team
+    pub maintainer: bool,
+}
+
+impl Team {
+    pub fn is_member(&self, username: &str) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.username == username)
+    }
+
+    pub fn is_maintainer(&self, username: &str) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.username == username && member.maintainer)
+    }
+
+    /// Check that the team still has a maintainer to manage it after a change to its members.
+    pub fn check_maintained(&self) -> Result<(), String> {
+        if self.members.iter().any(|member| member.maintainer) {
+            Ok(())
+        } else {
+            Err("a team must have at least one maintainer".to_owned())
+        }
+    }
+}
+
+/// Check whether a team name is well formed.
+pub fn is_valid_team_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Check whether `username` is a member of the team `team_name`.
+pub fn is_member(db: &crate::Db, team_name: &str, username: &str) -> Result<bool, anyhow::Error> {
+    Ok(matches!(db.get_team(team_name)?, Some(team) if team.is_member(username)))
+}
+
+/// Remove `username` from every team it is a member of, so that an account created later with the same name does not
+/// inherit its memberships. Teams may be left without a maintainer, which admins can still manage.
+pub fn remove_member(db: &crate::Db, username: &str) -> Result<(), anyhow::Error> {
+    let teams: Vec<_> = db
+        .iter_teams()
+        .filter(|team| team.is_member(username))
+        .collect();
+    for team in teams {
+        db.modify_team(&team.name, &mut |team| {
+            team.members.retain(|member| member.username != username);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Check whether `username` is a maintainer of the team `team_name`.
+pub fn is_maintainer(
+    db: &crate::Db,
+    team_name: &str,
+    username: &str,
+) -> Result<bool, anyhow::Error> {
+    Ok(matches!(db.get_team(team_name)?, Some(team) if team.is_maintainer(username)))
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/teams", get(teams_index).post(team_create))
+        .route("/teams/:name", get(team_view))
+        .route("/teams/:name/members", post(team_member_add))
+        .route("/teams/:name/members/delete", post(team_member_remove))
+}
+
+async fn teams_index(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+) -> Result<impl IntoResponse, InternalError> {
+    let body = render_teams_page(&db, &config, &tera, &username, None)?;
+    Ok((jar, body))
+}
+
+fn render_teams_page(
+    db: &crate::Db,
+    config: &Config,
+    tera: &tera::Tera,
+    username: &str,
+    warning: Option<String>,
+) -> Result<Html<String>, InternalError> {
+    let teams: Vec<_> = db
+        .iter_teams()
+        .filter(|team| config.is_admin(username) || team.is_member(username))
+        .collect();
+
+    let mut context = tera::Context::new();
+    if let Some(warning) = warning {
+        context.insert("warning", &warning);
+    }
+    context.insert("teams", &teams);
+
+    let body = tera.render("teams.html", &context)?;
+    Ok(Html(body))
+}
+
+#[derive(Deserialize)]
+struct TeamParams {
+    name: String,
+}
+
+async fn team_create(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Form(params): Form<TeamParams>,
+) -> Result<impl IntoResponse, InternalError> {
+    let warning = if !is_valid_team_name(&params.name) {
+        Some("team names may only contain letters, numbers, - and _".to_owned())
+    } else if db.get_team(&params.name)?.is_some() {
+        Some("a team with this name already exists".to_owned())
+    } else {
+        // The creator of a team becomes its first maintainer
+        let team = Team {
+            name: params.name,
+            members: vec![TeamMember {
+                username: username.clone(),
+                maintainer: true,
+            }],
+        };
+        db.insert_team(&team)?;
+        info!("user {username} created team {}", team.name);
+        None
+    };
+
+    let body = render_teams_page(&db, &config, &tera, &username, warning)?;
+    Ok((jar, body))
+}
+
+async fn team_view(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Path(name): Path<String>,
+) -> Result<Response, InternalError> {
+    let body = match render_team_page(&db, &config, &tera, &username, &name, None)? {
+        Some(body) => body,
+        None => return Ok(not_found()),
+    };
+    Ok((jar, body).into_response())
+}
+
+fn render_team_page(
+    db: &crate::Db,
+    config: &Config,
+    tera: &tera::Tera,
+    username: &str,
+    name: &str,
+    warning: Option<String>,
+) -> Result<Option<Html<String>>, InternalError> {
+    let Some(team) = db.get_team(name)? else {
+        return Ok(None);
+    };
+    if !config.is_admin(username) && !team.is_member(username) {
+        return Ok(None);
+    }
+
+    // Label service accounts so that they can be told apart from people
+    let mut members = Vec::with_capacity(team.members.len());
+    for member in &team.members {
+        let kind = db
+            .get_user(&member.username)?
+            .map_or("user", |user| user.kind_label());
+        members.push(MemberView {
+            username: &member.username,
+            maintainer: member.maintainer,
+            kind,
+        });
+    }
+
+    let mut context = tera::Context::new();
+    if let Some(warning) = warning {
+        context.insert("warning", &warning);
+    }
+    context.insert("team", &team.name);
+    context.insert("members", &members);
+    context.insert(
+        "can_manage",
+        &(config.is_admin(username) || team.is_maintainer(username)),
+    );
+
+    let body = tera.render("team.html", &context)?;
+    Ok(Some(Html(body)))
+}
+
+/// A team member as displayed on the team page.
+#[derive(Serialize)]
+struct MemberView<'a> {
+    username: &'a str,
+    maintainer: bool,
+    kind: &'static str,
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, "team not found").into_response()
+}
+
+#[derive(Deserialize)]
+struct MemberParams {
+    username: String,
+    #[serde(default)]
+    maintainer: bool,
+}
+
+async fn team_member_add(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(tera): State<tera::Tera>,
+    Path(name): Path<String>,
+    Form(params): Form<MemberParams>,
+) -> Result<Response, InternalError> {
+    let Some(team) = db.get_team(&name)? else {
+        return Ok(not_found());
+    };
+    if !config.is_admin(&username) && !team.is_maintainer(&username) {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "only team maintainers can add members",
+        )
+            .into_response());
+    }
+
+    let warning = if db.get_user(&params.username)?.is_none() {
+        Some(format!("user {} does not exist", params.username))
+    } else {
+        let modified = db.modify_team(&name, &mut |team| {
+            // Adding an existing member updates their role
+            team.members
+                .retain(|member| member.username != params.username);
+            team.members.push(TeamMember {
+                username: params.username.clone(),
+                maintainer: params.maintainer,
+            });
+            team.check_maintained()
+        })?;
+        match modified {
+            None => return Ok(not_found()),
+            Some(Ok(())) => {
+                info!(
+                    "user {username} added {} to team {name} (maintainer: {})",
+                    params.username, params.maintainer
+                );
+                None
+            }
+            Some(Err(warning)) => Some(warning),
+        }
+    };
+
+    // The team may have been deleted, or the user removed from it, since it was modified
+    let Some(body) = render_team_page(&db, &config, &tera, &username, &name, warning)? else {
+        return Ok(not_found());
+    };
+    Ok((jar, body).into_response())
+}
+
+#[derive(Deserialize)]
+struct MemberRemoveParams {
+    username: String,
+}
+
+async fn team_member_remove(
+    AuthSession(username, jar): AuthSession,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Path(name): Path<String>,
+    Form(params): Form<MemberRemoveParams>,
+) -> Result<Response, InternalError> {
+    let Some(team) = db.get_team(&name)? else {
+        return Ok(not_found());
+    };
+    // Members can always leave a team themselves
+    if !config.is_admin(&username) && !team.is_maintainer(&username) && username != params.username
+    {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "only team maintainers can remove members",
+        )
+            .into_response());
+    }
+
+    let modified = db.modify_team(&name, &mut |team| {
+        team.members
+            .retain(|member| member.username != params.username);
+        team.check_maintained()
+    })?;
+    match modified {
+        None => return Ok(not_found()),
+        Some(Ok(())) => {}
+        Some(Err(e)) => return Ok((StatusCode::BAD_REQUEST, e).into_response()),
+    }
+    info!(
+        "user {username} removed {} from team {name}",
+        params.username
+    );
+
+    Ok((jar, Redirect::to(&format!("/teams/{name}"))).into_response())
+}
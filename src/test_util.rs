@@ -0,0 +1,81 @@
+//! Helpers shared by the tests of several modules.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use serde_json::json;
+
+use crate::{config::Config, package::UploadedPackage};
+
+/// A directory for the data of a test, which is created empty and removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("altreg-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A version of a crate with no dependencies or features, as it is stored in the index.
+pub fn package(name: &str, vers: &str, cksum: &str) -> UploadedPackage {
+    UploadedPackage {
+        pkg: serde_json::from_value(json!({
+            "name": name,
+            "vers": vers,
+            "deps": [],
+            "cksum": cksum,
+            "features": {},
+            "yanked": false,
+            "links": null,
+            "v": 2,
+            "features2": null,
+        }))
+        .unwrap(),
+        upload_meta: None,
+        upload_timestamp: None,
+    }
+}
+
+/// A configuration with the default upstreams that keeps its data in `data_dir`.
+pub fn config(data_dir: &Path, offline: bool) -> Config {
+    toml::from_str(&format!(
+        r#"
+host = "127.0.0.1"
+port = 1491
+data_dir = "{}"
+external_url = "https://localhost:1491"
+offline = {offline}
+tls_cert = "cert.pem"
+tls_key = "key.pem"
+admins = ["admin"]
+"#,
+        data_dir.display()
+    ))
+    .unwrap()
+}
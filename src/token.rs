@@ -1,22 +1,58 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
+    extract::{ConnectInfo, FromRef, FromRequestParts},
     http::request::Parts,
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::{DateTime, Utc};
 use rand::{rngs::OsRng, RngCore};
 use reqwest::{header, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 
-use crate::{auth, db};
+use crate::{
+    audit::{self, AuditAction, AuditEvent, Outcome},
+    auth,
+    config::Config,
+    db, paseto,
+};
+
+/// Prefix of tokens in the current format, so that leaked tokens can be recognised by secret scanners
+static TOKEN_PREFIX: &str = "altreg_";
+/// Number of random bytes in a token
+const TOKEN_LENGTH: usize = 32;
+/// Number of hex digits in the checksum at the end of a token
+const TOKEN_CHECKSUM_LENGTH: usize = 8;
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// An API endpoint that a token can be restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EndpointScope {
+    PublishNew,
+    PublishUpdate,
+    Yank,
+    ChangeOwners,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TokenEntry {
     username: String,
     label: String,
+    /// Endpoints this token may be used for, or `None` if it is unrestricted
+    endpoint_scopes: Option<Vec<EndpointScope>>,
+    /// Crate name patterns this token may act on, or `None` if it is unrestricted
+    crate_scopes: Option<Vec<String>>,
+    created_at: DateTime<Utc>,
+    /// Time after which the token is no longer accepted, or `None` if it never expires
+    expires_at: Option<DateTime<Utc>>,
+    /// Last time the token was used for the API, not counting reads of the index, downloads and docs
+    last_used_at: Option<DateTime<Utc>>,
+    last_used_ip: Option<IpAddr>,
 }
 
 impl TokenEntry {
@@ -27,110 +63,483 @@ impl TokenEntry {
     pub fn label(&self) -> &str {
         self.label.as_ref()
     }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= Utc::now())
+    }
+
+    /// Check whether this token may be used on `endpoint` for the crate `crate_name`.
+    pub fn is_authorized(&self, endpoint: EndpointScope, crate_name: &str) -> bool {
+        let endpoint_allowed = match &self.endpoint_scopes {
+            Some(scopes) => scopes.contains(&endpoint),
+            None => true,
+        };
+        let crate_allowed = match &self.crate_scopes {
+            Some(patterns) => patterns
+                .iter()
+                .any(|pattern| crate_pattern_matches(pattern, crate_name)),
+            None => true,
+        };
+
+        endpoint_allowed && crate_allowed
+    }
+}
+
+/// Check whether a crate scope pattern is well formed.
+///
+/// Patterns are either a crate name, or a crate name prefix followed by a single trailing `*`.
+pub fn is_valid_crate_pattern(pattern: &str) -> bool {
+    let name = pattern.strip_suffix('*').unwrap_or(pattern);
+    (!name.is_empty() || pattern == "*")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Match a crate name against a crate scope pattern.
+///
+/// Crate names are compared case-insensitively and with `-` and `_` treated as equivalent, the same way that cargo
+/// treats them.
+fn crate_pattern_matches(pattern: &str, crate_name: &str) -> bool {
+    let normalise = |name: &str| name.to_ascii_lowercase().replace('-', "_");
+    let crate_name = normalise(crate_name);
+
+    match pattern.strip_suffix('*') {
+        Some(prefix) => crate_name.starts_with(&normalise(prefix)),
+        None => crate_name == normalise(pattern),
+    }
 }
 
 /// Create a new token for the user.
 ///
-/// Returns the token to be supplied back to the user.
+/// Returns the token to be supplied back to the user, or `None` if the user already has a token with this label.
 pub fn create_token(
     db: &db::Db,
     username: &str,
     label: &str,
+    endpoint_scopes: Option<Vec<EndpointScope>>,
+    crate_scopes: Option<Vec<String>>,
+    expires_at: Option<DateTime<Utc>>,
 ) -> Result<Option<String>, anyhow::Error> {
-    // Check if user already has a token with this label
-    if get_user_tokens(db, username)?.contains(&TokenEntry {
-        username: username.to_owned(),
-        label: label.to_owned(),
-    }) {
-        return Ok(None);
-    }
-
-    let mut token = [0u8; 32];
+    let mut token = [0u8; TOKEN_LENGTH];
     OsRng.fill_bytes(&mut token);
     let hashed_token = Sha256::digest(token);
 
-    db.insert_token(
+    let inserted = db.insert_token(
         &hashed_token,
         &TokenEntry {
             username: username.to_owned(),
             label: label.to_owned(),
+            endpoint_scopes,
+            crate_scopes,
+            created_at: Utc::now(),
+            expires_at,
+            last_used_at: None,
+            last_used_ip: None,
         },
     )?;
-    Ok(Some(bs58::encode(token).into_string()))
+    Ok(inserted.then(|| encode_token(&token)))
+}
+
+/// Encode the secret bytes of a token as `altreg_<base58 secret><hex crc32 of secret>`.
+fn encode_token(secret: &[u8]) -> String {
+    format!(
+        "{TOKEN_PREFIX}{}{:0width$x}",
+        bs58::encode(secret).into_string(),
+        crc32fast::hash(secret),
+        width = TOKEN_CHECKSUM_LENGTH
+    )
+}
+
+/// Decode a token into its secret bytes.
+///
+/// This validates the structure and checksum of the token, so that malformed tokens can be rejected without having
+/// to access the database. Tokens in the original unprefixed base58 format are also accepted.
+fn decode_token(token: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let secret = match token.strip_prefix(TOKEN_PREFIX) {
+        Some(body) => {
+            if body.len() <= TOKEN_CHECKSUM_LENGTH || !body.is_ascii() {
+                return Err(anyhow!("malformed token"));
+            }
+            let (secret, checksum) = body.split_at(body.len() - TOKEN_CHECKSUM_LENGTH);
+            let secret = bs58::decode(secret).into_vec()?;
+            let checksum = u32::from_str_radix(checksum, 16)?;
+            if crc32fast::hash(&secret) != checksum {
+                return Err(anyhow!("token checksum mismatch"));
+            }
+            secret
+        }
+        None => bs58::decode(token).into_vec()?,
+    };
+
+    if secret.len() != TOKEN_LENGTH {
+        return Err(anyhow!("token has incorrect length"));
+    }
+    Ok(secret)
 }
 
 pub fn lookup_token(
     db: &db::Db,
     token: &str,
 ) -> Result<Option<(TokenEntry, auth::User)>, anyhow::Error> {
-    let hashed_token = Sha256::digest(bs58::decode(token).into_vec()?);
+    let hashed_token = Sha256::digest(decode_token(token)?);
     db.get_token_user(&hashed_token)
 }
 
+/// Record that a token has just been used from the address `ip`.
+pub fn record_usage(
+    db: &db::Db,
+    token: &str,
+    mut entry: TokenEntry,
+    ip: Option<IpAddr>,
+) -> Result<(), anyhow::Error> {
+    let hashed_token = Sha256::digest(decode_token(token)?);
+    entry.last_used_at = Some(Utc::now());
+    entry.last_used_ip = ip;
+    db.update_token(&hashed_token, &entry)
+}
+
 pub fn get_user_tokens(db: &db::Db, username: &str) -> Result<Vec<TokenEntry>, anyhow::Error> {
-    db.iter_tokens()
-        .filter_map(|elem| elem.ok())
-        .map(|(_, value)| bincode::deserialize::<TokenEntry>(&value).map_err(|e| e.into()))
-        .filter(|entry| {
-            entry
-                .as_ref()
-                .map_or(false, |entry| entry.username() == username)
-        })
-        .collect()
+    db.get_user_tokens(username)
 }
 
 pub fn delete(db: &db::Db, username: &str, label: &str) -> Result<(), anyhow::Error> {
-    let reference = TokenEntry {
-        username: username.to_owned(),
-        label: label.to_owned(),
+    db.delete_user_token(username, label)
+}
+
+/// Revoke a token given only the token itself, such as one found by a secret scanner.
+///
+/// Returns the entry of the revoked token, or `None` if it wasn't a known token.
+pub fn revoke(db: &db::Db, token: &str) -> Result<Option<TokenEntry>, anyhow::Error> {
+    let Ok(secret) = decode_token(token) else {
+        return Ok(None);
     };
+    db.delete_token(&Sha256::digest(secret))
+}
 
-    // Find the tokens matching the username & label
-    let tokens = db
-        .iter_tokens()
-        .filter_map(|elem| elem.ok())
-        .filter_map(|(token, value)| {
-            bincode::deserialize::<TokenEntry>(&value)
-                .ok()
-                .map(|elem| (token, elem))
-        })
-        .filter_map(|(token, entry)| (entry == reference).then_some(token))
-        .collect::<Vec<_>>();
+/// The credential that a request to the API was authenticated with.
+pub enum Credential {
+    /// A bearer token created through the tokens page
+    Token(TokenEntry),
+    /// An asymmetric token signed by cargo with a registered key
+    PublicKey(paseto::PublicKeyEntry, paseto::Claims),
+}
 
-    // Delete the tokens
-    for token in tokens {
-        db.delete_token(&token)?;
+impl Credential {
+    pub fn label(&self) -> &str {
+        match self {
+            Credential::Token(entry) => entry.label(),
+            Credential::PublicKey(entry, _) => entry.label(),
+        }
     }
 
-    Ok(())
+    /// Check whether this credential may be used on `endpoint` for the crate `crate_name`.
+    pub fn is_authorized(&self, endpoint: EndpointScope, crate_name: &str) -> bool {
+        match self {
+            Credential::Token(entry) => entry.is_authorized(endpoint, crate_name),
+            Credential::PublicKey(..) => true,
+        }
+    }
+
+    /// Check whether this credential may be used to perform `mutation`.
+    ///
+    /// Bearer tokens may be used for any mutation, but asymmetric tokens are only valid for the mutation they were
+    /// signed for.
+    pub fn allows_mutation(&self, mutation: &paseto::Mutation) -> bool {
+        match self {
+            Credential::Token(_) => true,
+            Credential::PublicKey(_, claims) => claims.allows_mutation(mutation),
+        }
+    }
 }
 
-pub struct ApiAuth(pub TokenEntry, pub auth::User);
+pub struct ApiAuth(pub Credential, pub auth::User);
+
+fn auth_error(detail: &str) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "errors": [{ "detail": detail }] })),
+    )
+        .into_response()
+}
+
+/// Why the credential of a request was not accepted.
+pub struct AuthRejection {
+    pub detail: String,
+    /// The user that the credential belongs to and its label, if they are known
+    pub credential: Option<Box<(auth::User, String)>>,
+}
+
+impl AuthRejection {
+    fn new(detail: impl Into<String>) -> Self {
+        AuthRejection {
+            detail: detail.into(),
+            credential: None,
+        }
+    }
+}
+
+/// Authenticate a request by the credential in its `Authorization` header, returning why it was rejected otherwise.
+///
+/// With `record_use`, the time and address that a bearer token was used from are recorded on the token. Reads leave
+/// them alone, so that they do not write to the database on every request.
+pub fn authenticate<S>(
+    parts: &Parts,
+    state: &S,
+    record_use: bool,
+) -> Result<(Credential, auth::User), AuthRejection>
+where
+    db::Db: FromRef<S>,
+    Config: FromRef<S>,
+    paseto::ReplayCache: FromRef<S>,
+{
+    // Get token from header
+    let Some(token) = parts.headers.get(header::AUTHORIZATION) else {
+        return Err(AuthRejection::new("missing authorization token"));
+    };
+
+    // Ensure token is a valid string
+    let Ok(token) = token.to_str() else {
+        return Err(AuthRejection::new("invalid authorization token"));
+    };
+
+    let db = db::Db::from_ref(state);
+
+    let (credential, user) = if paseto::is_asymmetric_token(token) {
+        let index_url = Config::from_ref(state).external_url + "/index/";
+        let replay_cache = paseto::ReplayCache::from_ref(state);
+        let (entry, claims) = paseto::verify(&db, &replay_cache, &index_url, token)
+            .map_err(|e| AuthRejection::new(format!("invalid authorization token: {e}")))?;
+
+        let Ok(Some(user)) = db.get_user(entry.username()) else {
+            return Err(AuthRejection::new("invalid authorization token"));
+        };
+        (Credential::PublicKey(entry, claims), user)
+    } else {
+        // Check token is known
+        let Ok(Some((entry, user))) = lookup_token(&db, token) else {
+            return Err(AuthRejection::new("invalid authorization token"));
+        };
+
+        if entry.is_expired() {
+            return Err(AuthRejection {
+                detail: "authorization token has expired".to_owned(),
+                credential: Some(Box::new((user, entry.label().to_owned()))),
+            });
+        }
+        (Credential::Token(entry), user)
+    };
+
+    if user.blocked {
+        let label = credential.label().to_owned();
+        return Err(AuthRejection {
+            detail: "user blocked".to_owned(),
+            credential: Some(Box::new((user, label))),
+        });
+    }
+
+    // Keep track of when and where the token was last used from
+    if let (true, Credential::Token(entry)) = (record_use, &credential) {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if let Err(e) = record_usage(&db, token, entry.clone(), ip) {
+            tracing::warn!("could not record token usage: {e:?}");
+        }
+    }
+    Ok((credential, user))
+}
 
 #[async_trait]
 impl<S> FromRequestParts<S> for ApiAuth
 where
     S: Send + Sync + Clone,
     db::Db: FromRef<S>,
+    Config: FromRef<S>,
+    paseto::ReplayCache: FromRef<S>,
 {
     type Rejection = Response;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        // Get token from header
-        let Some(token) = parts.headers.get(header::AUTHORIZATION) else {
-            return Err((StatusCode::FORBIDDEN, Json(json!({ "errors": [{"detail": "missing authorization token"}]}))).into_response());
+        let rejection = match authenticate(parts, state, true) {
+            Ok((credential, user)) => return Ok(ApiAuth(credential, user)),
+            Err(rejection) => rejection,
         };
 
-        // Ensure token is a valid string
-        let Ok(token) = token.to_str() else {
-            return Err((StatusCode::FORBIDDEN, Json(json!({ "errors": [{"detail": "invalid authorization token"}]}))).into_response());
-        };
+        // Requests with refused credentials never reach their handler, so they are audited here. Only credentials of
+        // known users are, as anyone could otherwise fill the audit log by sending requests without a valid one.
+        let target = format!("{} {}", parts.method, parts.uri.path());
+        match rejection.credential.as_deref() {
+            Some((user, label)) => {
+                let mut event = AuditEvent::new(user, AuditAction::Authenticate, target)
+                    .token(label)
+                    .outcome(Outcome::Denied(rejection.detail.clone()));
+                if let Some(ConnectInfo(addr)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+                    event = event.ip(addr.ip());
+                }
+                if let Err(e) = audit::record(&db::Db::from_ref(state), &event) {
+                    tracing::warn!("could not record denied request: {e:?}");
+                }
+            }
+            None => tracing::debug!("refused {target}: {}", rejection.detail),
+        }
 
-        // Check token is known
-        let Ok(Some((entry, user))) = lookup_token(&db::Db::from_ref(state), token) else {
-            return Err((StatusCode::FORBIDDEN, Json(json!({ "errors": [{"detail": "invalid authorization token"}]}))).into_response());
+        Err(auth_error(&rejection.detail))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::FromRef;
+
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    fn scoped_entry(
+        endpoint_scopes: Option<Vec<EndpointScope>>,
+        crate_scopes: Option<Vec<&str>>,
+    ) -> TokenEntry {
+        TokenEntry {
+            username: "user".to_owned(),
+            label: "label".to_owned(),
+            endpoint_scopes,
+            crate_scopes: crate_scopes
+                .map(|patterns| patterns.into_iter().map(str::to_owned).collect()),
+            created_at: Utc::now(),
+            expires_at: None,
+            last_used_at: None,
+            last_used_ip: None,
+        }
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let secret = [7u8; TOKEN_LENGTH];
+        let token = encode_token(&secret);
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(decode_token(&token).unwrap(), secret);
+    }
+
+    #[test]
+    fn legacy_token_accepted() {
+        let secret = [7u8; TOKEN_LENGTH];
+        let token = bs58::encode(secret).into_string();
+        assert_eq!(decode_token(&token).unwrap(), secret);
+    }
+
+    #[test]
+    fn corrupted_token_rejected() {
+        let token = encode_token(&[7u8; TOKEN_LENGTH]);
+        let mut corrupted = token[..token.len() - 1].to_owned();
+        corrupted.push(if token.ends_with('0') { '1' } else { '0' });
+        assert!(decode_token(&corrupted).is_err());
+        assert!(decode_token(TOKEN_PREFIX).is_err());
+        assert!(decode_token("altreg_abc").is_err());
+        assert!(decode_token(&bs58::encode([7u8; 8]).into_string()).is_err());
+    }
+
+    #[test]
+    fn token_expiry() {
+        let mut entry = scoped_entry(None, None);
+        assert!(!entry.is_expired());
+
+        entry.expires_at = Some(Utc::now() + chrono::Duration::days(1));
+        assert!(!entry.is_expired());
+
+        entry.expires_at = Some(Utc::now() - chrono::Duration::seconds(1));
+        assert!(entry.is_expired());
+    }
+
+    #[test]
+    fn crate_pattern_validity() {
+        assert!(is_valid_crate_pattern("acme-proto"));
+        assert!(is_valid_crate_pattern("acme-proto-*"));
+        assert!(is_valid_crate_pattern("*"));
+        assert!(!is_valid_crate_pattern(""));
+        assert!(!is_valid_crate_pattern("acme*proto"));
+        assert!(!is_valid_crate_pattern("acme proto"));
+    }
+
+    #[test]
+    fn crate_pattern_samples() {
+        assert!(crate_pattern_matches("acme-proto-*", "acme-proto-core"));
+        assert!(crate_pattern_matches("acme-proto-*", "acme_proto_core"));
+        assert!(crate_pattern_matches("serde", "Serde"));
+        assert!(crate_pattern_matches("*", "anything"));
+        assert!(!crate_pattern_matches("acme-proto-*", "acme-other"));
+        assert!(!crate_pattern_matches("serde", "serde_json"));
+    }
+
+    #[test]
+    fn unrestricted_token_is_authorized() {
+        let entry = scoped_entry(None, None);
+        assert!(entry.is_authorized(EndpointScope::PublishNew, "anything"));
+        assert!(entry.is_authorized(EndpointScope::ChangeOwners, "anything"));
+    }
+
+    #[test]
+    fn scoped_token_is_restricted() {
+        let entry = scoped_entry(
+            Some(vec![
+                EndpointScope::PublishNew,
+                EndpointScope::PublishUpdate,
+            ]),
+            Some(vec!["acme-proto-*"]),
+        );
+        assert!(entry.is_authorized(EndpointScope::PublishUpdate, "acme-proto-core"));
+        assert!(!entry.is_authorized(EndpointScope::Yank, "acme-proto-core"));
+        assert!(!entry.is_authorized(EndpointScope::PublishNew, "acme-core"));
+    }
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: db::Db,
+        config: Config,
+        replay_cache: paseto::ReplayCache,
+    }
+
+    #[tokio::test]
+    async fn only_known_credentials_are_audited() {
+        let dir = TempDir::new("token-audit");
+        let db = db::Db::open(db::StoreKind::Sled, &dir).unwrap();
+        let state = TestState {
+            db: db.clone(),
+            config: test_util::config(&dir, true),
+            replay_cache: paseto::ReplayCache::default(),
         };
+        let expired = create_token(
+            &db,
+            "alice",
+            "old",
+            None,
+            None,
+            Some(Utc::now() - chrono::Duration::days(1)),
+        )
+        .unwrap()
+        .unwrap();
+        db.insert_user(
+            "alice",
+            &auth::User::new_service_account(
+                "alice".to_owned(),
+                auth::AccountOwner::User("admin".to_owned()),
+            ),
+        )
+        .unwrap();
+
+        for authorization in [None, Some("altreg_unknown"), Some(expired.as_str())] {
+            let mut request = axum::http::Request::builder().uri("/api/v1/crates/new");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let (mut parts, _) = request.body(()).unwrap().into_parts();
+            assert!(ApiAuth::from_request_parts(&mut parts, &state)
+                .await
+                .is_err());
+        }
 
-        Ok(ApiAuth(entry, user))
+        let events: Vec<_> = db.iter_audit_events().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token.as_deref(), Some("old"));
     }
 }
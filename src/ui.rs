@@ -1,48 +1,54 @@
-use std::{collections::HashMap, path};
+use std::collections::HashMap;
 
 use axum::{
     extract::{Path, Query, State},
-    response::{Html, Redirect},
-    routing::get,
-    Router,
+    http::header,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
 };
 use chrono_humanize::HumanTime;
 use reqwest::StatusCode;
+use serde::Deserialize;
 use tera::Tera;
-use tower_http::services::ServeDir;
+use tracing::info;
 
-use crate::{AppState, InternalError};
+use crate::{
+    auth::AuthSession,
+    blob::{self, Blobs},
+    config::Config,
+    owner,
+    visibility::{self, IndexReader, Reader, Visibility},
+    AppState, InternalError,
+};
 
-pub fn router(data_dir: &path::Path) -> Router<AppState> {
+pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", get(root))
         .route("/crates", get(crate_list))
         .route("/crates/:crate_name", get(crate_root))
         .route("/crates/:crate_name/:version", get(crate_view))
-        .nest_service(
-            "/docs",
-            axum::routing::get_service(ServeDir::new(data_dir.join("docs"))).handle_error(
-                |error: std::io::Error| async move {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Unhandled internal error: {}", error),
-                    )
-                },
-            ),
-        )
+        .route("/crates/:crate_name/visibility", post(crate_visibility))
+        .route("/docs/:crate_name/*path", get(crate_docs))
 }
 
 async fn crate_list(
+    reader: Reader,
     Query(params): Query<HashMap<String, String>>,
     State(db): State<crate::Db>,
+    State(config): State<Config>,
     State(tera): State<Tera>,
 ) -> Result<Html<String>, InternalError> {
     let filter = params.get("q");
 
-    let crates: HashMap<_, _> = db
-        .iter_crates()
-        .filter(|(crate_name, _)| filter.map_or(true, |filter| crate_name.contains(filter)))
-        .collect();
+    let mut crates = HashMap::new();
+    for (crate_name, entry) in db.iter_crate_headers() {
+        if filter.map_or(true, |filter| crate_name.contains(filter))
+            && visibility::can_read(&db, &config, &entry, reader.username())?
+        {
+            crates.insert(crate_name, entry);
+        }
+    }
 
     let mut context = tera::Context::new();
     context.insert("crates", &crates);
@@ -60,32 +66,50 @@ async fn crate_root(Path(crate_name): Path<String>) -> Redirect {
 }
 
 async fn crate_view(
+    reader: Reader,
     Path((crate_name, mut version)): Path<(String, String)>,
     State(db): State<crate::Db>,
+    State(config): State<Config>,
     State(tera): State<Tera>,
 ) -> Result<Html<String>, InternalError> {
-    let Some(crate_meta) = db.get_crate(&crate_name)? else {
-        let body = tera.render("crate_not_found.html", &tera::Context::new())?;
-        return Ok(Html(body));
+    let crate_meta = match db.get_crate_header(&crate_name)? {
+        Some(crate_meta) if visibility::can_read(&db, &config, &crate_meta, reader.username())? => {
+            crate_meta
+        }
+        _ => {
+            let body = tera.render("crate_not_found.html", &tera::Context::new())?;
+            return Ok(Html(body));
+        }
     };
 
     let is_local = crate_meta.is_local;
-    let versions = crate_meta
-        .versions
+    let owners = owner::describe(&db, &crate_meta.owners)?;
+    let can_manage = match reader.username() {
+        Some(username) if is_local => owner::is_owner(&db, &config, &crate_meta.owners, username)?,
+        _ => false,
+    };
+
+    // Owners can restrict the crate to any team they are a member of
+    let mut visibility_teams = Vec::new();
+    if let (true, Some(username)) = (can_manage, reader.username()) {
+        for team in db.iter_teams() {
+            if config.is_admin(username) || team.is_member(username) {
+                visibility_teams.push(Visibility::Team(team.name).to_string());
+            }
+        }
+    }
+    let packages = db.get_crate_versions(&crate_name)?;
+    let versions = packages
         .iter()
         .map(|package| package.pkg.vers.clone())
         .collect::<Vec<_>>();
 
     let meta = if version == "latest" {
-        let meta = crate_meta.versions.last().unwrap();
+        let meta = packages.last().unwrap();
         version = meta.pkg.vers.clone();
         meta
     } else {
-        match crate_meta
-            .versions
-            .iter()
-            .find(|package| package.pkg.vers == version)
-        {
+        match packages.iter().find(|package| package.pkg.vers == version) {
             Some(package) => package,
             None => {
                 let body = tera.render("crate_not_found.html", &tera::Context::new())?;
@@ -110,8 +134,88 @@ async fn crate_view(
     context.insert("version", &version);
     context.insert("meta", &meta);
     context.insert("is_local", &is_local);
+    context.insert("owners", &owners);
+    context.insert("visibility", &crate_meta.visibility.to_string());
+    context.insert("visibility_teams", &visibility_teams);
+    context.insert("can_manage", &can_manage);
     context.insert("rendered_readme", &readme);
     context.insert("versions", &versions);
     let body = tera.render("crate.html", &context)?;
     Ok(Html(body))
 }
+
+#[derive(Deserialize)]
+struct VisibilityParams {
+    visibility: String,
+}
+
+async fn crate_visibility(
+    AuthSession(username, jar): AuthSession,
+    Path(crate_name): Path<String>,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    Form(params): Form<VisibilityParams>,
+) -> Result<Response, InternalError> {
+    let Ok(new_visibility) = Visibility::try_from(params.visibility) else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid visibility").into_response());
+    };
+
+    // Retry until the crate is not changed by another request between reading and writing it
+    loop {
+        let expected = match db.get_crate_header(&crate_name)? {
+            Some(entry) if visibility::can_read(&db, &config, &entry, Some(&username))? => entry,
+            _ => return Ok((StatusCode::NOT_FOUND, "crate not found").into_response()),
+        };
+        if !expected.is_local || !owner::is_owner(&db, &config, &expected.owners, &username)? {
+            return Ok((
+                StatusCode::FORBIDDEN,
+                "only owners can change the visibility of a crate",
+            )
+                .into_response());
+        }
+
+        let mut entry = expected.clone();
+        entry.visibility = new_visibility.clone();
+        if db.replace_crate_header(&crate_name, &expected, &entry)? {
+            break;
+        }
+    }
+    info!("user {username} changed visibility of {crate_name} to {new_visibility}");
+
+    Ok((jar, Redirect::to(&format!("/crates/{crate_name}"))).into_response())
+}
+
+/// Serve the generated documentation of a crate, if the requester is allowed to see it.
+async fn crate_docs(
+    reader: IndexReader,
+    Path((crate_name, path)): Path<(String, String)>,
+    State(db): State<crate::Db>,
+    State(config): State<Config>,
+    State(blobs): State<Blobs>,
+) -> Result<Response, InternalError> {
+    if let Some(entry) = db.get_crate_header(&crate_name)? {
+        if !visibility::can_read(&db, &config, &entry, reader.username())? {
+            return Ok((StatusCode::NOT_FOUND, "not found").into_response());
+        }
+    }
+
+    // The path starts with the version, and directories are served from their index page
+    let path = path.trim_start_matches('/');
+    let mut key = format!("docs/{crate_name}/{path}");
+    if path.is_empty() || path.ends_with('/') {
+        key.push_str("index.html");
+    } else if blob::validate_key(&key).is_ok() && blobs.exists(&format!("{key}/index.html")).await?
+    {
+        // Relative links in an index page only resolve with a trailing slash
+        return Ok(Redirect::permanent(&format!("/docs/{crate_name}/{path}/")).into_response());
+    }
+    if blob::validate_key(&key).is_err() {
+        return Ok((StatusCode::NOT_FOUND, "not found").into_response());
+    }
+
+    let Some(body) = blobs.get(&key).await? else {
+        return Ok((StatusCode::NOT_FOUND, "not found").into_response());
+    };
+    let content_type = mime_guess::from_path(&key).first_or_octet_stream();
+    Ok(([(header::CONTENT_TYPE, content_type.to_string())], body).into_response())
+}
@@ -0,0 +1,376 @@
+//! Exporting the crates of a `Cargo.lock` as a tarball in the layout of `cargo vendor`.
+//!
+//! Each package is unpacked into `vendor/<name>-<version>/` next to a `.cargo-checksum.json`, which holds the checksum
+//! of its crate file and of every file in it, so that Cargo can build from the directory without network access.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::Component,
+};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use reqwest::StatusCode;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    blob::{self, Blobs},
+    cache::CacheStats,
+    config::Config,
+    db::Db,
+    mirror::{self, LockedPackage},
+    visibility::{self, IndexReader},
+    AppState, CrateHeader, InternalError,
+};
+
+/// Files in crate files that `cargo vendor` leaves out
+const SKIPPED_FILES: &[&str] = &[".gitattributes", ".gitignore", ".cargo-ok"];
+/// Most packages that a vendor tarball can hold
+const MAX_PACKAGES: usize = 5000;
+/// Most bytes that the crate files of a vendor tarball can add up to, and separately the files unpacked from them
+const MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// A gzipped tarball of vendored crates.
+pub struct Vendored {
+    pub archive: Vec<u8>,
+    /// Number of packages in the archive
+    pub packages: usize,
+}
+
+/// Why a vendor tarball could not be built.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VendorError {
+    /// Each package that could not be vendored, with the reason
+    Unavailable(Vec<String>),
+    /// The packages are more than a vendor tarball can hold
+    TooLarge(String),
+}
+
+fn too_large() -> VendorError {
+    VendorError::TooLarge(format!(
+        "the crates add up to more than the {} MB that a vendor tarball can hold",
+        MAX_BYTES / (1024 * 1024)
+    ))
+}
+
+pub fn api_router() -> Router<AppState> {
+    Router::new().route("/v1/vendor", post(api_vendor))
+}
+
+/// Build a vendor tarball of `packages` from the blob store, downloading crate files that are not cached from upstream
+/// unless the registry is offline. Crates are left out if `can_read` rejects them.
+///
+/// If any package cannot be vendored, returns each package that could not be, with the reason. Lockfiles with more
+/// than [`MAX_PACKAGES`] packages, or more than [`MAX_BYTES`] of crate files, are refused.
+pub async fn vendor(
+    db: &Db,
+    blobs: &Blobs,
+    config: &Config,
+    stats: &CacheStats,
+    packages: Vec<LockedPackage>,
+    can_read: impl Fn(&CrateHeader) -> Result<bool, anyhow::Error>,
+) -> Result<Result<Vendored, VendorError>, anyhow::Error> {
+    if packages.len() > MAX_PACKAGES {
+        return Ok(Err(VendorError::TooLarge(format!(
+            "a vendor tarball can hold at most {MAX_PACKAGES} packages"
+        ))));
+    }
+
+    let mut crates = Vec::new();
+    let mut problems = Vec::new();
+    let mut size = 0;
+    for package in packages {
+        match crate_file(db, blobs, config, stats, &package, &can_read).await? {
+            Ok(data) => {
+                size += data.len() as u64;
+                if size > MAX_BYTES {
+                    return Ok(Err(too_large()));
+                }
+                crates.push((package, data));
+            }
+            Err(reason) => problems.push(format!("{}@{}: {reason}", package.name, package.version)),
+        }
+    }
+    if !problems.is_empty() {
+        return Ok(Err(VendorError::Unavailable(problems)));
+    }
+
+    let packages = crates.len();
+    // Unpacking and compressing is CPU bound, so it is kept off the async runtime
+    let archive = tokio::task::spawn_blocking(move || build_archive(&crates)).await??;
+    Ok(archive.map(|archive| Vendored { archive, packages }))
+}
+
+/// Build a gzipped tarball of the crates, or return why it would be too large.
+fn build_archive(
+    crates: &[(LockedPackage, Bytes)],
+) -> Result<Result<Vec<u8>, VendorError>, anyhow::Error> {
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut remaining = MAX_BYTES;
+    for (package, data) in crates {
+        if let Err(e) = append_crate(&mut archive, package, data, &mut remaining)? {
+            return Ok(Err(e));
+        }
+    }
+    Ok(Ok(archive.into_inner()?.finish()?))
+}
+
+/// The crate file of a package, or why it cannot be vendored.
+async fn crate_file(
+    db: &Db,
+    blobs: &Blobs,
+    config: &Config,
+    stats: &CacheStats,
+    package: &LockedPackage,
+    can_read: &impl Fn(&CrateHeader) -> Result<bool, anyhow::Error>,
+) -> Result<Result<Bytes, &'static str>, anyhow::Error> {
+    let (name, version) = (&package.name, &package.version);
+    let matches_lockfile = |data: &[u8]| {
+        package
+            .checksum
+            .as_ref()
+            .is_none_or(|checksum| format!("{:x}", Sha256::digest(data)) == *checksum)
+    };
+    let header = db.get_crate_header(name)?;
+    if let Some(header) = &header {
+        if !can_read(header)? {
+            return Ok(Err("not found"));
+        }
+    }
+    let is_local = header.as_ref().is_some_and(|header| header.is_local);
+
+    let key = blob::crate_key(name, version);
+    if let Some(data) = blobs.get(&key).await? {
+        if !matches_lockfile(&data) {
+            return Ok(Err("crate file does not match the checksum in Cargo.lock"));
+        }
+        if !is_local {
+            stats.record_hit(db, &key)?;
+        }
+        return Ok(Ok(data));
+    }
+    if config.offline || is_local {
+        return Ok(Err("not found"));
+    }
+
+    let cached_from = header.and_then(|header| header.upstream);
+    let Some(upstream) = mirror::upstream_for(&config.upstreams, name, cached_from.as_deref())
+    else {
+        return Ok(Err("not found"));
+    };
+    let Some(data) =
+        mirror::download_crate(upstream, name, version, package.checksum.as_deref()).await?
+    else {
+        return Ok(Err("not found upstream"));
+    };
+    blobs.put(&key, data.clone()).await?;
+    stats.record_miss(db, &key, data.len() as u64)?;
+    Ok(Ok(data))
+}
+
+/// Unpack the crate file of `package` into `vendor/<name>-<version>/` in `archive`, along with its checksums, unless its
+/// files add up to more than the `remaining` bytes that the archive can hold.
+fn append_crate(
+    archive: &mut tar::Builder<impl Write>,
+    package: &LockedPackage,
+    data: &[u8],
+    remaining: &mut u64,
+) -> Result<Result<(), VendorError>, anyhow::Error> {
+    let dir = format!("{}-{}", package.name, package.version);
+    let mut files = BTreeMap::new();
+
+    let mut crate_archive = tar::Archive::new(GzDecoder::new(data));
+    for entry in crate_archive
+        .entries()
+        .with_context(|| format!("could not read crate file of {dir}"))?
+    {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let parts = path
+            .strip_prefix(&dir)
+            .ok()
+            .and_then(|relative| {
+                relative
+                    .components()
+                    .map(|component| match component {
+                        Component::Normal(part) => part.to_str(),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .filter(|parts| !parts.is_empty())
+            .ok_or_else(|| anyhow!("crate file of {dir} contains {}", path.display()))?;
+        if parts
+            .last()
+            .is_some_and(|file| SKIPPED_FILES.contains(file))
+        {
+            continue;
+        }
+        let relative = parts.join("/");
+
+        // Checked before reading, as crate files can unpack to much more than their own size
+        match remaining.checked_sub(entry.size()) {
+            Some(left) => *remaining = left,
+            None => return Ok(Err(too_large())),
+        }
+        let mode = entry.header().mode()?;
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(relative.clone(), format!("{:x}", Sha256::digest(&contents)));
+        append(
+            archive,
+            &format!("vendor/{dir}/{relative}"),
+            &contents,
+            mode,
+        )?;
+    }
+
+    let package_checksum = match &package.checksum {
+        Some(checksum) => checksum.clone(),
+        None => format!("{:x}", Sha256::digest(data)),
+    };
+    let checksums = serde_json::to_vec(&json!({ "files": files, "package": package_checksum }))?;
+    append(
+        archive,
+        &format!("vendor/{dir}/.cargo-checksum.json"),
+        &checksums,
+        0o644,
+    )?;
+    Ok(Ok(()))
+}
+
+fn append(
+    archive: &mut tar::Builder<impl Write>,
+    path: &str,
+    data: &[u8],
+    mode: u32,
+) -> Result<(), anyhow::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    archive
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("could not add {path} to vendor archive"))
+}
+
+async fn api_vendor(
+    reader: IndexReader,
+    State(db): State<Db>,
+    State(blobs): State<Blobs>,
+    State(config): State<Config>,
+    State(stats): State<CacheStats>,
+    lockfile: String,
+) -> Result<Response, InternalError> {
+    let packages = match mirror::lockfile_packages(&lockfile) {
+        Ok(packages) => packages,
+        Err(e) => return Ok(api_error(StatusCode::BAD_REQUEST, &format!("{e:#}"))),
+    };
+    let can_read =
+        |header: &CrateHeader| visibility::can_read(&db, &config, header, reader.username());
+
+    match vendor(&db, &blobs, &config, &stats, packages, can_read).await? {
+        Ok(vendored) => Ok((
+            [
+                (header::CONTENT_TYPE, "application/gzip"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"vendor.tar.gz\"",
+                ),
+            ],
+            vendored.archive,
+        )
+            .into_response()),
+        Err(VendorError::Unavailable(problems)) => Ok(api_error(
+            StatusCode::NOT_FOUND,
+            &format!("could not vendor {}", problems.join(", ")),
+        )),
+        Err(VendorError::TooLarge(reason)) => Ok(api_error(StatusCode::PAYLOAD_TOO_LARGE, &reason)),
+    }
+}
+
+fn api_error(status: StatusCode, detail: &str) -> Response {
+    (status, Json(json!({ "errors": [{ "detail": detail }] }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendor_layout() {
+        let mut crate_file = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in [
+            ("foo-1.0.0/Cargo.toml", "[package]\nname = \"foo\"\n"),
+            ("foo-1.0.0/src/lib.rs", "pub fn foo() {}\n"),
+            ("foo-1.0.0/.cargo-ok", "ok"),
+        ] {
+            append(&mut crate_file, path, contents.as_bytes(), 0o644).unwrap();
+        }
+        let crate_file = crate_file.into_inner().unwrap().finish().unwrap();
+        let package = LockedPackage {
+            name: "foo".to_owned(),
+            version: "1.0.0".to_owned(),
+            source: Some("registry+https://github.com/rust-lang/crates.io-index".to_owned()),
+            checksum: Some(format!("{:x}", Sha256::digest(&crate_file))),
+        };
+
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut remaining = MAX_BYTES;
+        append_crate(&mut archive, &package, &crate_file, &mut remaining)
+            .unwrap()
+            .unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let mut unpacked = BTreeMap::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_owned();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            unpacked.insert(path, contents);
+        }
+        assert_eq!(
+            unpacked.keys().collect::<Vec<_>>(),
+            [
+                "vendor/foo-1.0.0/.cargo-checksum.json",
+                "vendor/foo-1.0.0/Cargo.toml",
+                "vendor/foo-1.0.0/src/lib.rs"
+            ]
+        );
+
+        let checksums: serde_json::Value =
+            serde_json::from_str(&unpacked["vendor/foo-1.0.0/.cargo-checksum.json"]).unwrap();
+        assert_eq!(checksums["package"], package.checksum.as_deref().unwrap());
+        assert_eq!(
+            checksums["files"]["src/lib.rs"],
+            format!("{:x}", Sha256::digest("pub fn foo() {}\n"))
+        );
+
+        let mut remaining = 10;
+        assert_eq!(
+            append_crate(
+                &mut tar::Builder::new(Vec::new()),
+                &package,
+                &crate_file,
+                &mut remaining
+            )
+            .unwrap(),
+            Err(too_large())
+        );
+    }
+}
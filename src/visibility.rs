@@ -0,0 +1,193 @@
+use std::{convert::Infallible, fmt};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::cookie;
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth::AuthSession,
+    config::Config,
+    db, owner, paseto, team,
+    token::{self, Credential},
+    CrateHeader,
+};
+
+/// Prefix of a team visibility, as written in forms and the config file
+static TEAM_PREFIX: &str = "team:";
+
+/// Who is able to see a crate in the index, download it, and view it in the UI.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Visibility {
+    /// Visible to anyone who can reach the registry
+    #[default]
+    Public,
+    /// Visible to the crate's owners and the members of a team
+    Team(String),
+    /// Visible only to the crate's owners
+    Owners,
+}
+
+impl TryFrom<String> for Visibility {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "public" => Ok(Visibility::Public),
+            "owners" => Ok(Visibility::Owners),
+            _ => match value.strip_prefix(TEAM_PREFIX) {
+                Some(team) if team::is_valid_team_name(team) => {
+                    Ok(Visibility::Team(team.to_owned()))
+                }
+                _ => Err(format!("invalid visibility {value}")),
+            },
+        }
+    }
+}
+
+impl From<Visibility> for String {
+    fn from(visibility: Visibility) -> Self {
+        visibility.to_string()
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Public => write!(f, "public"),
+            Visibility::Team(team) => write!(f, "{TEAM_PREFIX}{team}"),
+            Visibility::Owners => write!(f, "owners"),
+        }
+    }
+}
+
+/// Check whether `reader` is allowed to see the crate with the header `entry`.
+pub fn can_read(
+    db: &db::Db,
+    config: &Config,
+    entry: &CrateHeader,
+    reader: Option<&str>,
+) -> Result<bool, anyhow::Error> {
+    let Some(reader) = reader else {
+        return Ok(entry.visibility == Visibility::Public);
+    };
+
+    match &entry.visibility {
+        Visibility::Public => Ok(true),
+        Visibility::Team(team) => Ok(owner::is_owner(db, config, &entry.owners, reader)?
+            || team::is_member(db, team, reader)?),
+        Visibility::Owners => owner::is_owner(db, config, &entry.owners, reader),
+    }
+}
+
+/// The user reading from the registry, identified by their API token or their session cookie.
+///
+/// Requests with no credentials, or with credentials that are not valid, are read anonymously.
+pub struct Reader(pub Option<String>);
+
+impl Reader {
+    pub fn username(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Reader
+where
+    S: Send + Sync + Clone,
+    db::Db: FromRef<S>,
+    Config: FromRef<S>,
+    paseto::ReplayCache: FromRef<S>,
+    cookie::Key: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(header::AUTHORIZATION) {
+            let username = match token::authenticate(parts, state, false) {
+                // Asymmetric tokens issued for a mutation can only be used for that mutation
+                Ok((Credential::PublicKey(_, claims), _)) if claims.is_mutation() => None,
+                Ok((_, user)) => Some(user.username),
+                Err(_) => None,
+            };
+            return Ok(Reader(username));
+        }
+
+        let username = AuthSession::from_request_parts(parts, state)
+            .await
+            .ok()
+            .map(|AuthSession(username, _)| username);
+        Ok(Reader(username))
+    }
+}
+
+/// A reader of the index, crate downloads or docs, who must be authenticated if the registry requires it.
+pub struct IndexReader(pub Reader);
+
+impl IndexReader {
+    pub fn username(&self) -> Option<&str> {
+        self.0.username()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IndexReader
+where
+    S: Send + Sync + Clone,
+    db::Db: FromRef<S>,
+    Config: FromRef<S>,
+    paseto::ReplayCache: FromRef<S>,
+    cookie::Key: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let reader = match Reader::from_request_parts(parts, state).await {
+            Ok(reader) => reader,
+            Err(never) => match never {},
+        };
+
+        let config = Config::from_ref(state);
+        if config.auth_required && reader.username().is_none() {
+            // Tell cargo where the user can get a token
+            let challenge = format!("Cargo login_url=\"{}/auth/tokens\"", config.external_url);
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, challenge)],
+                Json(json!({ "errors": [{ "detail": "this registry requires authentication" }] })),
+            )
+                .into_response());
+        }
+
+        Ok(IndexReader(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visibility_round_trip() {
+        for visibility in [
+            Visibility::Public,
+            Visibility::Owners,
+            Visibility::Team("platform".to_owned()),
+        ] {
+            assert_eq!(
+                Visibility::try_from(visibility.to_string()).unwrap(),
+                visibility
+            );
+        }
+        assert!(Visibility::try_from("private".to_owned()).is_err());
+        assert!(Visibility::try_from("team:".to_owned()).is_err());
+    }
+}